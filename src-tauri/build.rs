@@ -1,3 +1,6 @@
 fn main() {
-    tauri_build::build()
+    // Migrations are embedded with `sqlx::migrate!`.
+    println!("cargo:rerun-if-changed=migrations");
+    #[cfg(feature = "desktop")]
+    tauri_build::build();
 }
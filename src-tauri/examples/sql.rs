@@ -24,7 +24,7 @@ async fn main() -> anyhow::Result<()> {
 
     // sqlx::migrate::
 
-    sqlx::raw_sql(include_str!("../migrations/test.sql"))
+    sqlx::raw_sql(include_str!("test.sql"))
         .execute(&pool)
         .await?;
 
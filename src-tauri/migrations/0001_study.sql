@@ -1,5 +1,4 @@
-pragma foreign_keys = on;
-
+-- Databases from before versioned migrations already have this table.
 create table if not exists study (
   id integer primary key,
   name text not null,
@@ -0,0 +1,22 @@
+create table game (
+  id integer primary key,
+  white text not null,
+  black text not null,
+  result text not null,
+  termination text,
+  event text,
+  site text,
+  date text,
+  white_elo integer,
+  black_elo integer,
+  time_control text,
+  speed text,
+  eco text,
+  opening text,
+  -- Ply of the first move out of the opening book.
+  book_ply integer,
+  pgn text not null,
+  created_at text default current_timestamp
+) strict;
+
+create index game_eco on game (eco);
@@ -0,0 +1,10 @@
+-- Every position of every stored game, with the move played from it.
+create table position (
+  game_id integer not null references game (id) on delete cascade,
+  ply integer not null,
+  hash integer not null,
+  move text,
+  primary key (game_id, ply)
+) strict, without rowid;
+
+create index position_hash on position (hash);
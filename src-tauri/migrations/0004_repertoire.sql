@@ -0,0 +1,20 @@
+create table repertoire (
+  id integer primary key,
+  name text not null,
+  color text not null check (color in ('white', 'black')),
+  created_at text default current_timestamp,
+  updated_at text default current_timestamp
+) strict;
+
+-- Moves of a repertoire keyed by the position they are played from, so transpositions
+-- share their continuations.
+create table repertoire_move (
+  id integer primary key,
+  repertoire_id integer not null references repertoire (id) on delete cascade,
+  hash integer not null,
+  fen text not null,
+  uci text not null,
+  san text not null,
+  comment text,
+  unique (repertoire_id, hash, uci)
+) strict;
@@ -0,0 +1,26 @@
+-- A line of a repertoire, from the initial position to a leaf, scheduled with SM-2.
+create table drill_line (
+  id integer primary key,
+  repertoire_id integer not null references repertoire (id) on delete cascade,
+  -- UCI moves from the initial position, separated by spaces.
+  moves text not null,
+  ease real not null default 2.5,
+  -- Days until the next review.
+  interval integer not null default 0,
+  repetitions integer not null default 0,
+  due text not null default current_timestamp,
+  reviews integer not null default 0,
+  lapses integer not null default 0,
+  last_reviewed text,
+  unique (repertoire_id, moves)
+) strict;
+
+create table drill_mistake (
+  id integer primary key,
+  line_id integer not null references drill_line (id) on delete cascade,
+  ply integer not null,
+  fen text not null,
+  expected text not null,
+  played text not null,
+  created_at text default current_timestamp
+) strict;
@@ -0,0 +1,16 @@
+-- PGN tag pairs of the study's own tree, as a JSON array of [name, value] pairs.
+alter table study add column tags_json text not null default '[]';
+
+-- Further games of a study. The study's own tree is its first chapter.
+create table chapter (
+  id integer primary key,
+  study_id integer not null references study (id) on delete cascade,
+  ord integer not null,
+  name text not null,
+  tags_json text not null default '[]',
+  tree_json text not null,
+  created_at text default current_timestamp,
+  updated_at text default current_timestamp
+) strict;
+
+create index chapter_study on chapter (study_id, ord);
@@ -0,0 +1,37 @@
+-- Players and events, shared by the games that name them.
+create table player (
+  id integer primary key,
+  name text not null unique
+) strict;
+
+create table event (
+  id integer primary key,
+  name text not null unique
+) strict;
+
+alter table game add column white_id integer references player (id);
+alter table game add column black_id integer references player (id);
+alter table game add column event_id integer references event (id);
+alter table game add column round text;
+-- Mainline moves in SAN, separated by spaces.
+alter table game add column movetext text;
+alter table game add column ply_count integer;
+-- Where the game came from, e.g. the name of the imported file.
+alter table game add column source text;
+-- Hash of the normalized players, date and event, to find copies of a game. Copies are then
+-- told apart by comparing the moves, so that truncated copies match too. The key is computed
+-- by the app and filled in for older games.
+alter table game add column match_key integer;
+
+insert or ignore into player (name) select white from game union select black from game;
+insert or ignore into event (name) select event from game where event is not null;
+update game set
+  white_id = (select id from player where name = game.white),
+  black_id = (select id from player where name = game.black),
+  event_id = (select id from event where name = game.event),
+  ply_count = (select max(ply) from position where position.game_id = game.id);
+
+create index game_white on game (white_id);
+create index game_black on game (black_id);
+create index game_event on game (event_id);
+create index game_match_key on game (match_key);
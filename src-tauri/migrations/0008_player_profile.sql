@@ -0,0 +1,8 @@
+-- Player profiles. Another name of a player, like "Carlsen, M" or an online handle, points to
+-- their profile with alias_of; profiles themselves have none.
+alter table player add column alias_of integer references player (id) on delete set null;
+alter table player add column fide_id integer;
+alter table player add column federation text;
+
+create index player_alias_of on player (alias_of);
+create index player_fide_id on player (fide_id);
@@ -0,0 +1,354 @@
+//! The Tauri app: its state, the engine behind the analysis board and the commands.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use shakmaty::{
+    fen::Fen, san::San, uci::UciMove, CastlingMode, Chess, Color, FromSetup, Position, Setup,
+};
+use sqlx::{migrate::MigrateDatabase, Pool, Sqlite, SqlitePool};
+use tauri::{Builder, Manager, State};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, Mutex},
+};
+use tracing::{debug, error, Instrument};
+
+use crate::{
+    chess::{
+        self,
+        openings::{find_opening, OpeningBook},
+        search, Engine, Go, Info, Search,
+    },
+    db::{self, Database},
+};
+
+pub struct AppState {
+    manager: Arc<Mutex<EngineManager>>,
+    client_restart_count: AtomicUsize,
+    pub(crate) db: Database,
+    pub(crate) match_stop: Arc<AtomicBool>,
+    pub(crate) play: Mutex<Option<mpsc::Sender<chess::play::PlayOp>>>,
+    pub(crate) openings: std::sync::RwLock<Arc<OpeningBook>>,
+    pub(crate) drill: Mutex<Option<db::drill::DrillSession>>,
+    pub(crate) import_stop: Arc<AtomicBool>,
+    pub(crate) pattern_stop: Arc<AtomicBool>,
+}
+
+static mut CALL_COUNT: usize = 0;
+
+enum Op {
+    Go(Go),
+    NewGame,
+}
+
+struct EngineEntry {
+    tx: mpsc::Sender<Op>,
+    stop_tx: mpsc::Sender<oneshot::Sender<()>>,
+    is_searching: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct EngineManager {
+    engines: Vec<EngineEntry>,
+}
+
+fn uci_to_san(chess: &mut Chess, uci_move: &str) -> anyhow::Result<String> {
+    let m = uci_move.parse::<UciMove>()?.to_move(chess)?;
+    chess.play_unchecked(m);
+    Ok(San::from_move(chess, m).to_string())
+}
+
+fn prettyfy(fen: &str, info: &mut Info) -> anyhow::Result<()> {
+    let fen = fen.parse::<Fen>()?;
+    let mut chess: Chess = fen.into_position(CastlingMode::Standard)?;
+
+    for uci_move in info.pv.iter_mut() {
+        *uci_move = uci_to_san(&mut chess, uci_move)?;
+    }
+    Ok(())
+}
+
+async fn controller(
+    mut engine: Engine,
+    mut rx: mpsc::Receiver<Op>,
+    mut stop_rx: mpsc::Receiver<oneshot::Sender<()>>,
+    chan: tauri::ipc::Channel<Info>,
+    is_searching: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    while let Some(op) = rx.recv().await {
+        match op {
+            Op::Go(job) => {
+                debug!("new job");
+                engine.tx.send(job.to_cmd()).await?;
+                is_searching.store(true, Ordering::SeqCst);
+
+                loop {
+                    select! {
+                        Some(line) = engine.rx.recv() => match search(&line)? {
+                            Some(Search::Info(mut info)) => {
+                                prettyfy(job.fen.as_ref().unwrap(), &mut info)?;
+                                chan.send(info)?;
+                            },
+                            Some(Search::BestMove(_)) => {},
+                            None => continue,
+                        },
+                        Some(ack) = stop_rx.recv() => {
+                            engine.stop().await?;
+                            debug!("engine stop");
+                            _ = ack.send(());
+                            break;
+                        },
+                        else => break,
+                    }
+                }
+                is_searching.store(false, Ordering::SeqCst);
+            }
+            Op::NewGame => {
+                engine.tx.send("ucinewgame".into()).await?;
+                engine.isready().await?;
+                debug!("NEW GAME READY");
+            }
+        }
+    }
+    Ok(())
+}
+
+impl EngineManager {
+    async fn start_engine(&mut self, chan: tauri::ipc::Channel<Info>) -> anyhow::Result<()> {
+        if !self.engines.is_empty() {
+            return Ok(());
+        }
+
+        let mut engine = Engine::new("stockfish")?;
+        let opts = [("Threads", "8"), ("UCI_ShowWDL", "true"), ("MultiPV", "3")];
+        engine.uci().await?;
+        engine.opts(&opts).await?;
+        engine.isready().await?;
+
+        let (tx, rx) = mpsc::channel(32);
+        let (stop_tx, stop_rx) = mpsc::channel(1);
+        let is_searching = Arc::new(AtomicBool::new(false));
+
+        self.engines.push(EngineEntry {
+            tx,
+            stop_tx,
+            is_searching: is_searching.clone(),
+        });
+
+        tauri::async_runtime::spawn(
+            async move {
+                if let Err(e) = controller(engine, rx, stop_rx, chan, is_searching).await {
+                    tracing::error!(cause = %e, "controller error");
+                }
+            }
+            .instrument(tracing::trace_span!("controller")),
+        );
+
+        Ok(())
+    }
+
+    async fn go(&mut self, fen: &str) -> anyhow::Result<()> {
+        let EngineEntry {
+            tx,
+            stop_tx,
+            is_searching,
+        } = &mut self.engines[0];
+
+        debug!(?is_searching);
+
+        if is_searching.load(Ordering::SeqCst) {
+            let (ack, syn) = oneshot::channel();
+            stop_tx.send(ack).await?;
+            syn.await?;
+        }
+
+        let job = Go::new().fen(fen).depth(26);
+        tx.send(Op::Go(job)).await?;
+
+        Ok(())
+    }
+
+    async fn new_game(&mut self) -> anyhow::Result<()> {
+        let EngineEntry {
+            tx,
+            stop_tx,
+            is_searching,
+        } = &mut self.engines[0];
+
+        if is_searching.load(Ordering::SeqCst) {
+            let (ack, syn) = oneshot::channel();
+            stop_tx.send(ack).await?;
+            syn.await?;
+        }
+
+        tx.send(Op::NewGame).await?;
+
+        Ok(())
+    }
+}
+
+#[tauri::command]
+async fn start_engine(
+    state: State<'_, AppState>,
+    chan: tauri::ipc::Channel<Info>,
+) -> Result<(), String> {
+    let n = state
+        .client_restart_count
+        .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+    if n == 0 {
+        debug!("start engine");
+        state.manager.lock().await.start_engine(chan).await.unwrap();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn go(fen: &str, state: State<'_, AppState>) -> Result<(), String> {
+    state.manager.lock().await.go(fen).await.unwrap();
+    Ok(())
+}
+
+#[tauri::command]
+async fn new_game(state: State<'_, AppState>) -> Result<(), String> {
+    state.manager.lock().await.new_game().await.unwrap();
+    Ok(())
+}
+
+#[tauri::command]
+fn test_what() -> &'static str {
+    println!("test what");
+    "Hello World!"
+}
+
+#[tauri::command]
+fn test_obj(value: serde_json::Map<String, serde_json::Value>) {
+    debug!(?value);
+}
+
+fn setup(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error>> {
+    setup_logging();
+
+    let dir = std::env::home_dir()
+        .context("home env variable is not set")?
+        .join(".escacs");
+
+    std::fs::create_dir_all(&dir)?;
+
+    let db = tauri::async_runtime::block_on(Database::connect_and_migrate(dir.join("data.db")))?;
+
+    let state = AppState {
+        manager: Arc::new(Mutex::new(EngineManager::default())),
+        client_restart_count: AtomicUsize::default(),
+        db,
+        match_stop: Arc::default(),
+        import_stop: Arc::default(),
+        pattern_stop: Arc::default(),
+        play: Mutex::default(),
+        openings: std::sync::RwLock::new(OpeningBook::bundled()),
+        drill: Mutex::default(),
+    };
+
+    app.manage(state);
+    Ok(())
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_http::init())
+        .invoke_handler(tauri::generate_handler![
+            start_engine,
+            go,
+            new_game,
+            test_what,
+            find_opening,
+            chess::openings::find_game_opening,
+            chess::openings::load_opening_files,
+            chess::openings::opening_continuations,
+            chess::openings::search_openings,
+            chess::time_usage::analyse_time_usage,
+            chess::matches::start_match,
+            chess::matches::stop_match,
+            chess::play::play_start,
+            chess::play::play_move,
+            chess::play::play_takeback,
+            chess::play::play_resign,
+            chess::play::play_offer_draw,
+            chess::play::play_state,
+            chess::polyglot::build_polyglot_book,
+            chess::polyglot::polyglot_moves,
+            test_obj,
+            db::study::insert_study,
+            db::study::get_studies,
+            db::study::get_study,
+            db::study::update_study,
+            db::study::rename_study,
+            db::study::duplicate_study,
+            db::study::delete_study,
+            db::study::get_chapters,
+            db::study::import_study_pgn,
+            db::study::export_study_pgn,
+            db::classify_games,
+            db::games::import_games,
+            db::games::stop_import,
+            db::duplicates::merge_duplicate_games,
+            db::players::find_players,
+            db::players::get_player,
+            db::players::set_player_details,
+            db::players::set_player_alias,
+            db::players::player_alias_suggestions,
+            db::players::player_stats,
+            db::search::search_games,
+            db::search::position_moves,
+            db::pattern::search_pattern,
+            db::pattern::stop_pattern_search,
+            db::explorer::explore_position,
+            db::explorer::games_by_opening,
+            db::repertoire::create_repertoire,
+            db::repertoire::get_repertoires,
+            db::repertoire::delete_repertoire,
+            db::repertoire::import_repertoire,
+            db::repertoire::repertoire_moves,
+            db::repertoire::add_repertoire_move,
+            db::repertoire::remove_repertoire_move,
+            db::repertoire::repertoire_coverage,
+            db::repertoire::check_repertoire,
+            db::drill::start_drill,
+            db::drill::drill_move,
+            db::drill::due_reviews,
+            db::drill::drill_line_stats,
+        ])
+        .setup(setup)
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+fn setup_logging() {
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::filter::EnvFilter;
+
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::TRACE.into())
+        .from_env_lossy()
+        .add_directive("escacs_lib::chess::engine=debug".parse().unwrap())
+        .add_directive(
+            "tao::platform_impl::platform::window_delegate=info"
+                .parse()
+                .unwrap(),
+        );
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .without_time()
+        .with_target(true)
+        .compact()
+        .init();
+}
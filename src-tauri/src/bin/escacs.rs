@@ -0,0 +1,359 @@
+//! Headless batch analysis of PGN files.
+//!
+//! ```text
+//! escacs games.pgn --depth 18 --jobs 4 -o annotated.pgn --report report.csv
+//! ```
+//!
+//! It does not need Tauri, so servers without a desktop stack build it with
+//! `cargo build --release --no-default-features --bin escacs`.
+
+use std::{fmt::Write, fs::File, io::BufReader, path::PathBuf, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use escacs_lib::chess::{
+    analysis::{analyse_game, annotate, Summary},
+    openings::OpeningBook,
+    pgn::{read_games, Game},
+    Engine, Go,
+};
+use shakmaty::Color;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+const USAGE: &str = "usage: escacs <games.pgn> [options]
+
+options:
+  --engine <path>         UCI engine to run (default: stockfish)
+  --depth <n>             search depth per position (default: 16)
+  --nodes <n>             search nodes per position, instead of depth
+  --jobs <n>              number of engines analysing in parallel (default: 1)
+  --threads <n>           Threads option of each engine (default: 1)
+  --hash <mb>             Hash option of each engine
+  --option <name=value>   any other engine option, may be repeated
+  -o, --output <path>     annotated PGN output (default: stdout)
+  --report <path>         per-game report, .json or .csv";
+
+struct Args {
+    input: PathBuf,
+    engine: String,
+    depth: u32,
+    nodes: Option<u64>,
+    jobs: usize,
+    options: Vec<(String, String)>,
+    output: Option<PathBuf>,
+    report: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut args = std::env::args().skip(1);
+        let mut input = None;
+        let mut parsed = Self {
+            input: PathBuf::new(),
+            engine: "stockfish".into(),
+            depth: 16,
+            nodes: None,
+            jobs: 1,
+            options: vec![("Threads".into(), "1".into())],
+            output: None,
+            report: None,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().with_context(|| format!("{name} needs a value"));
+            match arg.as_str() {
+                "--engine" => parsed.engine = value("--engine")?,
+                "--depth" => parsed.depth = value("--depth")?.parse()?,
+                "--nodes" => parsed.nodes = Some(value("--nodes")?.parse()?),
+                "--jobs" => parsed.jobs = value("--jobs")?.parse::<usize>()?.max(1),
+                "--threads" => parsed.set_option("Threads", value("--threads")?),
+                "--hash" => parsed.set_option("Hash", value("--hash")?),
+                "--option" => {
+                    let option = value("--option")?;
+                    let (name, v) = option
+                        .split_once('=')
+                        .context("--option expects name=value")?;
+                    parsed.set_option(name, v.into());
+                }
+                "-o" | "--output" => parsed.output = Some(value("--output")?.into()),
+                "--report" => parsed.report = Some(value("--report")?.into()),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                other if other.starts_with('-') => bail!("unknown option {other}\n\n{USAGE}"),
+                other => input = Some(PathBuf::from(other)),
+            }
+        }
+
+        parsed.input = input.context(USAGE)?;
+        Ok(parsed)
+    }
+
+    fn set_option(&mut self, name: &str, value: String) {
+        self.options.retain(|(n, _)| n != name);
+        self.options.push((name.into(), value));
+    }
+
+    fn job(&self) -> Go {
+        match self.nodes {
+            Some(nodes) => Go::new().nodes(nodes),
+            None => Go::new().depth(self.depth),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct GameReport {
+    index: usize,
+    white: String,
+    black: String,
+    event: String,
+    date: String,
+    result: String,
+    eco: String,
+    opening: String,
+    white_summary: Summary,
+    black_summary: Summary,
+    error: Option<String>,
+}
+
+struct Analysed {
+    report: GameReport,
+    pgn: Option<String>,
+}
+
+async fn analyse(engine: &mut Engine, index: usize, mut game: Game, job: &Go) -> Analysed {
+    if let Some(c) = OpeningBook::bundled().classify_game(&game) {
+        c.tag(&mut game);
+    }
+    let tag = |name| game.tag(name).unwrap_or("?").to_string();
+    let mut report = GameReport {
+        index,
+        white: tag("White"),
+        black: tag("Black"),
+        event: tag("Event"),
+        date: tag("Date"),
+        result: tag("Result"),
+        eco: tag("ECO"),
+        opening: tag("Opening"),
+        ..Default::default()
+    };
+
+    let annotated = async {
+        engine.tx.send("ucinewgame".into()).await?;
+        engine.isready().await?;
+        let evals = analyse_game(engine, &game, job).await?;
+        report.white_summary = Summary::new(&evals, Color::White);
+        report.black_summary = Summary::new(&evals, Color::Black);
+        annotate(&game, &evals)
+    };
+
+    match annotated.await {
+        Ok(pgn) => Analysed {
+            report,
+            pgn: Some(pgn),
+        },
+        Err(e) => {
+            warn!(index, cause = %e, "failed to analyse game");
+            report.error = Some(e.to_string());
+            Analysed { report, pgn: None }
+        }
+    }
+}
+
+/// Games read from the input by their index from 1, shared by the workers.
+type GameQueue = Arc<Mutex<mpsc::Receiver<(usize, Result<Game>)>>>;
+
+async fn worker(args: Arc<Args>, games: GameQueue, results: mpsc::Sender<Analysed>) -> Result<()> {
+    let mut engine = Engine::new(&args.engine)?;
+    let analysed = analyse_all(&mut engine, &args, games, results).await;
+    engine.kill().await?;
+    analysed
+}
+
+async fn analyse_all(
+    engine: &mut Engine,
+    args: &Args,
+    games: GameQueue,
+    results: mpsc::Sender<Analysed>,
+) -> Result<()> {
+    engine.uci().await?;
+    engine.opts(&args.options).await?;
+    engine.isready().await?;
+
+    let job = args.job();
+    loop {
+        let next = games.lock().await.recv().await;
+        let Some((index, game)) = next else {
+            break;
+        };
+        let analysed = match game {
+            Ok(game) => analyse(engine, index, game, &job).await,
+            Err(e) => Analysed {
+                report: GameReport {
+                    index,
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                },
+                pgn: None,
+            },
+        };
+        info!(index, "analysed game");
+        results.send(analysed).await?;
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+fn write_csv(reports: &[GameReport]) -> String {
+    let mut csv = String::from(
+        "index,white,black,event,date,result,eco,opening,white_accuracy,white_acpl,black_accuracy,black_acpl,error\n",
+    );
+    for r in reports {
+        _ = writeln!(
+            &mut csv,
+            "{},{},{},{},{},{},{},{},{:.1},{:.1},{:.1},{:.1},{}",
+            r.index,
+            csv_field(&r.white),
+            csv_field(&r.black),
+            csv_field(&r.event),
+            csv_field(&r.date),
+            r.result,
+            r.eco,
+            csv_field(&r.opening),
+            r.white_summary.accuracy,
+            r.white_summary.acpl,
+            r.black_summary.accuracy,
+            r.black_summary.acpl,
+            csv_field(r.error.as_deref().unwrap_or_default()),
+        );
+    }
+    csv
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    setup_logging();
+
+    let args = Arc::new(Args::parse()?);
+    let file =
+        File::open(&args.input).with_context(|| format!("failed to open {:?}", args.input))?;
+
+    let (game_tx, game_rx) = mpsc::channel(args.jobs * 2);
+    let (result_tx, mut result_rx) = mpsc::channel(args.jobs * 2);
+    let game_rx = Arc::new(Mutex::new(game_rx));
+
+    let mut workers = Vec::with_capacity(args.jobs);
+    for _ in 0..args.jobs {
+        let (args, games, results) = (args.clone(), game_rx.clone(), result_tx.clone());
+        workers.push(tokio::spawn(worker(args, games, results)));
+    }
+    // Only the workers hold these, so a failed engine pool stops the reader and the collector.
+    drop(game_rx);
+    drop(result_tx);
+
+    // Parsing is blocking, so feed the workers from a separate thread.
+    let reader = tokio::task::spawn_blocking(move || {
+        for (index, game) in read_games(BufReader::new(file)).enumerate() {
+            if game_tx.blocking_send((index + 1, game)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut analysed = Vec::new();
+    while let Some(a) = result_rx.recv().await {
+        analysed.push(a);
+    }
+    reader.await?;
+    let mut errors = Vec::new();
+    for w in workers {
+        if let Err(e) = w.await? {
+            tracing::error!(cause = %e, "worker error");
+            errors.push(e);
+        }
+    }
+    // A failed engine leaves games unanalysed, so leave the output alone and fail the run.
+    let failed = errors.len();
+    if let Some(e) = errors.into_iter().next() {
+        return Err(e.context(format!("{failed} of {} workers failed", args.jobs)));
+    }
+    analysed.sort_by_key(|a| a.report.index);
+
+    let pgn = analysed
+        .iter()
+        .filter_map(|a| a.pgn.as_deref())
+        .collect::<Vec<_>>()
+        .join("\n");
+    match &args.output {
+        Some(path) => std::fs::write(path, pgn)?,
+        None => print!("{pgn}"),
+    }
+
+    if let Some(path) = &args.report {
+        let reports = analysed.into_iter().map(|a| a.report).collect::<Vec<_>>();
+        let report = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => write_csv(&reports),
+            _ => serde_json::to_string_pretty(&reports)?,
+        };
+        std::fs::write(path, report)?;
+    }
+
+    Ok(())
+}
+
+fn setup_logging() {
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::filter::EnvFilter;
+
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .without_time()
+        .with_target(false)
+        .compact()
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("Carlsen"), "Carlsen");
+        assert_eq!(csv_field("Carlsen, Magnus"), "\"Carlsen, Magnus\"");
+        assert_eq!(csv_field("the \"Immortal\""), "\"the \"\"Immortal\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+
+        let reports = [GameReport {
+            index: 1,
+            white: "Anderssen, Adolf".into(),
+            black: "Kieseritzky".into(),
+            result: "1-0".into(),
+            error: Some("illegal move \"Qxh9\"".into()),
+            ..Default::default()
+        }];
+        let csv = write_csv(&reports);
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("index,white,black,"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "1,\"Anderssen, Adolf\",Kieseritzky,,,1-0,,,0.0,0.0,0.0,0.0,\"illegal move \"\"Qxh9\"\"\""
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::Result;
+use shakmaty::{san::San, uci::UciMove, Chess, Color, Position};
+
+use super::{
+    pgn::{format_duration, write_tags, Game, MovetextWriter},
+    Engine, Go, Score, MATE_CP,
+};
+
+/// Evaluations are clamped to this many centipawns before computing losses.
+pub const CP_CEILING: i32 = 1000;
+
+/// Lichess win percentage (0..100) for a centipawn evaluation.
+pub fn win_pct(cp: i32) -> f64 {
+    let cp = cp.clamp(-CP_CEILING, CP_CEILING) as f64;
+    50.0 + 50.0 * (2.0 / (1.0 + (-0.00368208 * cp).exp()) - 1.0)
+}
+
+/// Lichess move accuracy (0..100) from the mover's win percentage before and after the move.
+pub fn move_accuracy(win_before: f64, win_after: f64) -> f64 {
+    if win_after >= win_before {
+        return 100.0;
+    }
+    let delta = win_before - win_after;
+    (103.1668100711649 * (-0.04354415386753951 * delta).exp() - 3.166924740191411 + 1.0)
+        .clamp(0.0, 100.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Judgement {
+    Best,
+    Excellent,
+    Good,
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl Judgement {
+    pub fn from_cp_loss(cp_loss: i32) -> Self {
+        match cp_loss {
+            ..=10 => Self::Best,
+            11..=25 => Self::Excellent,
+            26..=50 => Self::Good,
+            51..=100 => Self::Inaccuracy,
+            101..=200 => Self::Mistake,
+            _ => Self::Blunder,
+        }
+    }
+
+    /// The `?!`, `?` or `??` glyph for moves worse than good.
+    pub fn nag(&self) -> Option<u8> {
+        match self {
+            Self::Inaccuracy => Some(6),
+            Self::Mistake => Some(2),
+            Self::Blunder => Some(4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MoveEval {
+    pub ply: usize,
+    pub san: String,
+    pub is_white: bool,
+    /// Evaluation before the move, from White's point of view.
+    pub before: Score,
+    /// Evaluation after the move, from White's point of view.
+    pub after: Score,
+    /// The engine's preferred move in the position before the move, in SAN.
+    pub best: Option<String>,
+    pub cp_loss: i32,
+    pub accuracy: f64,
+    pub judgement: Judgement,
+}
+
+impl MoveEval {
+    pub fn color(&self) -> Color {
+        Color::from_white(self.is_white)
+    }
+}
+
+/// Score of a finished position from White's point of view, without asking the engine. The
+/// side to move has lost if checkmated.
+pub fn terminal_score(pos: &Chess) -> Option<Score> {
+    if pos.is_checkmate() {
+        Some(Score::Cp(pos.turn().fold_wb(-MATE_CP, MATE_CP)))
+    } else if pos.is_stalemate() || pos.is_insufficient_material() {
+        Some(Score::Cp(0))
+    } else {
+        None
+    }
+}
+
+/// Evaluates every position of the game's mainline and grades each move.
+///
+/// `job` carries the search limits; its position is replaced for every search.
+pub async fn analyse_game(engine: &mut Engine, game: &Game, job: &Go) -> Result<Vec<MoveEval>> {
+    let mut pos = game.start_position()?;
+    let uci = game.uci_moves();
+
+    // Evaluations of positions 0..=n from White's point of view, with the engine's best move.
+    let mut evals = Vec::with_capacity(uci.len() + 1);
+    for i in 0..=uci.len() {
+        let (score, best) = match terminal_score(&pos) {
+            Some(score) => (score, None),
+            None => {
+                let mut search = job.clone();
+                search.fen = game.fen().map(Into::into);
+                search.moves = uci[..i].to_vec();
+                let (info, best) = engine.go(search).await?;
+                let best = best
+                    .best
+                    .parse::<UciMove>()
+                    .ok()
+                    .and_then(|m| m.to_move(&pos).ok())
+                    .map(|m| San::from_move(&pos, m).to_string());
+                let mut score = info.score;
+                score.normalize(pos.turn() == Color::White);
+                (score, best)
+            }
+        };
+        evals.push((score, best));
+
+        if let Some(m) = uci.get(i) {
+            let m = m.parse::<UciMove>()?.to_move(&pos)?;
+            pos.play_unchecked(m);
+        }
+    }
+
+    Ok(grade(game, &evals))
+}
+
+/// Grades each move of the game from the White-relative evaluations of the positions before
+/// and after it, with the engine's best move in each.
+fn grade(game: &Game, evals: &[(Score, Option<String>)]) -> Vec<MoveEval> {
+    game.moves
+        .iter()
+        .zip(evals.windows(2))
+        .map(|(m, w)| {
+            let (before, best) = w[0].clone();
+            let (after, _) = w[1].clone();
+            let sign = if m.color.is_white() { 1 } else { -1 };
+            let cp_before = sign * before.as_cp().clamp(-CP_CEILING, CP_CEILING);
+            let cp_after = sign * after.as_cp().clamp(-CP_CEILING, CP_CEILING);
+            let cp_loss = (cp_before - cp_after).max(0);
+            MoveEval {
+                ply: m.ply,
+                san: m.san.clone(),
+                is_white: m.color.is_white(),
+                before,
+                after,
+                best,
+                cp_loss,
+                accuracy: move_accuracy(win_pct(cp_before), win_pct(cp_after)),
+                judgement: Judgement::from_cp_loss(cp_loss),
+            }
+        })
+        .collect()
+}
+
+/// Per-player totals over the graded moves of a game.
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Summary {
+    pub moves: usize,
+    pub accuracy: f64,
+    /// Average centipawn loss.
+    pub acpl: f64,
+    pub inaccuracies: usize,
+    pub mistakes: usize,
+    pub blunders: usize,
+}
+
+impl Summary {
+    pub fn new(evals: &[MoveEval], color: Color) -> Self {
+        let mut summary = Self::default();
+        let mut accuracy = 0.0;
+        let mut loss = 0;
+        for e in evals.iter().filter(|e| e.color() == color) {
+            summary.moves += 1;
+            accuracy += e.accuracy;
+            loss += e.cp_loss;
+            match e.judgement {
+                Judgement::Inaccuracy => summary.inaccuracies += 1,
+                Judgement::Mistake => summary.mistakes += 1,
+                Judgement::Blunder => summary.blunders += 1,
+                _ => {}
+            }
+        }
+        if summary.moves > 0 {
+            summary.accuracy = accuracy / summary.moves as f64;
+            summary.acpl = loss as f64 / summary.moves as f64;
+        }
+        summary
+    }
+}
+
+/// Formats a White-relative score as a `[%eval]` argument, e.g. `0.34` or `#-3`.
+pub fn eval_command(score: Score) -> String {
+    match score {
+        Score::Cp(cp) => format!("{:.2}", cp as f64 / 100.0),
+        Score::Mate(n) => format!("#{n}"),
+    }
+}
+
+/// Writes the game back as PGN with `[%eval]` comments and glyphs for inaccurate moves.
+pub fn annotate(game: &Game, evals: &[MoveEval]) -> Result<String> {
+    let start = game.start_position()?;
+    let mut out = String::new();
+    write_tags(&mut out, &game.tags);
+    out.push('\n');
+
+    let mut w = MovetextWriter::default();
+    let mut number = start.fullmoves().get();
+    for (m, e) in game.moves.iter().zip(evals) {
+        // Every move carries a comment, so Black's moves always repeat the number.
+        match m.color {
+            Color::White => w.token(&format!("{number}.")),
+            Color::Black => w.token(&format!("{number}...")),
+        }
+        w.token(&m.san);
+        if let Some(nag) = e.judgement.nag() {
+            w.token(&format!("${nag}"));
+        }
+
+        let mut comment = format!("[%eval {}]", eval_command(e.after));
+        if let Some(clock) = m.clock {
+            comment.push_str(&format!(" [%clk {}]", format_duration(clock)));
+        }
+        if let Some(text) = &m.comment {
+            comment.push(' ');
+            comment.push_str(text);
+        }
+        if let (Some(_), Some(best)) = (e.judgement.nag(), &e.best) {
+            comment.push_str(&format!(" {:?}. {best} was best.", e.judgement));
+        }
+        w.comment(&comment);
+
+        if m.color.is_black() {
+            number += 1;
+        }
+    }
+    w.token(game.tag("Result").unwrap_or("*"));
+    out.push_str(&w.finish());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::pgn::read_games;
+
+    #[test]
+    fn accuracy_bounds() {
+        assert_eq!(win_pct(0), 50.0);
+        assert!(win_pct(5000) > 97.0 && win_pct(5000) == win_pct(CP_CEILING));
+        assert_eq!(move_accuracy(60.0, 70.0), 100.0);
+        assert!(move_accuracy(90.0, 10.0) < 5.0);
+        assert_eq!(Judgement::from_cp_loss(0), Judgement::Best);
+        assert_eq!(Judgement::from_cp_loss(150), Judgement::Mistake);
+        assert_eq!(Judgement::from_cp_loss(900), Judgement::Blunder);
+    }
+
+    #[test]
+    fn mating_move() {
+        for (pgn, winner) in [
+            ("1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7# 1-0", Color::White),
+            ("1. f3 e5 2. g4 Qh4# 0-1", Color::Black),
+        ] {
+            let game = read_games(pgn.as_bytes()).next().unwrap().unwrap();
+            let positions = game.positions().unwrap();
+            let last = positions.last().unwrap();
+            let mate = terminal_score(last).unwrap();
+            assert_eq!(mate.as_cp(), winner.fold_wb(MATE_CP, -MATE_CP));
+
+            let mut evals = vec![(Score::Cp(0), None); positions.len() - 1];
+            evals.push((mate, None));
+            let graded = grade(&game, &evals);
+            let mating = graded.last().unwrap();
+            assert_eq!(mating.color(), winner);
+            assert_eq!(mating.judgement, Judgement::Best);
+        }
+    }
+}
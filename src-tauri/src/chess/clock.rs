@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+
+use shakmaty::{ByColor, Color};
+
+use super::{engine::GoClock, time_usage::parse_time_control};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockMode {
+    /// The increment is added after every move.
+    #[default]
+    Fischer,
+    /// The time used is given back, up to the increment.
+    Bronstein,
+    /// The clock only starts counting down after the increment has elapsed.
+    Delay,
+}
+
+/// Base time and increment, in milliseconds.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct TimeControl {
+    pub base: u64,
+    pub increment: u64,
+    #[serde(default)]
+    pub mode: ClockMode,
+}
+
+impl TimeControl {
+    /// The PGN `TimeControl` tag value, e.g. `180+2`.
+    pub fn tag(&self) -> String {
+        let base = self.base as f64 / 1000.0;
+        let increment = self.increment as f64 / 1000.0;
+        format!("{base}+{increment}")
+    }
+}
+
+/// Game speed categories, as used by Lichess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Speed {
+    UltraBullet,
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+}
+
+impl Speed {
+    /// Category by estimated game duration, the base time plus 40 increments.
+    pub fn new(base: Duration, increment: Duration) -> Self {
+        match (base + increment * 40).as_secs() {
+            0..30 => Self::UltraBullet,
+            30..180 => Self::Bullet,
+            180..480 => Self::Blitz,
+            480..1500 => Self::Rapid,
+            _ => Self::Classical,
+        }
+    }
+
+    /// The category of a PGN `TimeControl` tag.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        parse_time_control(tag).map(|(base, increment)| Self::new(base, increment))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UltraBullet => "ultrabullet",
+            Self::Bullet => "bullet",
+            Self::Blitz => "blitz",
+            Self::Rapid => "rapid",
+            Self::Classical => "classical",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    tc: TimeControl,
+    remaining: ByColor<Duration>,
+    running: Option<(Color, Instant)>,
+}
+
+impl Clock {
+    pub fn new(tc: TimeControl) -> Self {
+        let base = Duration::from_millis(tc.base);
+        Self {
+            tc,
+            remaining: ByColor {
+                white: base,
+                black: base,
+            },
+            running: None,
+        }
+    }
+
+    pub fn remaining(&self, color: Color) -> Duration {
+        *self.remaining.get(color)
+    }
+
+    /// Remaining time including the time elapsed since `color`'s clock was started.
+    pub fn remaining_now(&self, color: Color) -> Duration {
+        match self.running {
+            Some((c, since)) if c == color => self
+                .remaining(color)
+                .saturating_sub(self.charged(since.elapsed())),
+            _ => self.remaining(color),
+        }
+    }
+
+    /// A copy of the clock with nothing running, for restoring after a takeback.
+    pub fn snapshot(&self) -> Self {
+        Self {
+            running: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn running(&self) -> Option<Color> {
+        self.running.map(|(c, _)| c)
+    }
+
+    /// Time charged to the clock for a move that took `elapsed`.
+    fn charged(&self, elapsed: Duration) -> Duration {
+        match self.tc.mode {
+            ClockMode::Delay => elapsed.saturating_sub(Duration::from_millis(self.tc.increment)),
+            ClockMode::Fischer | ClockMode::Bronstein => elapsed,
+        }
+    }
+
+    /// Charges `elapsed` to `color` and applies the increment. Returns `false` if the flag fell.
+    pub fn spend(&mut self, color: Color, elapsed: Duration) -> bool {
+        let charged = self.charged(elapsed);
+        let increment = Duration::from_millis(self.tc.increment);
+        let remaining = self.remaining.get_mut(color);
+        if charged >= *remaining {
+            *remaining = Duration::ZERO;
+            return false;
+        }
+        *remaining -= charged;
+        match self.tc.mode {
+            ClockMode::Fischer => *remaining += increment,
+            ClockMode::Bronstein => *remaining += elapsed.min(increment),
+            ClockMode::Delay => {}
+        }
+        true
+    }
+
+    /// Starts counting down `color`'s time.
+    pub fn start(&mut self, color: Color) {
+        self.running = Some((color, Instant::now()));
+    }
+
+    /// Stops the running clock after a move. Returns `false` if the flag fell.
+    pub fn press(&mut self) -> bool {
+        match self.running.take() {
+            Some((color, since)) => self.spend(color, since.elapsed()),
+            None => true,
+        }
+    }
+
+    /// Stops the running clock without applying the increment, e.g. when the game ends.
+    pub fn halt(&mut self) {
+        if let Some((color, since)) = self.running.take() {
+            let charged = self.charged(since.elapsed());
+            let remaining = self.remaining.get_mut(color);
+            *remaining = remaining.saturating_sub(charged);
+        }
+    }
+
+    /// The side whose time has run out, counting the running clock.
+    pub fn flagged(&self) -> Option<Color> {
+        self.running
+            .map(|(c, _)| c)
+            .filter(|&c| self.remaining_now(c).is_zero())
+    }
+
+    /// The clock state as sent to an engine with `go wtime .. btime ..`.
+    ///
+    /// Engines only understand Fischer increments, so delay and Bronstein are sent without one.
+    pub fn go(&self) -> GoClock {
+        let inc = match self.tc.mode {
+            ClockMode::Fischer => self.tc.increment,
+            ClockMode::Bronstein | ClockMode::Delay => 0,
+        };
+        GoClock {
+            wtime: self.remaining.white.as_millis() as u64,
+            btime: self.remaining.black.as_millis() as u64,
+            winc: inc,
+            binc: inc,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(mode: ClockMode) -> Clock {
+        Clock::new(TimeControl {
+            base: 60_000,
+            increment: 2_000,
+            mode,
+        })
+    }
+
+    #[test]
+    fn modes() {
+        let secs = Duration::from_secs;
+
+        let mut fischer = clock(ClockMode::Fischer);
+        assert!(fischer.spend(Color::White, secs(1)));
+        assert_eq!(fischer.remaining(Color::White), secs(61));
+
+        let mut bronstein = clock(ClockMode::Bronstein);
+        assert!(bronstein.spend(Color::White, secs(1)));
+        assert_eq!(bronstein.remaining(Color::White), secs(60));
+        assert!(bronstein.spend(Color::White, secs(5)));
+        assert_eq!(bronstein.remaining(Color::White), secs(57));
+
+        let mut delay = clock(ClockMode::Delay);
+        assert!(delay.spend(Color::Black, secs(1)));
+        assert_eq!(delay.remaining(Color::Black), secs(60));
+        assert!(delay.spend(Color::Black, secs(5)));
+        assert_eq!(delay.remaining(Color::Black), secs(57));
+
+        assert!(!fischer.spend(Color::Black, secs(60)));
+        assert_eq!(fischer.remaining(Color::Black), Duration::ZERO);
+    }
+
+    #[test]
+    fn speeds() {
+        assert_eq!(Speed::from_tag("180+2"), Some(Speed::Blitz));
+        assert_eq!(Speed::from_tag("600+0"), Some(Speed::Rapid));
+        assert_eq!(Speed::from_tag("60+0"), Some(Speed::Bullet));
+        assert_eq!(Speed::from_tag("40/7200:3600"), Some(Speed::Classical));
+        assert_eq!(Speed::from_tag("-"), None);
+    }
+}
@@ -33,6 +33,8 @@ pub struct Engine {
     pub tx: mpsc::Sender<String>,
     pub rx: mpsc::Receiver<String>,
     pub is_searching: bool,
+    /// The reply the engine is pondering on, while a `go ponder` search runs.
+    pondering: Option<String>,
 }
 
 impl Engine {
@@ -65,6 +67,7 @@ impl Engine {
             tx: input_tx,
             rx: output_rx,
             is_searching: false,
+            pondering: None,
         })
     }
 
@@ -109,15 +112,24 @@ impl Engine {
     }
 
     pub async fn go(&mut self, job: Go) -> Result<(Info, BestMove)> {
+        self.cancel_ponder().await?;
         let cmd = job.to_cmd();
         self.tx.send(cmd).await?;
+        self.wait_best().await
+    }
 
+    /// Reads the running search until its best move, keeping the last principal variation.
+    pub async fn wait_best(&mut self) -> Result<(Info, BestMove)> {
         let mut info: Option<Info> = None;
         let mut best: Option<BestMove> = None;
 
         while let Some(line) = self.rx.recv().await {
             match search(&line)? {
-                Some(Search::Info(i)) => info = Some(i),
+                // Skip `currmove` progress lines and secondary MultiPV lines.
+                Some(Search::Info(i)) if i.multipv <= 1 && (i.depth == 0 || !i.pv.is_empty()) => {
+                    info = Some(i)
+                }
+                Some(Search::Info(_)) => continue,
                 Some(Search::BestMove(b)) => {
                     best = Some(b);
                     break;
@@ -132,6 +144,46 @@ impl Engine {
         Ok((info.unwrap(), best.unwrap()))
     }
 
+    /// Starts thinking on the opponent's time, assuming they reply with `expected`.
+    ///
+    /// `job` is the search for the current position; the expected move is appended to it.
+    pub async fn ponder(&mut self, mut job: Go, expected: &str) -> Result<()> {
+        self.cancel_ponder().await?;
+        job.moves.push(expected.into());
+        job.ponder = true;
+        self.tx.send(job.to_cmd()).await?;
+        self.pondering = Some(expected.into());
+        Ok(())
+    }
+
+    pub fn pondering(&self) -> Option<&str> {
+        self.pondering.as_deref()
+    }
+
+    /// Starts the search for the engine's move after the opponent played `played`.
+    ///
+    /// On a ponder hit the running search continues as a normal one with `ponderhit`.
+    /// Otherwise the ponder search is stopped, its best move discarded, and `job` is searched
+    /// from scratch. Returns whether the ponder move was hit. Follow with [`Engine::wait_best`].
+    pub async fn resume(&mut self, played: &str, job: Go) -> Result<bool> {
+        if self.pondering.as_deref() == Some(played) {
+            self.pondering = None;
+            self.tx.send("ponderhit".into()).await?;
+            return Ok(true);
+        }
+        self.cancel_ponder().await?;
+        self.tx.send(job.to_cmd()).await?;
+        Ok(false)
+    }
+
+    /// Stops a ponder search, if any, discarding its best move.
+    pub async fn cancel_ponder(&mut self) -> Result<()> {
+        if self.pondering.take().is_some() {
+            self.stop().await?;
+        }
+        Ok(())
+    }
+
     pub async fn go_with<V: Visitor>(&mut self, job: Go, visitor: &mut V) -> Result<()> {
         println!("go_with");
         let cmd = job.to_cmd();
@@ -158,7 +210,23 @@ impl Engine {
 pub struct Go {
     pub fen: Option<String>,
     pub moves: Vec<String>,
+    /// Depth limit, used only when no other limit is set.
     pub depth: u32,
+    pub nodes: Option<u64>,
+    /// Fixed time per move in milliseconds.
+    pub movetime: Option<u64>,
+    /// Remaining clock times and increments in milliseconds.
+    pub clock: Option<GoClock>,
+    /// Search in ponder mode, until `ponderhit` or `stop`.
+    pub ponder: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GoClock {
+    pub wtime: u64,
+    pub btime: u64,
+    pub winc: u64,
+    pub binc: u64,
 }
 
 impl Go {
@@ -186,6 +254,21 @@ impl Go {
         self
     }
 
+    pub fn nodes(mut self, nodes: u64) -> Self {
+        self.nodes = Some(nodes);
+        self
+    }
+
+    pub fn movetime(mut self, millis: u64) -> Self {
+        self.movetime = Some(millis);
+        self
+    }
+
+    pub fn clock(mut self, clock: GoClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
     pub async fn execute(self, engine: &mut Engine) -> Result<(Info, BestMove)> {
         engine.go(self).await
     }
@@ -205,7 +288,27 @@ impl Go {
         }
         cmd.push('\n');
 
-        _ = writeln!(&mut cmd, "go depth {}", self.depth);
+        cmd.push_str("go");
+        if self.ponder {
+            cmd.push_str(" ponder");
+        }
+        if let Some(c) = self.clock {
+            _ = write!(
+                &mut cmd,
+                " wtime {} btime {} winc {} binc {}",
+                c.wtime, c.btime, c.winc, c.binc
+            );
+        }
+        if let Some(movetime) = self.movetime {
+            _ = write!(&mut cmd, " movetime {movetime}");
+        }
+        if let Some(nodes) = self.nodes {
+            _ = write!(&mut cmd, " nodes {nodes}");
+        }
+        if self.clock.is_none() && self.movetime.is_none() && self.nodes.is_none() {
+            _ = write!(&mut cmd, " depth {}", self.depth);
+        }
+        cmd.push('\n');
 
         cmd
     }
@@ -235,6 +338,8 @@ pub enum Score {
     Mate(i32),
 }
 
+pub const MATE_CP: i32 = 10_000;
+
 impl Default for Score {
     fn default() -> Self {
         Self::Cp(0)
@@ -253,6 +358,15 @@ impl Score {
             _ => {}
         }
     }
+
+    /// Centipawn value of the score, mapping mates to `±MATE_CP` minus the distance to mate.
+    pub fn as_cp(&self) -> i32 {
+        match *self {
+            Score::Cp(n) => n,
+            Score::Mate(n) if n > 0 => MATE_CP - n,
+            Score::Mate(n) => -MATE_CP - n,
+        }
+    }
 }
 
 #[derive(Debug, Default, serde::Serialize)]
@@ -352,9 +466,26 @@ impl FromStr for BestMove {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_name() {
         assert_eq!(1, 1);
     }
+
+    #[test]
+    fn ponder_cmd() {
+        let mut job = Go::new().moves(&["e2e4"]).clock(GoClock {
+            wtime: 1000,
+            btime: 2000,
+            winc: 0,
+            binc: 0,
+        });
+        job.moves.push("e7e5".into());
+        job.ponder = true;
+        assert_eq!(
+            job.to_cmd(),
+            "position startpos moves e2e4 e7e5\ngo ponder wtime 1000 btime 2000 winc 0 binc 0\n"
+        );
+    }
 }
@@ -0,0 +1,727 @@
+//! Engine versus engine matches.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    ByColor, CastlingMode, Chess, Color, EnPassantMode, Position,
+};
+#[cfg(feature = "desktop")]
+use tauri::State;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use super::{
+    clock::{Clock, TimeControl},
+    openings::OpeningBook,
+    pgn::{today, write_tags, MovetextWriter},
+    sprt::{Sprt, SprtConfig, Wdl},
+    Engine, Go, Score, MATE_CP,
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Extra time an engine gets past its clock before it is stopped and forfeits.
+const TIME_MARGIN: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EngineConfig {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub options: Vec<(String, String)>,
+}
+
+impl EngineConfig {
+    pub async fn start(&self) -> Result<Engine> {
+        let mut engine =
+            Engine::new(&self.path).with_context(|| format!("failed to start {}", self.name))?;
+        let ready = async {
+            engine.uci().await?;
+            engine.opts(&self.options).await?;
+            engine.isready().await
+        };
+        if let Err(e) = ready.await {
+            _ = engine.kill().await;
+            return Err(e.context(format!("{} did not start", self.name)));
+        }
+        Ok(engine)
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum OpeningSuite {
+    Startpos,
+    /// One position per line of an EPD file.
+    Epd {
+        path: PathBuf,
+    },
+    /// The bundled ECO openings with at least `min_moves` plies.
+    Bundled {
+        min_moves: usize,
+    },
+}
+
+impl OpeningSuite {
+    pub fn load(&self) -> Result<Vec<String>> {
+        let fens = match self {
+            Self::Startpos => vec![Fen::default().to_string()],
+            Self::Epd { path } => parse_epd(&std::fs::read_to_string(path)?)?,
+            Self::Bundled { min_moves } => OpeningBook::bundled()
+                .openings()
+                .iter()
+                .filter(|o| o.move_count() >= *min_moves)
+                .map(|o| o.fen().to_string())
+                .collect(),
+        };
+        if fens.is_empty() {
+            bail!("opening suite is empty");
+        }
+        Ok(fens)
+    }
+}
+
+/// Reads the positions of an EPD file as FENs, ignoring the operations after the fourth field.
+pub fn parse_epd(epd: &str) -> Result<Vec<String>> {
+    epd.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let fields = line.split_whitespace().take(4).collect::<Vec<_>>();
+            let fen = format!("{} 0 1", fields.join(" "));
+            fen.parse::<Fen>()
+                .ok()
+                .and_then(|f| f.into_position::<Chess>(CastlingMode::Standard).ok())
+                .with_context(|| format!("invalid position on line {}", i + 1))?;
+            Ok(fen)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct ResignRule {
+    /// Consecutive moves of the losing engine at or below `-score`.
+    pub moves: u32,
+    pub score: i32,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct DrawRule {
+    /// First full move at which the rule applies.
+    pub after_move: u32,
+    /// Consecutive moves of both engines within `±score`.
+    pub moves: u32,
+    pub score: i32,
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+pub struct Adjudication {
+    pub resign: Option<ResignRule>,
+    pub draw: Option<DrawRule>,
+    /// Adjudicate positions with at most this many pieces once the engine reports tablebase hits.
+    pub tablebase: Option<u32>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MatchConfig {
+    pub engines: [EngineConfig; 2],
+    pub openings: OpeningSuite,
+    /// Each round plays one opening twice with colours reversed.
+    pub rounds: usize,
+    pub time_control: TimeControl,
+    #[serde(default)]
+    pub adjudication: Adjudication,
+    #[serde(default = "one")]
+    pub concurrency: usize,
+    pub sprt: Option<SprtConfig>,
+    /// Games are appended to this file as they finish.
+    pub pgn: Option<PathBuf>,
+    pub event: Option<String>,
+    /// Let engines think on their opponent's time.
+    #[serde(default)]
+    pub ponder: bool,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum GameResult {
+    #[serde(rename = "1-0")]
+    WhiteWins,
+    #[serde(rename = "0-1")]
+    BlackWins,
+    #[serde(rename = "1/2-1/2")]
+    Draw,
+}
+
+impl GameResult {
+    pub fn win(color: Color) -> Self {
+        match color {
+            Color::White => Self::WhiteWins,
+            Color::Black => Self::BlackWins,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::WhiteWins => "1-0",
+            Self::BlackWins => "0-1",
+            Self::Draw => "1/2-1/2",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayedMove {
+    pub san: String,
+    /// Final score reported by the mover, from its own point of view.
+    pub score: Score,
+    pub depth: u32,
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchGame {
+    pub round: usize,
+    /// Index into `MatchConfig::engines` of the engine playing White.
+    pub white: usize,
+    pub fen: String,
+    pub moves: Vec<PlayedMove>,
+    pub result: GameResult,
+    pub termination: String,
+}
+
+struct Job {
+    round: usize,
+    white: usize,
+    fen: String,
+}
+
+/// Tracks the consecutive-move conditions of the adjudication rules.
+#[derive(Default)]
+struct Adjudicator {
+    resign: ByColor<u32>,
+    draw: u32,
+}
+
+impl Adjudicator {
+    fn update(
+        &mut self,
+        rules: &Adjudication,
+        pos: &Chess,
+        mover: Color,
+        score: Score,
+        tbhits: u64,
+    ) -> Option<(GameResult, &'static str)> {
+        let cp = score.as_cp();
+
+        if let Some(pieces) = rules.tablebase {
+            if tbhits > 0 && pos.board().occupied().count() as u32 <= pieces {
+                if cp == 0 {
+                    return Some((GameResult::Draw, "tablebase draw"));
+                }
+                if cp.abs() >= MATE_CP - 1000 {
+                    let winner = if cp > 0 { mover } else { !mover };
+                    return Some((GameResult::win(winner), "tablebase win"));
+                }
+            }
+        }
+
+        if let Some(rule) = rules.resign {
+            let count = self.resign.get_mut(mover);
+            *count = if cp <= -rule.score { *count + 1 } else { 0 };
+            if *count >= rule.moves {
+                return Some((GameResult::win(!mover), "adjudication"));
+            }
+        }
+
+        if let Some(rule) = rules.draw {
+            let applies = pos.fullmoves().get() >= rule.after_move && cp.abs() <= rule.score;
+            self.draw = if applies { self.draw + 1 } else { 0 };
+            if self.draw >= rule.moves * 2 {
+                return Some((GameResult::Draw, "adjudication"));
+            }
+        }
+
+        None
+    }
+}
+
+/// Result of the game by the rules alone, before asking the engine to move.
+pub fn rules_result(pos: &Chess, repetitions: u32) -> Option<(GameResult, &'static str)> {
+    if pos.is_checkmate() {
+        Some((GameResult::win(!pos.turn()), "checkmate"))
+    } else if pos.is_stalemate() {
+        Some((GameResult::Draw, "stalemate"))
+    } else if pos.is_insufficient_material() {
+        Some((GameResult::Draw, "insufficient material"))
+    } else if pos.halfmoves() >= 100 {
+        Some((GameResult::Draw, "fifty-move rule"))
+    } else if repetitions >= 3 {
+        Some((GameResult::Draw, "threefold repetition"))
+    } else {
+        None
+    }
+}
+
+async fn play_game(
+    engines: &mut [Engine; 2],
+    job: &Job,
+    config: &MatchConfig,
+) -> Result<MatchGame> {
+    let mut pos: Chess = job
+        .fen
+        .parse::<Fen>()?
+        .into_position(CastlingMode::Standard)?;
+    let mut clock = Clock::new(config.time_control);
+    let mut adjudicator = Adjudicator::default();
+    let mut repetitions = HashMap::<Zobrist64, u32>::new();
+    let mut uci = Vec::new();
+    let mut moves = Vec::new();
+
+    for engine in engines.iter_mut() {
+        engine.tx.send("ucinewgame".into()).await?;
+        engine.isready().await?;
+    }
+
+    let (result, termination) = loop {
+        let seen = repetitions
+            .entry(pos.zobrist_hash(EnPassantMode::Legal))
+            .or_default();
+        *seen += 1;
+        if let Some(end) = rules_result(&pos, *seen) {
+            break end;
+        }
+
+        let turn = pos.turn();
+        let engine = match turn {
+            Color::White => &mut engines[job.white],
+            Color::Black => &mut engines[1 - job.white],
+        };
+        let search = Go::new().fen(&job.fen).moves(&uci).clock(clock.go());
+
+        let started = Instant::now();
+        let budget = clock.remaining(turn) + TIME_MARGIN;
+        let thinking = async {
+            engine
+                .resume(uci.last().map_or("", String::as_str), search)
+                .await?;
+            engine.wait_best().await
+        };
+        let (info, best) = match tokio::time::timeout(budget, thinking).await {
+            Ok(Ok(found)) => found,
+            Ok(Err(e)) => {
+                warn!(cause = %e, "engine failed to move");
+                break (GameResult::win(!turn), "abandoned");
+            }
+            Err(_) => {
+                engine.stop().await?;
+                break (GameResult::win(!turn), "time forfeit");
+            }
+        };
+        let elapsed = started.elapsed();
+
+        if !clock.spend(turn, elapsed) {
+            if pos.has_insufficient_material(!turn) {
+                break (GameResult::Draw, "time forfeit");
+            }
+            break (GameResult::win(!turn), "time forfeit");
+        }
+
+        let Some(m) = best
+            .best
+            .parse::<UciMove>()
+            .ok()
+            .and_then(|m| m.to_move(&pos).ok())
+        else {
+            warn!(best = best.best, "illegal move");
+            break (GameResult::win(!turn), "illegal move");
+        };
+
+        uci.push(best.best);
+        let san = SanPlus::from_move_and_play_unchecked(&mut pos, m);
+        if let Some(expected) = best.ponder.filter(|_| config.ponder) {
+            let search = Go::new().fen(&job.fen).moves(&uci).clock(clock.go());
+            engine.ponder(search, &expected).await?;
+        }
+        moves.push(PlayedMove {
+            san: san.to_string(),
+            score: info.score,
+            depth: info.depth,
+            time: elapsed,
+        });
+
+        if let Some(end) =
+            adjudicator.update(&config.adjudication, &pos, turn, info.score, info.tbhits)
+        {
+            break end;
+        }
+    };
+
+    for engine in engines.iter_mut() {
+        engine.cancel_ponder().await?;
+    }
+
+    Ok(MatchGame {
+        round: job.round,
+        white: job.white,
+        fen: job.fen.clone(),
+        moves,
+        result,
+        termination: termination.into(),
+    })
+}
+
+/// Plays jobs until none are left or `stop` is set. An error is sent as a result, which fails
+/// the match; the engines are killed either way.
+async fn worker(
+    config: Arc<MatchConfig>,
+    jobs: Arc<std::sync::Mutex<VecDeque<Job>>>,
+    results: mpsc::Sender<Result<MatchGame>>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut engines = Vec::with_capacity(2);
+    let played = play_jobs(&config, &jobs, &results, &stop, &mut engines).await;
+    for engine in engines.iter_mut() {
+        if let Err(e) = engine.kill().await {
+            warn!(cause = %e, "failed to kill engine");
+        }
+    }
+    if let Err(e) = played {
+        error!(cause = %e, "match worker error");
+        // Fails only if the match is over already.
+        _ = results.send(Err(e)).await;
+    }
+}
+
+async fn play_jobs(
+    config: &MatchConfig,
+    jobs: &std::sync::Mutex<VecDeque<Job>>,
+    results: &mpsc::Sender<Result<MatchGame>>,
+    stop: &AtomicBool,
+    started: &mut Vec<Engine>,
+) -> Result<()> {
+    for engine in &config.engines {
+        started.push(engine.start().await?);
+    }
+    let engines: &mut [Engine; 2] = started
+        .as_mut_slice()
+        .try_into()
+        .context("a match needs two engines")?;
+    if config.ponder {
+        for engine in engines.iter_mut() {
+            engine.opts(&[("Ponder", "true")]).await?;
+        }
+    }
+
+    while !stop.load(Ordering::SeqCst) {
+        let Some(job) = jobs.lock().unwrap().pop_front() else {
+            break;
+        };
+        let game = play_game(engines, &job, config)
+            .await
+            .with_context(|| format!("round {} failed", job.round))?;
+        debug!(
+            round = game.round,
+            result = game.result.as_str(),
+            "game over"
+        );
+        results.send(Ok(game)).await?;
+    }
+    Ok(())
+}
+
+/// Writes a finished match game as PGN, with cutechess-style `{score/depth time}` comments.
+pub fn game_pgn(game: &MatchGame, config: &MatchConfig) -> String {
+    let white = &config.engines[game.white].name;
+    let black = &config.engines[1 - game.white].name;
+    let mut tags = vec![
+        (
+            "Event".to_string(),
+            config.event.clone().unwrap_or("escacs match".into()),
+        ),
+        ("Site".into(), "?".into()),
+        ("Date".into(), today()),
+        ("Round".into(), game.round.to_string()),
+        ("White".into(), white.clone()),
+        ("Black".into(), black.clone()),
+        ("Result".into(), game.result.as_str().into()),
+    ];
+    if game.fen != Fen::default().to_string() {
+        tags.push(("SetUp".into(), "1".into()));
+        tags.push(("FEN".into(), game.fen.clone()));
+    }
+    tags.push(("TimeControl".into(), config.time_control.tag()));
+    tags.push(("Termination".into(), game.termination.clone()));
+
+    let mut out = String::new();
+    write_tags(&mut out, &tags);
+    out.push('\n');
+
+    let start = game
+        .fen
+        .parse::<Fen>()
+        .ok()
+        .and_then(|f| f.into_position::<Chess>(CastlingMode::Standard).ok())
+        .unwrap_or_default();
+    let mut number = start.fullmoves().get();
+    let mut turn = start.turn();
+    let mut w = MovetextWriter::default();
+    for m in &game.moves {
+        match turn {
+            Color::White => w.token(&format!("{number}.")),
+            Color::Black => w.token(&format!("{number}...")),
+        }
+        w.token(&m.san);
+        let score = match m.score {
+            Score::Cp(cp) => format!("{:+.2}", cp as f64 / 100.0),
+            Score::Mate(n) if n > 0 => format!("+M{n}"),
+            Score::Mate(n) => format!("-M{}", -n),
+        };
+        w.comment(&format!("{score}/{} {:.2}s", m.depth, m.time.as_secs_f64()));
+        if turn.is_black() {
+            number += 1;
+        }
+        turn = !turn;
+    }
+    w.token(game.result.as_str());
+    out.push_str(&w.finish());
+    out
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct GameSummary {
+    pub round: usize,
+    pub white: String,
+    pub black: String,
+    pub result: GameResult,
+    pub termination: String,
+    pub moves: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MatchUpdate {
+    pub played: usize,
+    pub total: usize,
+    /// Results of the first engine.
+    pub score: Wdl,
+    pub elo: Option<(f64, f64)>,
+    pub los: Option<f64>,
+    pub sprt: Option<Sprt>,
+    pub last: Option<GameSummary>,
+}
+
+/// Plays the match to completion, an SPRT decision or `stop`, reporting after every game.
+pub async fn run_match(
+    config: MatchConfig,
+    stop: Arc<AtomicBool>,
+    mut on_update: impl FnMut(&MatchUpdate),
+) -> Result<MatchUpdate> {
+    let openings = config.openings.load()?;
+    let jobs = (0..config.rounds)
+        .flat_map(|round| {
+            let fen = &openings[round % openings.len()];
+            [0, 1].map(|white| Job {
+                round: round + 1,
+                white,
+                fen: fen.clone(),
+            })
+        })
+        .collect::<VecDeque<_>>();
+    let total = jobs.len();
+
+    // Opened before any engine starts, so that a bad path fails the match right away.
+    let mut pgn = match &config.pgn {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+
+    let config = Arc::new(config);
+    let jobs = Arc::new(std::sync::Mutex::new(jobs));
+    let (tx, mut rx) = mpsc::channel(config.concurrency.max(1));
+
+    for _ in 0..config.concurrency.max(1) {
+        let (config, jobs, tx, stop) = (config.clone(), jobs.clone(), tx.clone(), stop.clone());
+        tokio::spawn(worker(config, jobs, tx, stop));
+    }
+    drop(tx);
+
+    let mut update = MatchUpdate {
+        played: 0,
+        total,
+        score: Wdl::default(),
+        elo: None,
+        los: None,
+        sprt: None,
+        last: None,
+    };
+
+    while let Some(game) = rx.recv().await {
+        // A game that could not be finished fails the match, so that it never reports fewer
+        // games than it played as complete. Stopping ends the other workers.
+        let game = match game {
+            Ok(game) => game,
+            Err(e) => {
+                stop.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        if let Some(file) = &mut pgn {
+            if let Err(e) = writeln!(file, "{}", game_pgn(&game, &config)) {
+                stop.store(true, Ordering::SeqCst);
+                return Err(e.into());
+            }
+        }
+
+        let first_color = if game.white == 0 {
+            Color::White
+        } else {
+            Color::Black
+        };
+        match game.result {
+            GameResult::Draw => update.score.draws += 1,
+            r if r == GameResult::win(first_color) => update.score.wins += 1,
+            _ => update.score.losses += 1,
+        }
+
+        update.played += 1;
+        update.elo = update.score.elo();
+        update.los = update.score.los();
+        update.sprt = config.sprt.map(|s| s.test(&update.score));
+        update.last = Some(GameSummary {
+            round: game.round,
+            white: config.engines[game.white].name.clone(),
+            black: config.engines[1 - game.white].name.clone(),
+            result: game.result,
+            termination: game.termination,
+            moves: game.moves.len(),
+        });
+        on_update(&update);
+
+        if update.sprt.is_some_and(|s| s.decision.is_some()) {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    Ok(update)
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn start_match(
+    config: MatchConfig,
+    chan: tauri::ipc::Channel<MatchUpdate>,
+    state: State<'_, AppState>,
+) -> Result<MatchUpdate, String> {
+    let stop = state.match_stop.clone();
+    stop.store(false, Ordering::SeqCst);
+    run_match(config, stop, |update| {
+        if let Err(e) = chan.send(update.clone()) {
+            warn!(cause = %e, "failed to send match update");
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn stop_match(state: State<'_, AppState>) {
+    state.match_stop.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn engine_failure_fails_match() {
+        let config: MatchConfig = serde_json::from_str(
+            r#"{
+                "engines": [
+                    {"name": "missing", "path": "/nonexistent/engine"},
+                    {"name": "missing too", "path": "/nonexistent/engine"}
+                ],
+                "openings": {"kind": "startpos"},
+                "rounds": 2,
+                "time_control": {"base": 1000, "increment": 0}
+            }"#,
+        )
+        .unwrap();
+        let mut updates = 0;
+        let result = run_match(config, Arc::new(AtomicBool::new(false)), |_| updates += 1).await;
+        assert!(result.is_err());
+        assert_eq!(updates, 0);
+    }
+
+    #[tokio::test]
+    async fn unwritable_pgn_fails_match() {
+        let config: MatchConfig = serde_json::from_str(
+            r#"{
+                "engines": [
+                    {"name": "missing", "path": "/nonexistent/engine"},
+                    {"name": "missing too", "path": "/nonexistent/engine"}
+                ],
+                "openings": {"kind": "startpos"},
+                "rounds": 2,
+                "time_control": {"base": 1000, "increment": 0},
+                "pgn": "/nonexistent/dir/match.pgn"
+            }"#,
+        )
+        .unwrap();
+        let error = run_match(config, Arc::default(), |_| {}).await.unwrap_err();
+        assert!(error.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[test]
+    fn epd_suite() {
+        let epd =
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - id \"e4\";\n\n# comment\n";
+        let fens = parse_epd(epd).unwrap();
+        assert_eq!(
+            fens,
+            vec!["rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"]
+        );
+        assert!(parse_epd("not a position").is_err());
+    }
+
+    #[test]
+    fn resign_adjudication() {
+        let rules = Adjudication {
+            resign: Some(ResignRule {
+                moves: 2,
+                score: 600,
+            }),
+            ..Default::default()
+        };
+        let pos = Chess::default();
+        let mut adj = Adjudicator::default();
+        assert!(adj
+            .update(&rules, &pos, Color::Black, Score::Cp(-700), 0)
+            .is_none());
+        assert!(adj
+            .update(&rules, &pos, Color::White, Score::Cp(700), 0)
+            .is_none());
+        assert_eq!(
+            adj.update(&rules, &pos, Color::Black, Score::Cp(-650), 0),
+            Some((GameResult::WhiteWins, "adjudication"))
+        );
+    }
+}
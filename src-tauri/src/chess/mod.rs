@@ -1,5 +1,15 @@
-mod analysis;
+pub mod analysis;
+pub mod clock;
 mod engine;
+pub mod matches;
 pub mod openings;
+pub mod pattern;
+pub mod pgn;
+#[cfg(feature = "desktop")]
+pub mod play;
+pub mod polyglot;
+pub mod sprt;
+pub mod time_usage;
+pub mod tree;
 
-pub use engine::{search, BestMove, Engine, Go, Info, Score, Search, Visitor};
+pub use engine::{search, BestMove, Engine, Go, GoClock, Info, Score, Search, Visitor, MATE_CP};
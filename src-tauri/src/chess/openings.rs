@@ -1,8 +1,27 @@
-#![allow(static_mut_refs)]
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
 
-use std::time::Instant;
+use anyhow::{bail, Context, Result};
+use shakmaty::{
+    fen::Fen,
+    san::{San, SanPlus},
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    CastlingMode, Chess, EnPassantMode, Position,
+};
+#[cfg(feature = "desktop")]
+use std::path::PathBuf;
 
-use shakmaty::{fen::Fen, san::San, Chess, EnPassantMode, Position};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::pgn::Game;
+#[cfg(feature = "desktop")]
+use crate::AppState;
 
 const TSVS: [&str; 5] = [
     include_str!("../../openings/a.tsv"),
@@ -12,62 +31,532 @@ const TSVS: [&str; 5] = [
     include_str!("../../openings/e.tsv"),
 ];
 
-static mut OPENINGS: Vec<Opening> = Vec::new();
-
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Opening {
-    eco: &'static str,
-    name: &'static str,
-    pgn: &'static str,
+    eco: String,
+    name: String,
+    pgn: String,
     move_count: usize,
     fen: String,
+    #[serde(skip)]
+    key: Zobrist64,
+}
+
+/// A book move from one named-line position to the next.
+#[derive(Debug, Clone)]
+struct Edge {
+    uci: String,
+    san: String,
+    to: Zobrist64,
+}
+
+/// A position on the way to one or more named openings.
+#[derive(Debug, Default, Clone)]
+struct Node {
+    moves: Vec<Edge>,
+    /// Indices of the openings whose lines pass through or end at this position.
+    lines: Vec<usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OpeningLine {
+    pub eco: String,
+    pub name: String,
+}
+
+/// A book move from a position with the openings it leads to.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Continuation {
+    pub uci: String,
+    pub san: String,
+    /// Position after the move.
+    pub fen: String,
+    /// The opening named by the position after the move, if any.
+    pub opening: Option<Opening>,
+    /// Named lines reachable through the move, by ECO code.
+    pub lines: Vec<OpeningLine>,
 }
 
 impl Opening {
-    pub fn from_tsv(line: &'static str) -> Self {
-        let mut chess = Chess::new();
+    /// Parses an `eco<TAB>name<TAB>pgn` line.
+    pub fn from_tsv(line: &str) -> Result<Self> {
+        Self::parse(line).map(|(opening, _)| opening)
+    }
+
+    /// Parses a line together with the positions and moves of its PGN.
+    fn parse(line: &str) -> Result<(Self, Vec<(Zobrist64, Edge)>)> {
         let mut parts = line.split('\t');
-        let eco = parts.next().unwrap();
-        let name = parts.next().unwrap();
-        let pgn = parts.next().unwrap();
-        let mut move_count = 0;
-        pgn.split_whitespace()
-            .filter_map(|m| m.parse::<San>().ok())
-            .for_each(|san| {
-                chess.play_unchecked(san.to_move(&chess).unwrap());
-                move_count += 1;
-            });
-        let fen = Fen::from_position(&chess, EnPassantMode::Legal).to_string();
-        Self {
-            eco,
-            name,
-            pgn,
-            move_count,
-            fen,
+        let (Some(eco), Some(name), Some(pgn)) = (parts.next(), parts.next(), parts.next()) else {
+            bail!("expected eco, name and pgn separated by tabs");
+        };
+        let is_eco = eco.len() == 3
+            && matches!(eco.as_bytes()[0], b'A'..=b'E')
+            && eco[1..].bytes().all(|b| b.is_ascii_digit());
+        if !is_eco {
+            bail!("invalid ECO code {eco:?}");
+        }
+
+        let mut chess = Chess::new();
+        let mut steps = Vec::new();
+        for token in pgn.split_whitespace() {
+            // Move numbers, `1.` or `1...`.
+            if token.ends_with('.')
+                && token
+                    .trim_end_matches('.')
+                    .bytes()
+                    .all(|b| b.is_ascii_digit())
+            {
+                continue;
+            }
+            let san = token
+                .parse::<San>()
+                .with_context(|| format!("invalid move {token:?}"))?;
+            let m = san
+                .to_move(&chess)
+                .with_context(|| format!("illegal move {token:?}"))?;
+            let from = position_key(&chess);
+            let uci = m.to_uci(CastlingMode::Standard).to_string();
+            let san = SanPlus::from_move_and_play_unchecked(&mut chess, m).to_string();
+            let to = position_key(&chess);
+            steps.push((from, Edge { uci, san, to }));
+        }
+        if steps.is_empty() {
+            bail!("opening {name:?} has no moves");
         }
+
+        let opening = Self {
+            eco: eco.into(),
+            name: name.into(),
+            pgn: pgn.into(),
+            move_count: steps.len(),
+            fen: Fen::from_position(&chess, EnPassantMode::Legal).to_string(),
+            key: position_key(&chess),
+        };
+        Ok((opening, steps))
+    }
+
+    pub fn eco(&self) -> &str {
+        &self.eco
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pgn(&self) -> &str {
+        &self.pgn
+    }
+
+    pub fn fen(&self) -> &str {
+        &self.fen
+    }
+
+    pub fn move_count(&self) -> usize {
+        self.move_count
+    }
+
+    /// The part of the name before the variation, e.g. `Sicilian Defense`.
+    pub fn family(&self) -> &str {
+        self.name.split(':').next().unwrap_or_default().trim()
     }
 }
 
-pub fn gather_openings() {
-    let start = Instant::now();
-    let mut max = 0;
-    for tsv in TSVS {
-        for line in tsv.lines().skip(1) {
-            let opening = Opening::from_tsv(line);
-            max = opening.move_count.max(max);
-            unsafe { OPENINGS.push(opening) };
+/// Filters for [`OpeningBook::search`]. Every given filter must match.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct OpeningQuery {
+    /// Words looked up in the name, ignoring case, punctuation and small typos.
+    pub name: Option<String>,
+    /// An ECO code, range or prefix: `B90`, `B90-B99` or `B9`.
+    pub eco: Option<String>,
+    /// All variations of a family, e.g. `Sicilian Defense`.
+    pub family: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Lowercase words of a name, without punctuation.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase().replace('\'', ""))
+        .collect()
+}
+
+/// Whether `a` and `b` are at most one insertion, deletion, substitution or swap of adjacent
+/// letters apart.
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+    let prefix = short.iter().zip(&long).take_while(|(x, y)| x == y).count();
+    if prefix == long.len() {
+        true
+    } else if short.len() == long.len() {
+        let swapped = prefix + 1 < short.len()
+            && short[prefix] == long[prefix + 1]
+            && short[prefix + 1] == long[prefix]
+            && short[prefix + 2..] == long[prefix + 2..];
+        swapped || short[prefix + 1..] == long[prefix + 1..]
+    } else {
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+/// How well a query word matches a name word: 3 for the whole word, 2 for a prefix and 1 for a
+/// substring or typo.
+fn word_match(query: &str, word: &str) -> u32 {
+    if word == query {
+        3
+    } else if word.starts_with(query) {
+        2
+    } else if word.contains(query) || (query.chars().count() >= 5 && within_one_edit(query, word)) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Parses an ECO filter into an inclusive range of codes.
+fn eco_range(filter: &str) -> Result<(String, String)> {
+    let filter = filter.trim().to_uppercase();
+    let valid = |code: &str| {
+        (1..=3).contains(&code.len())
+            && matches!(code.as_bytes()[0], b'A'..=b'E')
+            && code[1..].bytes().all(|b| b.is_ascii_digit())
+    };
+    let (low, high) = filter.split_once('-').unwrap_or((&filter, &filter));
+    let (low, high) = (low.trim(), high.trim());
+    if !valid(low) || !valid(high) {
+        bail!("invalid ECO filter {filter:?}");
+    }
+    // A prefix like `B9` covers `B90` to `B99`.
+    let low = format!("{low:0<3}");
+    let high = format!("{high:9<3}");
+    Ok((low, high))
+}
+
+/// Hash of a position ignoring move counters and unusable en passant squares.
+pub fn position_key(pos: &Chess) -> Zobrist64 {
+    pos.zobrist_hash(EnPassantMode::Legal)
+}
+
+/// Named openings indexed by position, ignoring move counters and unusable en passant squares.
+#[derive(Debug, Default, Clone)]
+pub struct OpeningBook {
+    openings: Vec<Opening>,
+    index: HashMap<Zobrist64, usize>,
+    /// Move tree of all the lines, merged at transpositions.
+    tree: HashMap<Zobrist64, Node>,
+}
+
+impl OpeningBook {
+    /// The openings bundled with the app, parsed on first use.
+    pub fn bundled() -> Arc<Self> {
+        static BUNDLED: OnceLock<Arc<OpeningBook>> = OnceLock::new();
+        BUNDLED
+            .get_or_init(|| {
+                let start = Instant::now();
+                let mut book = Self::default();
+                for tsv in TSVS {
+                    book.add_tsv(tsv).expect("bundled openings are valid");
+                }
+                tracing::trace!(
+                    "gathered {} openings in {:?}",
+                    book.openings.len(),
+                    start.elapsed()
+                );
+                Arc::new(book)
+            })
+            .clone()
+    }
+
+    /// Adds the openings of a TSV file in the bundled format, with an optional header line.
+    pub fn add_tsv(&mut self, tsv: &str) -> Result<()> {
+        for (i, line) in tsv.lines().enumerate() {
+            if line.trim().is_empty() || (i == 0 && line.starts_with("eco\t")) {
+                continue;
+            }
+            let (opening, steps) =
+                Opening::parse(line).with_context(|| format!("line {}", i + 1))?;
+            let id = self.openings.len();
+            // Some lines transpose into an earlier one; the first name wins.
+            self.index.entry(opening.key).or_insert(id);
+            for (from, edge) in steps {
+                let to = edge.to;
+                let node = self.tree.entry(from).or_default();
+                if !node.moves.iter().any(|e| e.uci == edge.uci) {
+                    node.moves.push(edge);
+                }
+                node.lines.push(id);
+                self.tree.entry(to).or_default();
+            }
+            self.tree.entry(opening.key).or_default().lines.push(id);
+            self.openings.push(opening);
         }
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tsv = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        self.add_tsv(&tsv)
+            .with_context(|| format!("invalid opening file {}", path.display()))
+    }
+
+    pub fn openings(&self) -> &[Opening] {
+        &self.openings
+    }
+
+    /// The named opening of exactly this position, whatever move order reached it.
+    pub fn lookup(&self, pos: &Chess) -> Option<&Opening> {
+        self.index
+            .get(&position_key(pos))
+            .map(|&i| &self.openings[i])
+    }
+
+    pub fn find(&self, fen: &str) -> Option<&Opening> {
+        let pos: Chess = fen
+            .parse::<Fen>()
+            .ok()?
+            .into_position(CastlingMode::Standard)
+            .ok()?;
+        self.lookup(&pos)
+    }
+
+    /// Book moves from `pos`, each with the named lines it leads to, most popular first.
+    pub fn continuations(&self, pos: &Chess) -> Vec<Continuation> {
+        let Some(node) = self.tree.get(&position_key(pos)) else {
+            return Vec::new();
+        };
+        let mut continuations = node
+            .moves
+            .iter()
+            .filter_map(|edge| {
+                let m = edge.uci.parse::<UciMove>().ok()?.to_move(pos).ok()?;
+                let mut after = pos.clone();
+                after.play_unchecked(m);
+                let mut lines = self.tree.get(&edge.to)?.lines.clone();
+                lines.sort_by(|&a, &b| {
+                    let (a, b) = (&self.openings[a], &self.openings[b]);
+                    (&a.eco, &a.name).cmp(&(&b.eco, &b.name))
+                });
+                let mut lines = lines
+                    .into_iter()
+                    .map(|i| OpeningLine {
+                        eco: self.openings[i].eco.clone(),
+                        name: self.openings[i].name.clone(),
+                    })
+                    .collect::<Vec<_>>();
+                lines.dedup_by(|a, b| a.eco == b.eco && a.name == b.name);
+                Some(Continuation {
+                    uci: edge.uci.clone(),
+                    san: edge.san.clone(),
+                    fen: Fen::from_position(&after, EnPassantMode::Legal).to_string(),
+                    opening: self.lookup(&after).cloned(),
+                    lines,
+                })
+            })
+            .collect::<Vec<_>>();
+        continuations.sort_by_key(|c| std::cmp::Reverse(c.lines.len()));
+        continuations
     }
-    tracing::trace!(
-        "gathered {} openings in {:?}; max = {max}",
-        unsafe { OPENINGS.len() },
-        start.elapsed()
-    );
+
+    /// Openings matching `query`, best name matches first, then by ECO code and length.
+    pub fn search(&self, query: &OpeningQuery) -> Result<Vec<&Opening>> {
+        let range = query.eco.as_deref().map(eco_range).transpose()?;
+        let family = query.family.as_deref().map(words);
+        let name = query.name.as_deref().map(words).unwrap_or_default();
+
+        let mut found = self
+            .openings
+            .iter()
+            .filter(|o| {
+                range
+                    .as_ref()
+                    .is_none_or(|(low, high)| (low.as_str()..=high.as_str()).contains(&o.eco()))
+            })
+            .filter(|o| family.as_ref().is_none_or(|f| words(o.family()) == *f))
+            .filter_map(|o| {
+                let candidates = words(&o.name);
+                let mut score = 0;
+                for q in &name {
+                    let best = candidates.iter().map(|w| word_match(q, w)).max();
+                    match best {
+                        Some(s) if s > 0 => score += s,
+                        _ => return None,
+                    }
+                }
+                Some((score, o))
+            })
+            .collect::<Vec<_>>();
+        found.sort_by(|(sa, a), (sb, b)| {
+            sb.cmp(sa)
+                .then_with(|| a.eco.cmp(&b.eco))
+                .then_with(|| a.move_count.cmp(&b.move_count))
+        });
+        // Transposed lines end in the same position; keep the first of them.
+        let mut seen = std::collections::HashSet::new();
+        Ok(found
+            .into_iter()
+            .map(|(_, o)| o)
+            .filter(|o| seen.insert(o.key))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// The deepest named position along a game, with the ply at which it was reached.
+    ///
+    /// `positions` starts with the initial position, so a returned ply of 0 means the game
+    /// started from a named position.
+    pub fn deepest<'a>(
+        &self,
+        positions: impl IntoIterator<Item = &'a Chess>,
+    ) -> Option<(usize, &Opening)> {
+        positions
+            .into_iter()
+            .enumerate()
+            .filter_map(|(ply, pos)| self.lookup(pos).map(|o| (ply, o)))
+            .last()
+    }
+
+    /// The opening of a game given as UCI moves, falling back to the last named position before
+    /// the game left the book.
+    pub fn find_game(&self, fen: Option<&str>, moves: &[String]) -> Option<&Opening> {
+        let mut pos: Chess = match fen {
+            Some(fen) => fen
+                .parse::<Fen>()
+                .ok()?
+                .into_position(CastlingMode::Standard)
+                .ok()?,
+            None => Chess::default(),
+        };
+        let mut positions = vec![pos.clone()];
+        for uci in moves {
+            let Some(m) = uci
+                .parse::<UciMove>()
+                .ok()
+                .and_then(|m| m.to_move(&pos).ok())
+            else {
+                break;
+            };
+            pos.play_unchecked(m);
+            positions.push(pos.clone());
+        }
+        self.deepest(&positions).map(|(_, o)| o)
+    }
+}
+
+impl OpeningBook {
+    /// Classifies a game by the deepest named position along `positions`, which start with
+    /// the initial position.
+    pub fn classify(&self, positions: &[Chess]) -> Option<Classification> {
+        let (ply, opening) = self.deepest(positions)?;
+        let left_book = positions
+            .iter()
+            .position(|pos| !self.tree.contains_key(&position_key(pos)));
+        Some(Classification {
+            eco: opening.eco.clone(),
+            name: opening.name.clone(),
+            ply,
+            left_book,
+        })
+    }
+
+    pub fn classify_game(&self, game: &Game) -> Option<Classification> {
+        self.classify(&game.positions().ok()?)
+    }
+}
+
+/// The opening a game was played in.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Classification {
+    pub eco: String,
+    pub name: String,
+    /// Ply at which the named position was reached.
+    pub ply: usize,
+    /// Ply of the first move that left the book lines, `None` if the game never did.
+    pub left_book: Option<usize>,
 }
 
+impl Classification {
+    /// Fills in the `ECO` and `Opening` tags.
+    pub fn tag(&self, game: &mut Game) {
+        game.set_tag("ECO", &self.eco);
+        game.set_tag("Opening", &self.name);
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn find_opening(fen: &str, state: State<'_, AppState>) -> Option<Opening> {
+    state.openings.read().unwrap().find(fen).cloned()
+}
+
+#[cfg(feature = "desktop")]
 #[tauri::command]
-pub fn find_opening(fen: &str) -> Option<&Opening> {
-    unsafe { OPENINGS.iter().find(|o| o.fen == fen) }
+pub fn find_game_opening(
+    fen: Option<&str>,
+    moves: Vec<String>,
+    state: State<'_, AppState>,
+) -> Option<Opening> {
+    state
+        .openings
+        .read()
+        .unwrap()
+        .find_game(fen, &moves)
+        .cloned()
+}
+
+/// Openings by name, ECO code or range, and family.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn search_openings(
+    query: OpeningQuery,
+    state: State<'_, AppState>,
+) -> Result<Vec<Opening>, String> {
+    let book = state.openings.read().unwrap();
+    let found = book.search(&query).map_err(|e| e.to_string())?;
+    Ok(found.into_iter().cloned().collect())
+}
+
+/// The book moves from a position and the named openings behind each of them. Call again with a
+/// continuation's `fen` to expand the next level.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn opening_continuations(
+    fen: &str,
+    state: State<'_, AppState>,
+) -> Result<Vec<Continuation>, String> {
+    let pos: Chess = fen
+        .parse::<Fen>()
+        .map_err(|e| e.to_string())?
+        .into_position(CastlingMode::Standard)
+        .map_err(|e| e.to_string())?;
+    Ok(state.openings.read().unwrap().continuations(&pos))
+}
+
+/// Replaces the opening book with the given TSV files, on top of the bundled openings unless
+/// `bundled` is false. Returns the number of openings.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn load_opening_files(
+    paths: Vec<PathBuf>,
+    bundled: bool,
+    state: State<'_, AppState>,
+) -> Result<usize, String> {
+    let mut book = match bundled {
+        true => OpeningBook::bundled().as_ref().clone(),
+        false => OpeningBook::default(),
+    };
+    for path in paths {
+        book.add_file(path).map_err(|e| format!("{e:#}"))?;
+    }
+    let count = book.openings().len();
+    *state.openings.write().unwrap() = Arc::new(book);
+    Ok(count)
 }
 
 #[cfg(test)]
@@ -76,24 +565,128 @@ mod tests {
 
     #[test]
     fn generic() {
-        gather_openings();
+        let book = OpeningBook::bundled();
 
         let anti_nimzo = "rnbqkb1r/pppp1ppp/4pn2/8/2PP4/5N2/PP2PPPP/RNBQKB1R b KQkq - 1 3";
         assert_eq!(
             Some("Indian Defense: Anti-Nimzo-Indian"),
-            find_opening(anti_nimzo).map(|o| o.name)
+            book.find(anti_nimzo).map(|o| o.name())
         );
 
         let catalan = "rnbqkb1r/pppp1ppp/4pn2/8/2PP4/6P1/PP2PP1P/RNBQKBNR b KQkq - 0 3";
         assert_eq!(
             Some("Catalan Opening"),
-            find_opening(catalan).map(|o| o.name)
+            book.find(catalan).map(|o| o.name())
         );
 
         let open_catalan = "rnbqkb1r/ppp2ppp/4pn2/8/2pP4/5NP1/PP2PPBP/RNBQK2R b KQkq - 1 5";
         assert_eq!(
             Some("Catalan Opening: Open Defense"),
-            find_opening(open_catalan).map(|o| o.name)
+            book.find(open_catalan).map(|o| o.name())
+        );
+
+        // The Anti-Nimzo-Indian reached through 1. Nf3, with different move counters.
+        let transposed = "rnbqkb1r/pppp1ppp/4pn2/8/2PP4/5N2/PP2PPPP/RNBQKB1R b KQkq - 0 7";
+        assert_eq!(
+            Some("Indian Defense: Anti-Nimzo-Indian"),
+            book.find(transposed).map(|o| o.name())
         );
+
+        let moves = ["g1f3", "g8f6", "c2c4", "e7e6", "d2d4", "h7h6", "a2a3"];
+        let opening = book.find_game(None, &moves.map(String::from));
+        assert_eq!(
+            Some("Indian Defense: Anti-Nimzo-Indian"),
+            opening.map(|o| o.name())
+        );
+    }
+
+    #[test]
+    fn classify() {
+        let book = OpeningBook::bundled();
+        let pgn =
+            "1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Be3 e5 7. Nb3 Be6 8. h4 *";
+        let game = super::super::pgn::read_games(pgn.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let c = book.classify_game(&game).unwrap();
+        assert_eq!(c.eco, "B90");
+        assert!(c.name.starts_with("Sicilian Defense: Najdorf Variation"));
+        assert!(c.ply >= 10 && c.left_book.unwrap() > c.ply);
+
+        let mut game = game;
+        c.tag(&mut game);
+        assert_eq!(game.tag("ECO"), Some("B90"));
+        assert!(book.classify(&[]).is_none());
+    }
+
+    #[test]
+    fn continuations() {
+        let book = OpeningBook::bundled();
+
+        let first = book.continuations(&Chess::default());
+        let e4 = first.iter().find(|c| c.san == "e4").unwrap();
+        assert!(e4.lines.iter().any(|l| l.name == "Sicilian Defense"));
+        assert_eq!(e4.opening.as_ref().map(|o| o.name()), Some("King's Pawn"));
+
+        let catalan: Chess = "rnbqkb1r/pppp1ppp/4pn2/8/2PP4/6P1/PP2PP1P/RNBQKBNR b KQkq - 0 3"
+            .parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap();
+        let d5 = book.continuations(&catalan);
+        let d5 = d5.iter().find(|c| c.uci == "d7d5").unwrap();
+        assert!(d5
+            .lines
+            .iter()
+            .all(|l| l.name.starts_with("Catalan Opening")));
+        assert!(d5
+            .lines
+            .iter()
+            .any(|l| l.name == "Catalan Opening: Open Defense"));
+    }
+
+    #[test]
+    fn search() {
+        let book = OpeningBook::bundled();
+        let query = |name: &str, eco: Option<&str>, family: Option<&str>| OpeningQuery {
+            name: Some(name.into()).filter(|n: &String| !n.is_empty()),
+            eco: eco.map(Into::into),
+            family: family.map(Into::into),
+            limit: None,
+        };
+
+        let najdorf = book.search(&query("najdorf", None, None)).unwrap();
+        assert!(!najdorf.is_empty());
+        assert!(najdorf.iter().all(|o| o.name().contains("Najdorf")));
+        assert_eq!(najdorf[0].family(), "Sicilian Defense");
+
+        let catalan = book.search(&query("catalan open", None, None)).unwrap();
+        assert_eq!(catalan[0].name(), "Catalan Opening: Open Defense");
+        let typo = book.search(&query("catalna", None, None)).unwrap();
+        assert!(typo.iter().any(|o| o.name() == "Catalan Opening"));
+
+        let range = book.search(&query("", Some("b90-b99"), None)).unwrap();
+        assert!(range.iter().all(|o| o.eco().starts_with("B9")));
+        let prefix = book.search(&query("", Some("B9"), None)).unwrap();
+        assert_eq!(range.len(), prefix.len());
+
+        let family = book
+            .search(&query("", None, Some("sicilian defense")))
+            .unwrap();
+        assert!(family.iter().all(|o| o.family() == "Sicilian Defense"));
+        assert!(family.len() > 50);
+
+        assert!(book.search(&query("", Some("Z00"), None)).is_err());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let mut book = OpeningBook::default();
+        let tsv = "eco\tname\tpgn\nA00\tAmar Opening\t1. Nh3\n\nA00\tBroken\t1. Nh3 Ke5\n";
+        let err = book.add_tsv(tsv).unwrap_err();
+        assert!(format!("{err:#}").starts_with("line 4: illegal move \"Ke5\""));
+        assert!(book.add_tsv("Z99\tNope\t1. e4").is_err());
+        assert!(book.add_tsv("A00 no tabs").is_err());
     }
 }
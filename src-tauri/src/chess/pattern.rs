@@ -0,0 +1,323 @@
+//! Material and piece patterns, matched against positions with shakmaty.
+
+use anyhow::{anyhow, bail, Context, Result};
+use shakmaty::{Bitboard, ByRole, Chess, Color, File, Piece, Position, Rank, Role};
+
+use crate::db::explorer::Side;
+
+/// One thing a position must have.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Condition {
+    /// Material like `KRPvKR`, White first. Kings may be left out. Counts are exact unless the
+    /// side ends with `*`, so `KQ*vKQ*` is any position with queens on.
+    Material {
+        material: String,
+        /// Also match with the colours swapped.
+        #[serde(default)]
+        either_color: bool,
+    },
+    /// One bishop each, on squares of different colours.
+    OppositeBishops,
+    /// A pawn of `color` with no pawns of its own on the neighbouring files, on `file` or any.
+    IsolatedPawn {
+        color: Side,
+        file: Option<char>,
+    },
+    /// `piece` on one of `squares`. The piece is a FEN letter, `*` for any piece or `.` for an
+    /// empty square. The squares are a name like `e5`, where `?` stands for any file or rank.
+    Piece {
+        piece: char,
+        squares: String,
+    },
+    Not {
+        condition: Box<Condition>,
+    },
+}
+
+/// Conditions that must all hold at once.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Pattern {
+    pub conditions: Vec<Condition>,
+    /// Plies in a row the pattern must hold, so that e.g. material in the middle of a capture
+    /// sequence does not count. One if unset.
+    pub min_plies: usize,
+}
+
+/// A side's material with the counts of every role but the king.
+#[derive(Debug, Clone, PartialEq)]
+struct SideMaterial {
+    counts: ByRole<u8>,
+    /// Whether more pieces than `counts` are allowed.
+    more: bool,
+}
+
+impl SideMaterial {
+    fn parse(s: &str) -> Result<Self> {
+        let (pieces, more) = match s.strip_suffix('*') {
+            Some(pieces) => (pieces, true),
+            None => (s, false),
+        };
+        let mut counts = ByRole::<u8>::default();
+        for ch in pieces.chars() {
+            let role = Role::from_char(ch.to_ascii_lowercase())
+                .with_context(|| format!("invalid piece {ch:?} in material {s:?}"))?;
+            *counts.get_mut(role) += 1;
+        }
+        counts.king = 0;
+        Ok(Self { counts, more })
+    }
+
+    fn matches(&self, pos: &Chess, color: Color) -> bool {
+        let board = pos.board();
+        Role::ALL
+            .into_iter()
+            .filter(|&role| role != Role::King)
+            .all(|role| {
+                let n = (board.by_color(color) & board.by_role(role)).count();
+                let want = usize::from(*self.counts.get(role));
+                n == want || (self.more && n > want)
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Material {
+        white: SideMaterial,
+        black: SideMaterial,
+        either_color: bool,
+    },
+    OppositeBishops,
+    IsolatedPawn {
+        color: Color,
+        files: Vec<File>,
+    },
+    Piece {
+        /// `None` for any piece, `Some(None)` for an empty square.
+        piece: Option<Option<Piece>>,
+        squares: Bitboard,
+    },
+    Not(Box<Test>),
+}
+
+fn squares(pattern: &str) -> Result<Bitboard> {
+    let mut chars = pattern.chars();
+    let (Some(file), Some(rank), None) = (chars.next(), chars.next(), chars.next()) else {
+        bail!("invalid square pattern {pattern:?}");
+    };
+    let invalid = || anyhow!("invalid square pattern {pattern:?}");
+    let files = match file {
+        '?' => Bitboard::FULL,
+        f => Bitboard::from_file(File::from_char(f).ok_or_else(invalid)?),
+    };
+    let ranks = match rank {
+        '?' => Bitboard::FULL,
+        r => Bitboard::from_rank(Rank::from_char(r).ok_or_else(invalid)?),
+    };
+    Ok(files & ranks)
+}
+
+impl Test {
+    fn new(condition: &Condition) -> Result<Self> {
+        Ok(match condition {
+            Condition::Material {
+                material,
+                either_color,
+            } => {
+                let (white, black) = material
+                    .split_once('v')
+                    .with_context(|| format!("material {material:?} has no `v`"))?;
+                Self::Material {
+                    white: SideMaterial::parse(white.trim())?,
+                    black: SideMaterial::parse(black.trim())?,
+                    either_color: *either_color,
+                }
+            }
+            Condition::OppositeBishops => Self::OppositeBishops,
+            Condition::IsolatedPawn { color, file } => Self::IsolatedPawn {
+                color: color.color(),
+                files: match file {
+                    Some(f) => {
+                        vec![File::from_char(*f).with_context(|| format!("invalid file {f:?}"))?]
+                    }
+                    None => File::ALL.to_vec(),
+                },
+            },
+            Condition::Piece { piece, squares: s } => Self::Piece {
+                piece: match piece {
+                    '*' => None,
+                    '.' => Some(None),
+                    ch => Some(Some(
+                        Piece::from_char(*ch).with_context(|| format!("invalid piece {ch:?}"))?,
+                    )),
+                },
+                squares: squares(s)?,
+            },
+            Condition::Not { condition } => Self::Not(Box::new(Self::new(condition)?)),
+        })
+    }
+
+    fn matches(&self, pos: &Chess) -> bool {
+        let board = pos.board();
+        match self {
+            Self::Material {
+                white,
+                black,
+                either_color,
+            } => {
+                (white.matches(pos, Color::White) && black.matches(pos, Color::Black))
+                    || (*either_color
+                        && white.matches(pos, Color::Black)
+                        && black.matches(pos, Color::White))
+            }
+            Self::OppositeBishops => {
+                let bishops = |color| {
+                    board.by_piece(Piece {
+                        color,
+                        role: Role::Bishop,
+                    })
+                };
+                match (
+                    bishops(Color::White).single_square(),
+                    bishops(Color::Black).single_square(),
+                ) {
+                    (Some(white), Some(black)) => white.is_light() != black.is_light(),
+                    _ => false,
+                }
+            }
+            Self::IsolatedPawn { color, files } => {
+                let pawns = board.by_piece(Piece {
+                    color: *color,
+                    role: Role::Pawn,
+                });
+                files.iter().any(|&file| {
+                    let neighbours = [file.offset(-1), file.offset(1)]
+                        .into_iter()
+                        .flatten()
+                        .fold(Bitboard::EMPTY, |bb, f| bb | Bitboard::from_file(f));
+                    (pawns & Bitboard::from_file(file)).any() && (pawns & neighbours).is_empty()
+                })
+            }
+            Self::Piece { piece, squares } => match piece {
+                None => (board.occupied() & *squares).any(),
+                Some(None) => (!board.occupied() & *squares).any(),
+                Some(Some(piece)) => (board.by_piece(*piece) & *squares).any(),
+            },
+            Self::Not(test) => !test.matches(pos),
+        }
+    }
+}
+
+/// A compiled [`Pattern`].
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    tests: Vec<Test>,
+    min_plies: usize,
+}
+
+impl Matcher {
+    pub fn new(pattern: &Pattern) -> Result<Self> {
+        if pattern.conditions.is_empty() {
+            bail!("the pattern has no conditions");
+        }
+        Ok(Self {
+            tests: pattern
+                .conditions
+                .iter()
+                .map(Test::new)
+                .collect::<Result<_>>()?,
+            min_plies: pattern.min_plies.max(1),
+        })
+    }
+
+    pub fn matches(&self, pos: &Chess) -> bool {
+        self.tests.iter().all(|test| test.matches(pos))
+    }
+
+    /// The first ply of the first run of `min_plies` matching positions in a row.
+    pub fn find(&self, positions: &[Chess]) -> Option<usize> {
+        let mut run = 0;
+        for (ply, pos) in positions.iter().enumerate() {
+            run = if self.matches(pos) { run + 1 } else { 0 };
+            if run == self.min_plies {
+                return Some(ply + 1 - run);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode};
+
+    fn matcher(json: &str) -> Matcher {
+        Matcher::new(&serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    fn pos(fen: &str) -> Chess {
+        fen.parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap()
+    }
+
+    #[test]
+    fn patterns() {
+        let rook_ending = pos("8/8/4k3/8/3P4/8/1r6/3RK3 w - - 0 1");
+        let reversed = pos("8/8/4k3/3p4/8/8/1R6/3rK3 w - - 0 1");
+        let r_p_vs_r = matcher(r#"{"conditions": [{"kind": "material", "material": "KRPvKR"}]}"#);
+        assert!(r_p_vs_r.matches(&rook_ending));
+        assert!(!r_p_vs_r.matches(&reversed));
+        let either = matcher(
+            r#"{"conditions": [{"kind": "material", "material": "RPvR", "eitherColor": true}]}"#,
+        );
+        assert!(either.matches(&reversed));
+
+        // Queens on, light- against dark-squared bishop.
+        let ocb = matcher(
+            r#"{"conditions": [
+                {"kind": "material", "material": "KQB*vKQB*"},
+                {"kind": "oppositeBishops"}
+            ]}"#,
+        );
+        assert!(ocb.matches(&pos("3qk3/4b3/8/8/8/3B4/8/3QK3 w - - 0 1")));
+        assert!(!ocb.matches(&pos("3qk3/3b4/8/8/8/3B4/8/3QK3 w - - 0 1")));
+        assert!(!ocb.matches(&pos("4k3/4b3/8/8/8/3B4/8/3QK3 w - - 0 1")));
+
+        let iqp =
+            matcher(r#"{"conditions": [{"kind": "isolatedPawn", "color": "white", "file": "d"}]}"#);
+        assert!(iqp.matches(&pos("4k3/pp3ppp/8/8/3P4/8/PP3PPP/4K3 w - - 0 1")));
+        assert!(!iqp.matches(&pos("4k3/pp3ppp/8/8/3P4/2P5/PP3PPP/4K3 w - - 0 1")));
+
+        // A white knight anywhere on the fifth rank and no black pawn on d6.
+        let outpost = matcher(
+            r#"{"conditions": [
+                {"kind": "piece", "piece": "N", "squares": "?5"},
+                {"kind": "not", "condition": {"kind": "piece", "piece": "p", "squares": "d6"}}
+            ]}"#,
+        );
+        assert!(outpost.matches(&pos("4k3/8/8/4N3/8/8/8/4K3 w - - 0 1")));
+        assert!(!outpost.matches(&pos("4k3/8/3p4/4N3/8/8/8/4K3 w - - 0 1")));
+
+        let positions = [Chess::default(), rook_ending.clone(), rook_ending];
+        let lasting = Matcher {
+            min_plies: 2,
+            ..r_p_vs_r
+        };
+        assert_eq!(lasting.find(&positions), Some(1));
+        assert!(Matcher::new(&Pattern::default()).is_err());
+        assert!(Matcher::new(
+            &serde_json::from_str(r#"{"conditions": [{"kind": "material", "material": "KX"}]}"#)
+                .unwrap()
+        )
+        .is_err());
+    }
+}
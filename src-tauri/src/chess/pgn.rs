@@ -0,0 +1,1059 @@
+use std::{io::Read, ops::ControlFlow, time::Duration};
+
+use anyhow::{anyhow, Result};
+use pgn_reader::{Nag, RawComment, RawTag, Reader, SanPlus, Skip};
+use shakmaty::{fen::Fen, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Position};
+
+use super::tree::{GameTree, Node, Shape};
+
+/// A single mainline move together with the comment that follows it.
+#[derive(Debug, Clone)]
+pub struct GameMove {
+    pub ply: usize,
+    pub san: String,
+    pub uci: String,
+    pub color: Color,
+    /// Position after the move.
+    pub fen: String,
+    pub comment: Option<String>,
+    /// Remaining time on the mover's clock, from `[%clk h:mm:ss]`.
+    pub clock: Option<Duration>,
+    /// Time spent on the move, from `[%emt h:mm:ss]`.
+    pub emt: Option<Duration>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Game {
+    pub tags: Vec<(String, String)>,
+    pub moves: Vec<GameMove>,
+}
+
+impl Game {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Sets a tag, replacing its value if the game has it already.
+    pub fn set_tag(&mut self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.tags.iter_mut().find(|(k, _)| k == name) {
+            Some((_, v)) => *v = value,
+            None => self.tags.push((name.to_string(), value)),
+        }
+    }
+
+    /// The `FEN` tag, if the game does not start from the initial position.
+    pub fn fen(&self) -> Option<&str> {
+        self.tag("FEN")
+    }
+
+    pub fn start_position(&self) -> Result<Chess> {
+        setup(self.fen())
+    }
+
+    pub fn uci_moves(&self) -> Vec<String> {
+        self.moves.iter().map(|m| m.uci.clone()).collect()
+    }
+
+    /// Mainline moves in SAN, separated by spaces.
+    pub fn movetext(&self) -> String {
+        let sans = self.moves.iter().map(|m| m.san.as_str());
+        sans.collect::<Vec<_>>().join(" ")
+    }
+
+    /// Writes the game as PGN, with the comments, clocks and move times it was read with.
+    pub fn to_pgn(&self) -> String {
+        let mut out = String::new();
+        write_tags(&mut out, &self.tags);
+        out.push('\n');
+
+        let mut w = MovetextWriter::default();
+        let mut number = self
+            .start_position()
+            .map(|pos| pos.fullmoves().get())
+            .unwrap_or(1);
+        let mut interrupted = true;
+        for m in &self.moves {
+            match m.color {
+                Color::White => w.token(&format!("{number}.")),
+                Color::Black if interrupted => w.token(&format!("{number}...")),
+                Color::Black => {}
+            }
+            w.token(&m.san);
+
+            let mut comment = Vec::new();
+            if let Some(clock) = m.clock {
+                comment.push(format!("[%clk {}]", format_clock(clock)));
+            }
+            if let Some(emt) = m.emt {
+                comment.push(format!("[%emt {}]", format_clock(emt)));
+            }
+            comment.extend(m.comment.clone());
+            interrupted = !comment.is_empty();
+            if interrupted {
+                w.comment(&comment.join(" "));
+            }
+            if m.color.is_black() {
+                number += 1;
+            }
+        }
+        w.token(self.tag("Result").unwrap_or("*"));
+        out.push_str(&w.finish());
+        out
+    }
+
+    /// Every position of the mainline, from the start position to the final one.
+    pub fn positions(&self) -> Result<Vec<Chess>> {
+        let mut pos = self.start_position()?;
+        let mut positions = Vec::with_capacity(self.moves.len() + 1);
+        positions.push(pos.clone());
+        for m in &self.moves {
+            let m = m.uci.parse::<UciMove>()?.to_move(&pos)?;
+            pos.play_unchecked(m);
+            positions.push(pos.clone());
+        }
+        Ok(positions)
+    }
+}
+
+fn setup(fen: Option<&str>) -> Result<Chess> {
+    match fen {
+        Some(fen) => Ok(fen.parse::<Fen>()?.into_position(CastlingMode::Standard)?),
+        None => Ok(Chess::default()),
+    }
+}
+
+/// Reads the mainline of every game, keeping tags and move comments. Variations are skipped.
+#[derive(Default)]
+pub struct MainlineVisitor;
+
+pub struct Movetext {
+    pos: Chess,
+    game: Game,
+}
+
+impl pgn_reader::Visitor for MainlineVisitor {
+    type Tags = Game;
+    type Movetext = Movetext;
+    type Output = Result<Game>;
+
+    fn begin_tags(&mut self) -> ControlFlow<Self::Output, Self::Tags> {
+        ControlFlow::Continue(Game::default())
+    }
+
+    fn tag(
+        &mut self,
+        tags: &mut Self::Tags,
+        name: &[u8],
+        value: RawTag<'_>,
+    ) -> ControlFlow<Self::Output> {
+        let name = String::from_utf8_lossy(name).into_owned();
+        tags.tags
+            .push((name, value.decode_utf8_lossy().into_owned()));
+        ControlFlow::Continue(())
+    }
+
+    fn begin_movetext(&mut self, game: Self::Tags) -> ControlFlow<Self::Output, Self::Movetext> {
+        match game.start_position() {
+            Ok(pos) => ControlFlow::Continue(Movetext { pos, game }),
+            Err(e) => ControlFlow::Break(Err(e)),
+        }
+    }
+
+    fn san(
+        &mut self,
+        movetext: &mut Self::Movetext,
+        san_plus: SanPlus,
+    ) -> ControlFlow<Self::Output> {
+        let pos = &mut movetext.pos;
+        let color = pos.turn();
+        match san_plus.san.to_move(pos) {
+            Ok(m) => {
+                let uci = m.to_uci(CastlingMode::Standard).to_string();
+                pos.play_unchecked(m);
+                let moves = &mut movetext.game.moves;
+                moves.push(GameMove {
+                    ply: moves.len() + 1,
+                    san: san_plus.to_string(),
+                    uci,
+                    color,
+                    fen: Fen::from_position(pos, EnPassantMode::Legal).to_string(),
+                    comment: None,
+                    clock: None,
+                    emt: None,
+                });
+                ControlFlow::Continue(())
+            }
+            Err(e) => ControlFlow::Break(Err(anyhow!(
+                "illegal move {san_plus} at ply {}: {e}",
+                movetext.game.moves.len() + 1
+            ))),
+        }
+    }
+
+    fn comment(
+        &mut self,
+        movetext: &mut Self::Movetext,
+        comment: RawComment<'_>,
+    ) -> ControlFlow<Self::Output> {
+        let Some(m) = movetext.game.moves.last_mut() else {
+            return ControlFlow::Continue(());
+        };
+        let comment = String::from_utf8_lossy(comment.as_bytes());
+        if let Some(clock) = command(&comment, "clk").and_then(parse_duration) {
+            m.clock = Some(clock);
+        }
+        if let Some(emt) = command(&comment, "emt").and_then(parse_duration) {
+            m.emt = Some(emt);
+        }
+        let text = strip_commands(&comment);
+        if !text.is_empty() {
+            match &mut m.comment {
+                Some(c) => {
+                    c.push(' ');
+                    c.push_str(&text);
+                }
+                None => m.comment = Some(text),
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn begin_variation(
+        &mut self,
+        _movetext: &mut Self::Movetext,
+    ) -> ControlFlow<Self::Output, Skip> {
+        ControlFlow::Continue(Skip(true))
+    }
+
+    fn end_game(&mut self, movetext: Self::Movetext) -> Self::Output {
+        Ok(movetext.game)
+    }
+}
+
+/// Iterator over the games of a PGN source.
+pub struct Games<R> {
+    reader: Reader<R>,
+    visitor: MainlineVisitor,
+}
+
+impl<R: Read> Iterator for Games<R> {
+    type Item = Result<Game>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_game(&mut self.visitor) {
+            Ok(Some(game)) => Some(game),
+            Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+pub fn read_games<R: Read>(reader: R) -> Games<R> {
+    Games {
+        reader: Reader::new(reader),
+        visitor: MainlineVisitor,
+    }
+}
+
+/// A game with all of its variations and annotations.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AnnotatedGame {
+    pub tags: Vec<(String, String)>,
+    pub tree: GameTree,
+}
+
+impl AnnotatedGame {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Merges two copies of a game, one possibly truncated: the mainline of the longer copy
+    /// with the variations and annotations of both, and the tags of both where the longer copy
+    /// has none.
+    pub fn merged(&self, other: &AnnotatedGame) -> AnnotatedGame {
+        let (mut game, short) = if other.tree.mainline().count() > self.tree.mainline().count() {
+            (other.clone(), self)
+        } else {
+            (self.clone(), other)
+        };
+        for (name, value) in &short.tags {
+            match game.tags.iter_mut().find(|(k, _)| k == name) {
+                Some((_, v)) if v.is_empty() || ["?", "*", "????.??.??"].contains(&v.as_str()) => {
+                    *v = value.clone();
+                }
+                Some(_) => {}
+                None => game.tags.push((name.clone(), value.clone())),
+            }
+        }
+        game.tree.merge(&short.tree);
+        game
+    }
+
+    /// The mainline as a [`Game`], as [`MainlineVisitor`] would have read it.
+    pub fn mainline(&self) -> Game {
+        let mut pos = self.tree.start.clone();
+        let mut moves = Vec::new();
+        let mut nodes = &self.tree.children;
+        while let Some(node) = nodes.first() {
+            let color = pos.turn();
+            pos.play_unchecked(node.mv);
+            let comment = node.comment.as_deref();
+            moves.push(GameMove {
+                ply: moves.len() + 1,
+                san: node.san.clone(),
+                uci: node.uci(),
+                color,
+                fen: Fen::from_position(&pos, EnPassantMode::Legal).to_string(),
+                comment: comment.map(strip_commands).filter(|c| !c.is_empty()),
+                clock: node.clock,
+                emt: comment
+                    .and_then(|c| command(c, "emt"))
+                    .and_then(parse_duration),
+            });
+            nodes = &node.children;
+        }
+        Game {
+            tags: self.tags.clone(),
+            moves,
+        }
+    }
+}
+
+/// The Seven Tag Roster, which every exported game starts with, and the value of missing tags.
+const ROSTER: [(&str, &str); 7] = [
+    ("Event", "?"),
+    ("Site", "?"),
+    ("Date", "????.??.??"),
+    ("Round", "?"),
+    ("White", "?"),
+    ("Black", "?"),
+    ("Result", "*"),
+];
+
+impl AnnotatedGame {
+    /// Writes the game as PGN: the Seven Tag Roster first, then the other tags, and the movetext
+    /// with every variation, NAG, comment, shape and clock.
+    pub fn to_pgn(&self) -> String {
+        let mut tags = ROSTER
+            .iter()
+            .map(|&(name, default)| {
+                (
+                    name.to_string(),
+                    self.tag(name).unwrap_or(default).to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        tags.extend(
+            self.tags
+                .iter()
+                .filter(|(k, _)| !ROSTER.iter().any(|(name, _)| k == name))
+                .cloned(),
+        );
+        if self.tree.start != Chess::default() {
+            let fen = Fen::from_position(&self.tree.start, EnPassantMode::Legal).to_string();
+            for (name, value) in [("SetUp", "1".to_string()), ("FEN", fen)] {
+                match tags.iter_mut().find(|(k, _)| k == name) {
+                    Some((_, v)) => *v = value,
+                    None => tags.push((name.to_string(), value)),
+                }
+            }
+        }
+
+        let mut out = String::new();
+        write_tags(&mut out, &tags);
+        out.push('\n');
+        let mut w = MovetextWriter::default();
+        let start = &self.tree.start;
+        write_line(
+            &mut w,
+            &self.tree.children,
+            start.fullmoves().get(),
+            start.turn(),
+        );
+        let result = tags[6].1.as_str();
+        w.token(match result {
+            "1-0" | "0-1" | "1/2-1/2" => result,
+            _ => "*",
+        });
+        out.push_str(&w.finish());
+        out
+    }
+}
+
+/// Writes `nodes[0]` with its alternatives as variations, then the line that follows it.
+fn write_line(w: &mut MovetextWriter, nodes: &[Node], mut number: u32, mut turn: Color) {
+    // Black's moves need their number after anything that interrupts the line.
+    let mut interrupted = true;
+    let mut nodes = nodes;
+    while let Some((node, alternatives)) = nodes.split_first() {
+        if let Some(text) = &node.comment_before {
+            w.comment(text);
+            interrupted = true;
+        }
+        match turn {
+            Color::White => w.token(&format!("{number}.")),
+            Color::Black if interrupted => w.token(&format!("{number}...")),
+            Color::Black => {}
+        }
+        w.token(&node.san);
+        for nag in &node.nags {
+            w.token(&format!("${nag}"));
+        }
+        interrupted = write_comment(w, node);
+
+        let (next, next_turn) = match turn {
+            Color::White => (number, Color::Black),
+            Color::Black => (number + 1, Color::White),
+        };
+        for alternative in alternatives {
+            w.token("(");
+            write_line(w, std::slice::from_ref(alternative), number, turn);
+            w.token(")");
+            interrupted = true;
+        }
+        (number, turn) = (next, next_turn);
+        nodes = &node.children;
+    }
+}
+
+/// Writes the comment after a move, with its clock and shapes as commands. Returns whether there
+/// was one.
+fn write_comment(w: &mut MovetextWriter, node: &Node) -> bool {
+    let mut commands = Vec::new();
+    if let Some(clock) = node.clock {
+        commands.push(format!("[%clk {}]", format_clock(clock)));
+    }
+    let (squares, arrows): (Vec<&Shape>, Vec<_>) =
+        node.shapes.iter().partition(|s| s.dest.is_none());
+    for (name, shapes) in [("csl", squares), ("cal", arrows)] {
+        if !shapes.is_empty() {
+            let shapes = shapes.iter().map(|s| s.to_pgn()).collect::<Vec<_>>();
+            commands.push(format!("[%{name} {}]", shapes.join(",")));
+        }
+    }
+    commands.extend(node.comment.clone());
+    if commands.is_empty() {
+        return false;
+    }
+    w.comment(&commands.join(" "));
+    true
+}
+
+/// Comment commands that are read into [`Node`] fields rather than kept as text.
+const NODE_COMMANDS: [&str; 3] = ["clk", "csl", "cal"];
+
+/// Reads whole games into move trees: variations, comments, NAGs, `[%csl]`/`[%cal]` shapes and
+/// `[%clk]` clocks. Other commands, like `[%eval]`, stay in the comment text, as do all commands
+/// of a comment before a move, which belong to the position before it.
+#[derive(Default)]
+pub struct TreeVisitor;
+
+pub struct TreeMovetext {
+    game: AnnotatedGame,
+    /// Child indices from the root to the last move played.
+    path: Vec<usize>,
+    /// The positions along `path`, starting with the initial one.
+    positions: Vec<Chess>,
+    /// `path` and `positions` of the lines enclosing the current variation.
+    stack: Vec<(Vec<usize>, Vec<Chess>, Option<String>)>,
+    /// Whether a comment belongs to the last move rather than to the next one.
+    after_move: bool,
+    /// Comment before the next move.
+    before: Option<String>,
+    /// Index of the first mainline move among the root's children, which can follow
+    /// variations that start before it.
+    first_move: Option<usize>,
+}
+
+impl TreeMovetext {
+    /// The moves that can follow the last one.
+    fn children(&mut self) -> &mut Vec<Node> {
+        let mut children = &mut self.game.tree.children;
+        for &i in &self.path {
+            children = &mut children[i].children;
+        }
+        children
+    }
+
+    fn last(&mut self) -> Option<&mut Node> {
+        let (&last, parents) = self.path.split_last()?;
+        let mut children = &mut self.game.tree.children;
+        for &i in parents {
+            children = &mut children[i].children;
+        }
+        children.get_mut(last)
+    }
+}
+
+fn append(comment: &mut Option<String>, text: String) {
+    if text.is_empty() {
+        return;
+    }
+    match comment {
+        Some(c) => {
+            c.push(' ');
+            c.push_str(&text);
+        }
+        None => *comment = Some(text),
+    }
+}
+
+/// Moves the commands of a comment after `node` into its fields and appends the rest.
+fn annotate(node: &mut Node, comment: &str) {
+    if let Some(clock) = command(comment, "clk").and_then(parse_duration) {
+        node.clock = Some(clock);
+    }
+    for name in ["csl", "cal"] {
+        if let Some(shapes) = command(comment, name) {
+            node.shapes
+                .extend(shapes.split(',').filter_map(|s| Shape::from_pgn(s.trim())));
+        }
+    }
+    append(
+        &mut node.comment,
+        strip_named_commands(comment, &NODE_COMMANDS),
+    );
+}
+
+impl pgn_reader::Visitor for TreeVisitor {
+    type Tags = Vec<(String, String)>;
+    type Movetext = TreeMovetext;
+    type Output = Result<AnnotatedGame>;
+
+    fn begin_tags(&mut self) -> ControlFlow<Self::Output, Self::Tags> {
+        ControlFlow::Continue(Vec::new())
+    }
+
+    fn tag(
+        &mut self,
+        tags: &mut Self::Tags,
+        name: &[u8],
+        value: RawTag<'_>,
+    ) -> ControlFlow<Self::Output> {
+        let name = String::from_utf8_lossy(name).into_owned();
+        tags.push((name, value.decode_utf8_lossy().into_owned()));
+        ControlFlow::Continue(())
+    }
+
+    fn begin_movetext(&mut self, tags: Self::Tags) -> ControlFlow<Self::Output, Self::Movetext> {
+        let fen = tags
+            .iter()
+            .find(|(k, _)| k == "FEN")
+            .map(|(_, v)| v.as_str());
+        match setup(fen) {
+            Ok(start) => ControlFlow::Continue(TreeMovetext {
+                game: AnnotatedGame {
+                    tree: GameTree::new(start.clone()),
+                    tags,
+                },
+                path: Vec::new(),
+                positions: vec![start],
+                stack: Vec::new(),
+                after_move: false,
+                before: None,
+                first_move: None,
+            }),
+            Err(e) => ControlFlow::Break(Err(e)),
+        }
+    }
+
+    fn san(
+        &mut self,
+        movetext: &mut Self::Movetext,
+        san_plus: SanPlus,
+    ) -> ControlFlow<Self::Output> {
+        let pos = movetext.positions.last().expect("start position");
+        let m = match san_plus.san.to_move(pos) {
+            Ok(m) => m,
+            Err(e) => {
+                return ControlFlow::Break(Err(anyhow!(
+                    "illegal move {san_plus} at ply {}: {e}",
+                    movetext.path.len() + 1
+                )))
+            }
+        };
+        let mut after = pos.clone();
+        let mut node = Node::new(&mut after, m);
+        node.comment_before = movetext.before.take();
+        let children = movetext.children();
+        children.push(node);
+        let index = children.len() - 1;
+        if movetext.path.is_empty() && movetext.stack.is_empty() {
+            movetext.first_move.get_or_insert(index);
+        }
+        movetext.path.push(index);
+        movetext.positions.push(after);
+        movetext.after_move = true;
+        ControlFlow::Continue(())
+    }
+
+    fn nag(&mut self, movetext: &mut Self::Movetext, nag: Nag) -> ControlFlow<Self::Output> {
+        if movetext.after_move {
+            if let Some(node) = movetext.last() {
+                node.nags.push(nag.0);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn comment(
+        &mut self,
+        movetext: &mut Self::Movetext,
+        comment: RawComment<'_>,
+    ) -> ControlFlow<Self::Output> {
+        let comment = String::from_utf8_lossy(comment.as_bytes());
+        match movetext.after_move {
+            true => {
+                if let Some(node) = movetext.last() {
+                    annotate(node, &comment);
+                }
+            }
+            false => append(
+                &mut movetext.before,
+                comment.split_whitespace().collect::<Vec<_>>().join(" "),
+            ),
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn begin_variation(
+        &mut self,
+        movetext: &mut Self::Movetext,
+    ) -> ControlFlow<Self::Output, Skip> {
+        // A comment before the next move is kept for it, as when a variation comes first.
+        movetext.stack.push((
+            movetext.path.clone(),
+            movetext.positions.clone(),
+            movetext.before.take(),
+        ));
+        // The variation replaces the last move. One before the first move is read as an
+        // alternative to it.
+        if !movetext.path.is_empty() {
+            movetext.path.pop();
+            movetext.positions.pop();
+        }
+        movetext.after_move = false;
+        ControlFlow::Continue(Skip(false))
+    }
+
+    fn end_variation(&mut self, movetext: &mut Self::Movetext) -> ControlFlow<Self::Output> {
+        if let Some((path, positions, before)) = movetext.stack.pop() {
+            movetext.path = path;
+            movetext.positions = positions;
+            movetext.after_move = !movetext.path.is_empty();
+            movetext.before = before;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn end_game(&mut self, mut movetext: Self::Movetext) -> Self::Output {
+        let children = &mut movetext.game.tree.children;
+        if let Some(first) = movetext.first_move.filter(|&i| i > 0) {
+            let node = children.remove(first);
+            children.insert(0, node);
+        }
+        Ok(movetext.game)
+    }
+}
+
+/// Iterator over the games of a PGN source, read as move trees.
+pub struct AnnotatedGames<R> {
+    reader: Reader<R>,
+    visitor: TreeVisitor,
+}
+
+impl<R: Read> Iterator for AnnotatedGames<R> {
+    type Item = Result<AnnotatedGame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_game(&mut self.visitor) {
+            Ok(Some(game)) => Some(game),
+            Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+pub fn read_annotated<R: Read>(reader: R) -> AnnotatedGames<R> {
+    AnnotatedGames {
+        reader: Reader::new(reader),
+        visitor: TreeVisitor,
+    }
+}
+
+/// Lines of exported movetext are wrapped at this many columns.
+pub const LINE_WIDTH: usize = 80;
+
+/// Writes the tag pair section, escaping quotes and backslashes in values.
+pub fn write_tags(out: &mut String, tags: &[(String, String)]) {
+    for (name, value) in tags {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        out.push_str(&format!("[{name} \"{value}\"]\n"));
+    }
+}
+
+/// Sets tags in the header of a single game's PGN text, leaving the movetext as it is.
+pub fn set_tags(pgn: &str, tags: &[(&str, &str)]) -> String {
+    let pgn = pgn.trim_start();
+    let header_len = pgn
+        .lines()
+        .take_while(|line| line.trim_start().starts_with('['))
+        .count();
+    let mut lines = pgn.lines();
+    let mut header = lines
+        .by_ref()
+        .take(header_len)
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    for (name, value) in tags {
+        let mut line = String::new();
+        write_tags(&mut line, &[(name.to_string(), value.to_string())]);
+        let line = line.trim_end().to_string();
+        let prefix = format!("[{name} ");
+        match header
+            .iter_mut()
+            .find(|l| l.trim_start().starts_with(&prefix))
+        {
+            Some(l) => *l = line,
+            None => header.push(line),
+        }
+    }
+
+    let mut out = header.join("\n");
+    out.push('\n');
+    if header_len == 0 {
+        out.push('\n');
+    }
+    out.push_str(&lines.collect::<Vec<_>>().join("\n"));
+    if pgn.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+/// Joins movetext tokens with spaces, wrapping lines at [`LINE_WIDTH`].
+#[derive(Default)]
+pub struct MovetextWriter {
+    out: String,
+    line: usize,
+}
+
+impl MovetextWriter {
+    pub fn token(&mut self, token: &str) {
+        let len = token.chars().count();
+        if self.line > 0 && self.line + 1 + len > LINE_WIDTH {
+            self.out.push('\n');
+            self.line = 0;
+        } else if self.line > 0 {
+            self.out.push(' ');
+            self.line += 1;
+        }
+        self.out.push_str(token);
+        self.line += len;
+    }
+
+    /// Writes a `{ comment }`, which may be wrapped between its words. PGN comments cannot
+    /// contain `}`, so it is left out.
+    pub fn comment(&mut self, text: &str) {
+        self.token("{");
+        for word in text.split_whitespace() {
+            let word = word.replace('}', "");
+            if !word.is_empty() {
+                self.token(&word);
+            }
+        }
+        self.token("}");
+    }
+
+    pub fn finish(mut self) -> String {
+        self.out.push('\n');
+        self.out
+    }
+}
+
+/// Returns the argument of an embedded command like `[%clk 0:03:00]`.
+pub fn command<'a>(comment: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("[%{name}");
+    // Any whitespace may follow the name, including a line break of wrapped movetext.
+    let (start, _) = comment.match_indices(&prefix).find(|(i, _)| {
+        comment[i + prefix.len()..]
+            .chars()
+            .next()
+            .is_some_and(char::is_whitespace)
+    })?;
+    let start = start + prefix.len();
+    let end = comment[start..].find(']')? + start;
+    Some(comment[start..end].trim())
+}
+
+/// Removes every `[%...]` command from a comment, leaving the plain text.
+pub fn strip_commands(comment: &str) -> String {
+    strip_matching_commands(comment, |_| true)
+}
+
+/// Removes the commands called `names` from a comment, keeping any others.
+pub fn strip_named_commands(comment: &str, names: &[&str]) -> String {
+    strip_matching_commands(comment, |command| {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        names.contains(&name)
+    })
+}
+
+/// Removes the `[%...]` commands whose contents, without the brackets and `%`, satisfy `strip`.
+fn strip_matching_commands(comment: &str, strip: impl Fn(&str) -> bool) -> String {
+    let mut text = String::with_capacity(comment.len());
+    let mut rest = comment;
+    while let Some(start) = rest.find("[%") {
+        match rest[start..].find(']') {
+            Some(end) if strip(&rest[start + 2..start + end]) => {
+                text.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            Some(end) => {
+                text.push_str(&rest[..start + end + 1]);
+                rest = &rest[start + end + 1..];
+            }
+            None => {
+                text.push_str(&rest[..start]);
+                rest = "";
+                break;
+            }
+        }
+    }
+    text.push_str(rest);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Parses `h:mm:ss`, `m:ss` or `ss`, with optional fractional seconds. Negative, infinite and
+/// out of range times are rejected.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in s.trim().split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Formats a duration as `h:mm:ss`, the way clock commands are written.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Formats a clock like [`format_duration`], keeping milliseconds if there are any.
+pub fn format_clock(d: Duration) -> String {
+    let millis = d.subsec_millis();
+    match millis {
+        0 => format_duration(d),
+        _ => {
+            let fraction = format!("{millis:03}");
+            format!("{}.{}", format_duration(d), fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+/// Today's date in the PGN `Date` tag format, `YYYY.MM.DD` (UTC).
+pub fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}.{month:02}.{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_comments() {
+        let pgn = r#"[White "a"]
+[Black "b"]
+[TimeControl "180+2"]
+
+1. e4 { [%clk 0:03:00] } 1... e5 { [%clk 0:02:58.5] good reply } 2. Nf3 { [%emt 0:00:12] } (2. f4 exf4) 2... Nc6 *"#;
+
+        let game = read_games(pgn.as_bytes()).next().unwrap().unwrap();
+        assert_eq!(game.tag("TimeControl"), Some("180+2"));
+        assert_eq!(game.moves.len(), 4);
+        assert_eq!(game.moves[0].clock, Some(Duration::from_secs(180)));
+        assert_eq!(game.moves[1].clock, Some(Duration::from_millis(178_500)));
+        assert_eq!(game.moves[1].comment.as_deref(), Some("good reply"));
+        assert_eq!(game.moves[2].emt, Some(Duration::from_secs(12)));
+        assert_eq!(game.moves[3].san, "Nc6");
+        assert_eq!(game.moves[3].uci, "b8c6");
+    }
+
+    #[test]
+    fn annotated_games() {
+        let pgn = r#"[Event "Study"]
+
+{ Start } 1. e4 $1 { [%clk 0:03:00] [%csl Ge4,Rd5] [%cal Ge2e4] Best by test [%eval 0.3] }
+(1. d4 d5 (1... Nf6 2. c4 { Indian }) 2. c4) (1. c4) 1... c5 ( { Classical } 1... e5 2. Nf3 ) 2. Nf3 *
+
+[Event "Endgame"]
+[SetUp "1"]
+[FEN "8/8/8/4k3/8/8/4P3/4K3 w - - 0 1"]
+
+1. Kd2 Kd4 *"#;
+        let games = read_annotated(pgn.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(games.len(), 2);
+
+        let tree = &games[0].tree;
+        assert_eq!(tree.len(), 11);
+        fn sans(nodes: &[Node]) -> Vec<&str> {
+            nodes.iter().map(|n| n.san.as_str()).collect()
+        }
+        assert_eq!(sans(&tree.children), ["e4", "d4", "c4"]);
+        assert_eq!(
+            tree.mainline().map(|n| n.san.as_str()).collect::<Vec<_>>(),
+            ["e4", "c5", "Nf3"]
+        );
+        let e4 = &tree.children[0];
+        assert_eq!(e4.comment_before.as_deref(), Some("Start"));
+        assert_eq!(e4.comment.as_deref(), Some("Best by test [%eval 0.3]"));
+        assert_eq!(e4.nags, [1]);
+        assert_eq!(e4.clock, Some(Duration::from_secs(180)));
+        assert_eq!(
+            e4.shapes.iter().map(Shape::to_pgn).collect::<Vec<_>>(),
+            ["Ge4", "Rd5", "Ge2e4"]
+        );
+        assert_eq!(sans(&e4.children), ["c5", "e5"]);
+        assert_eq!(e4.children[1].comment_before.as_deref(), Some("Classical"));
+        let d4 = &tree.children[1];
+        assert_eq!(sans(&d4.children), ["d5", "Nf6"]);
+        assert_eq!(
+            d4.children[1].children[0].comment.as_deref(),
+            Some("Indian")
+        );
+
+        let endgame = &games[1];
+        assert_eq!(endgame.tag("SetUp"), Some("1"));
+        assert_eq!(endgame.tree.start.board().occupied().count(), 3);
+        assert_eq!(endgame.tree.mainline().count(), 2);
+    }
+
+    #[test]
+    fn before_first_move() {
+        let pgn = "{ [%cal Ge2e4] [%clk 0:05:00] Start } ( 1. d4 d5 ) 1. e4 e5 *";
+        let game = read_annotated(pgn.as_bytes()).next().unwrap().unwrap();
+        let tree = &game.tree;
+        let sans = tree
+            .children
+            .iter()
+            .map(|n| n.san.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sans, ["e4", "d4"]);
+        assert_eq!(tree.mainline().count(), 2);
+        assert_eq!(
+            tree.children[0].comment_before.as_deref(),
+            Some("[%cal Ge2e4] [%clk 0:05:00] Start")
+        );
+        assert!(tree.children[0].shapes.is_empty());
+        assert!(game
+            .to_pgn()
+            .contains("{ [%cal Ge2e4] [%clk 0:05:00] Start } 1. e4 ( 1. d4 d5 ) 1... e5 *"));
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let pgn = r#"[Event "Training"]
+[Site "?"]
+[Date "2024.05.01"]
+[Round "?"]
+[White "Student"]
+[Black "Coach"]
+[Result "1-0"]
+[Annotator "Coach"]
+
+{ A long introduction to the opening, long enough that the comment has to be wrapped over
+more than one line } 1. e4 $1 { [%clk 0:03:00] [%csl Ge4] [%cal Ge2e4,Rd7d5] } 1... c5
+( 1... e5 2. Nf3 ( 2. f4 { Gambit } 2... exf4 $5 ) ( { Quiet } 2. Nc3 ) 2... Nc6 ) 2. Nf3
+{ [%clk 0:02:58.5] [%eval 0.3] } d6 $6 3. d4 1-0
+
+[Event "Endgame"]
+[SetUp "1"]
+[FEN "8/8/8/4k3/8/8/4P3/4K3 b - - 0 60"]
+
+60... Kd4 (60... Ke4 61. Kf2) 61. Kd2 *"#;
+        let games = read_annotated(pgn.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let exported = games.iter().map(AnnotatedGame::to_pgn).collect::<Vec<_>>();
+        assert!(exported
+            .iter()
+            .flat_map(|pgn| pgn.lines())
+            .all(|line| line.chars().count() <= LINE_WIDTH));
+        assert!(exported[0].starts_with("[Event \"Training\"]\n[Site \"?\"]\n"));
+        assert!(exported[0].contains("[Result \"1-0\"]\n[Annotator \"Coach\"]\n\n"));
+        assert!(exported[0].contains("2... exf4 $5 ) ( {\nQuiet } 2. Nc3 ) 2... Nc6 ) 2. Nf3 {"));
+        assert!(exported[1].contains("[Date \"????.??.??\"]"));
+        assert!(exported[1].contains("[FEN \"8/8/8/4k3/8/8/4P3/4K3 b - - 0 60\"]"));
+        assert!(exported[1].contains("60... Kd4 ( 60... Ke4 61. Kf2 ) 61. Kd2 *"));
+
+        let again = read_annotated(exported.join("\n").as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(again[0], games[0]);
+        assert_eq!(again[1].tree, games[1].tree);
+        assert_eq!(
+            again.iter().map(AnnotatedGame::to_pgn).collect::<Vec<_>>(),
+            exported
+        );
+
+        // A `}` would end the comment early and turn the rest into movetext.
+        let mut edited = games[0].clone();
+        edited.tree.children[0].comment = Some("Threatens} mate } 2. Qh5".into());
+        let read = read_annotated(edited.to_pgn().as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        edited.tree.children[0].comment = Some("Threatens mate 2. Qh5".into());
+        assert_eq!(read, edited);
+    }
+
+    #[test]
+    fn setting_tags() {
+        let pgn = "[White \"a\"]\n[ECO \"A00\"]\n\n1. e4 *\n";
+        let tagged = set_tags(pgn, &[("ECO", "B00"), ("Opening", "King's Pawn")]);
+        assert_eq!(
+            tagged,
+            "[White \"a\"]\n[ECO \"B00\"]\n[Opening \"King's Pawn\"]\n\n1. e4 *\n"
+        );
+        assert_eq!(
+            set_tags("1. e4 *", &[("ECO", "B00")]),
+            "[ECO \"B00\"]\n\n1. e4 *"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1:00:01"), Some(Duration::from_secs(3601)));
+        assert_eq!(parse_duration("0:59"), Some(Duration::from_secs(59)));
+        assert_eq!(parse_duration("x"), None);
+        for bad in ["inf", "1e400", "NaN", "-5"] {
+            assert_eq!(parse_duration(bad), None);
+        }
+        assert_eq!(format_duration(Duration::from_secs(3601)), "1:00:01");
+        assert_eq!(format_clock(Duration::from_millis(178_500)), "0:02:58.5");
+    }
+}
@@ -0,0 +1,677 @@
+//! Playing a game against a strength-limited engine.
+
+use std::{
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    CastlingMode, Chess, Color, EnPassantMode, Position,
+};
+use tauri::{ipc::Channel, State};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, error, Instrument};
+
+use super::{
+    clock::{Clock, TimeControl},
+    matches::{rules_result, EngineConfig, GameResult},
+    openings::OpeningBook,
+    pgn::{format_duration, today, write_tags, MovetextWriter},
+    polyglot::PolyglotBook,
+    search, Engine, Go, Score, Search,
+};
+use crate::{db::Database, AppState};
+
+/// Search time per move when the game is played without a clock.
+const DEFAULT_MOVETIME: u64 = 1000;
+
+/// How strong the engine opponent plays.
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Strength {
+    #[default]
+    Full,
+    /// `UCI_LimitStrength` with `UCI_Elo`.
+    Elo { elo: u32 },
+    /// Stockfish's `Skill Level`, from 0 to 20.
+    Skill { level: u32 },
+}
+
+impl Strength {
+    fn options(&self) -> Vec<(String, String)> {
+        match *self {
+            Strength::Full => vec![("UCI_LimitStrength".into(), "false".into())],
+            Strength::Elo { elo } => vec![
+                ("UCI_LimitStrength".into(), "true".into()),
+                ("UCI_Elo".into(), elo.to_string()),
+            ],
+            Strength::Skill { level } => vec![("Skill Level".into(), level.to_string())],
+        }
+    }
+
+    fn describe(&self) -> Option<String> {
+        match *self {
+            Strength::Full => None,
+            Strength::Elo { elo } => Some(format!("{elo}")),
+            Strength::Skill { level } => Some(format!("level {level}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PlayConfig {
+    pub engine: EngineConfig,
+    #[serde(default)]
+    pub strength: Strength,
+    pub time_control: Option<TimeControl>,
+    /// Engine search time per move in milliseconds, used when there is no clock.
+    pub movetime: Option<u64>,
+    pub player_is_white: bool,
+    pub fen: Option<String>,
+    pub player_name: Option<String>,
+    /// Let the engine think on the player's time.
+    #[serde(default)]
+    pub ponder: bool,
+    /// A Polyglot book the engine plays from while it has moves for the position.
+    pub book: Option<PathBuf>,
+}
+
+/// A move of the game with what is needed to take it back.
+struct Ply {
+    san: String,
+    uci: String,
+    before: Chess,
+    clock_before: Option<Clock>,
+    /// Remaining time of the mover after the move.
+    remaining: Option<Duration>,
+    hash: Zobrist64,
+}
+
+pub struct PlayGame {
+    config: PlayConfig,
+    start: Chess,
+    pos: Chess,
+    history: Vec<Ply>,
+    clock: Option<Clock>,
+    result: Option<(GameResult, String)>,
+    /// Latest score of the engine, from its own point of view.
+    engine_score: Option<Score>,
+    book: Option<PolyglotBook>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PlayState {
+    pub fen: String,
+    /// Moves played so far, in SAN.
+    pub moves: Vec<String>,
+    pub last_move: Option<String>,
+    pub white_to_move: bool,
+    pub player_is_white: bool,
+    /// Remaining white and black time in milliseconds.
+    pub clock: Option<[u64; 2]>,
+    pub engine_thinking: bool,
+    pub engine_score: Option<Score>,
+    pub result: Option<GameResult>,
+    pub termination: Option<String>,
+}
+
+impl PlayGame {
+    pub fn new(config: PlayConfig) -> Result<Self> {
+        let start: Chess = match &config.fen {
+            Some(fen) => fen.parse::<Fen>()?.into_position(CastlingMode::Standard)?,
+            None => Chess::default(),
+        };
+        let book = config.book.as_ref().map(PolyglotBook::open).transpose()?;
+        let mut clock = config.time_control.map(Clock::new);
+        if let Some(clock) = &mut clock {
+            clock.start(start.turn());
+        }
+        let mut game = Self {
+            config,
+            pos: start.clone(),
+            start,
+            history: Vec::new(),
+            clock,
+            result: None,
+            engine_score: None,
+            book,
+        };
+        game.check_rules();
+        Ok(game)
+    }
+
+    fn player(&self) -> Color {
+        Color::from_white(self.config.player_is_white)
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.result.is_some()
+    }
+
+    pub fn is_engine_turn(&self) -> bool {
+        !self.is_over() && self.pos.turn() != self.player()
+    }
+
+    fn finish(&mut self, result: GameResult, termination: &str) {
+        if let Some(clock) = &mut self.clock {
+            clock.halt();
+        }
+        debug!(result = result.as_str(), termination, "game over");
+        self.result = Some((result, termination.into()));
+    }
+
+    /// Ends the game if `color` ran out of time. A flag is only a loss if the opponent can mate.
+    fn flag(&mut self, color: Color) {
+        if self.pos.has_insufficient_material(!color) {
+            self.finish(GameResult::Draw, "time forfeit");
+        } else {
+            self.finish(GameResult::win(!color), "time forfeit");
+        }
+    }
+
+    pub fn check_flag(&mut self) {
+        if let Some(color) = self.clock.as_ref().and_then(Clock::flagged) {
+            self.flag(color);
+        }
+    }
+
+    fn check_rules(&mut self) {
+        let hash = self.hash();
+        let repetitions = u32::from(self.start_hash() == hash)
+            + self.history.iter().filter(|p| p.hash == hash).count() as u32;
+        if let Some((result, termination)) = rules_result(&self.pos, repetitions) {
+            self.finish(result, termination);
+        }
+    }
+
+    fn hash(&self) -> Zobrist64 {
+        self.pos.zobrist_hash(EnPassantMode::Legal)
+    }
+
+    fn start_hash(&self) -> Zobrist64 {
+        self.start.zobrist_hash(EnPassantMode::Legal)
+    }
+
+    /// Plays a move in UCI notation for the side to move.
+    pub fn play(&mut self, uci: &str) -> Result<()> {
+        ensure!(!self.is_over(), "the game is over");
+        let m = uci.parse::<UciMove>()?.to_move(&self.pos)?;
+        let mover = self.pos.turn();
+
+        let clock_before = self.clock.as_ref().map(Clock::snapshot);
+        if let Some(clock) = &mut self.clock {
+            if !clock.press() {
+                self.flag(mover);
+                return Ok(());
+            }
+        }
+
+        let before = self.pos.clone();
+        let san = SanPlus::from_move_and_play_unchecked(&mut self.pos, m);
+        self.history.push(Ply {
+            san: san.to_string(),
+            uci: m.to_uci(CastlingMode::Standard).to_string(),
+            before,
+            clock_before,
+            remaining: self.clock.as_ref().map(|c| c.remaining(mover)),
+            hash: self.hash(),
+        });
+
+        self.check_rules();
+        if !self.is_over() {
+            if let Some(clock) = &mut self.clock {
+                clock.start(self.pos.turn());
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes back the player's last move, and the engine's reply to it if there was one.
+    pub fn takeback(&mut self) -> Result<()> {
+        ensure!(!self.is_over(), "the game is over");
+        let plies = if self.pos.turn() == self.player() {
+            2
+        } else {
+            1
+        };
+        ensure!(self.history.len() >= plies, "no move to take back");
+
+        for _ in 0..plies {
+            let ply = self.history.pop().context("no move to take back")?;
+            self.pos = ply.before;
+            self.clock = ply.clock_before;
+        }
+        if let Some(clock) = &mut self.clock {
+            clock.start(self.pos.turn());
+        }
+        self.engine_score = None;
+        Ok(())
+    }
+
+    pub fn resign(&mut self) -> Result<()> {
+        ensure!(!self.is_over(), "the game is over");
+        self.finish(GameResult::win(!self.player()), "resignation");
+        Ok(())
+    }
+
+    /// Offers a draw to the engine, which accepts when it is not better. Returns whether it did.
+    pub fn offer_draw(&mut self) -> Result<bool> {
+        ensure!(!self.is_over(), "the game is over");
+        let cp = self.engine_score.map(|s| s.as_cp()).unwrap_or_default();
+        let accepted = cp <= -100 || (cp <= 25 && self.pos.fullmoves().get() > 20);
+        if accepted {
+            self.finish(GameResult::Draw, "agreement");
+        }
+        Ok(accepted)
+    }
+
+    /// A weighted random move from the book, if the position is in it.
+    fn book_move(&self) -> Option<String> {
+        let roll = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        let m = self.book.as_ref()?.pick(&self.pos, roll)?;
+        Some(m.to_uci(CastlingMode::Standard).to_string())
+    }
+
+    fn uci_moves(&self) -> Vec<&str> {
+        self.history.iter().map(|p| p.uci.as_str()).collect()
+    }
+
+    /// The search for the engine's next move.
+    fn go(&self) -> Go {
+        let job = Go::new()
+            .fen(Fen::from_position(&self.start, EnPassantMode::Legal).to_string())
+            .moves(&self.uci_moves());
+        match &self.clock {
+            Some(clock) => job.clock(clock.go()),
+            None => job.movetime(self.config.movetime.unwrap_or(DEFAULT_MOVETIME)),
+        }
+    }
+
+    pub fn state(&self) -> PlayState {
+        let (result, termination) = match &self.result {
+            Some((result, termination)) => (Some(*result), Some(termination.clone())),
+            None => (None, None),
+        };
+        PlayState {
+            fen: Fen::from_position(&self.pos, EnPassantMode::Legal).to_string(),
+            moves: self.history.iter().map(|p| p.san.clone()).collect(),
+            last_move: self.history.last().map(|p| p.uci.clone()),
+            white_to_move: self.pos.turn().is_white(),
+            player_is_white: self.config.player_is_white,
+            clock: self.clock.as_ref().map(|c| {
+                [Color::White, Color::Black].map(|color| c.remaining_now(color).as_millis() as u64)
+            }),
+            engine_thinking: self.is_engine_turn(),
+            engine_score: self.engine_score,
+            result,
+            termination,
+        }
+    }
+
+    fn names(&self) -> (String, String) {
+        let player = self.config.player_name.clone().unwrap_or("Player".into());
+        let engine = match self.config.strength.describe() {
+            Some(strength) => format!("{} ({strength})", self.config.engine.name),
+            None => self.config.engine.name.clone(),
+        };
+        match self.config.player_is_white {
+            true => (player, engine),
+            false => (engine, player),
+        }
+    }
+
+    /// The finished game as PGN, with `[%clk]` comments when played with a clock.
+    pub fn pgn(&self) -> String {
+        let (white, black) = self.names();
+        let result = self.result.as_ref().map_or("*", |(r, _)| r.as_str());
+        let mut tags = vec![
+            ("Event".to_string(), "Casual game".to_string()),
+            ("Site".into(), "escacs".into()),
+            ("Date".into(), today()),
+            ("Round".into(), "-".into()),
+            ("White".into(), white),
+            ("Black".into(), black),
+            ("Result".into(), result.into()),
+        ];
+        if let Some(fen) = &self.config.fen {
+            tags.push(("SetUp".into(), "1".into()));
+            tags.push(("FEN".into(), fen.clone()));
+        }
+        if let Some(tc) = &self.config.time_control {
+            tags.push(("TimeControl".into(), tc.tag()));
+        }
+        if let Some((_, termination)) = &self.result {
+            tags.push(("Termination".into(), termination.clone()));
+        }
+
+        let mut out = String::new();
+        write_tags(&mut out, &tags);
+        out.push('\n');
+
+        let mut w = MovetextWriter::default();
+        for ply in &self.history {
+            let number = ply.before.fullmoves().get();
+            match ply.before.turn() {
+                Color::White => w.token(&format!("{number}.")),
+                Color::Black => w.token(&format!("{number}...")),
+            }
+            w.token(&ply.san);
+            if let Some(remaining) = ply.remaining {
+                w.comment(&format!("[%clk {}]", format_duration(remaining)));
+            }
+        }
+        w.token(result);
+        out.push_str(&w.finish());
+        out
+    }
+}
+
+type Reply<T> = oneshot::Sender<Result<T, String>>;
+
+pub enum PlayOp {
+    Move(String, Reply<PlayState>),
+    Takeback(Reply<PlayState>),
+    Resign(Reply<PlayState>),
+    OfferDraw(Reply<bool>),
+    State(Reply<PlayState>),
+}
+
+/// Applies a request from the player. Returns `true` if a running engine search must be abandoned.
+fn apply(game: &mut PlayGame, op: PlayOp) -> bool {
+    let thinking = game.is_engine_turn();
+    let reply = |game: &PlayGame, result: Result<()>, reply: Reply<PlayState>| {
+        _ = reply.send(result.map(|_| game.state()).map_err(|e| e.to_string()));
+    };
+    match op {
+        PlayOp::Move(uci, tx) => {
+            let result = match thinking {
+                true => Err(anyhow::anyhow!("it is not your turn")),
+                false => game.play(&uci),
+            };
+            reply(game, result, tx);
+            false
+        }
+        PlayOp::Takeback(tx) => {
+            let result = game.takeback();
+            let abandon = thinking && result.is_ok();
+            reply(game, result, tx);
+            abandon
+        }
+        PlayOp::Resign(tx) => {
+            let result = game.resign();
+            let abandon = thinking && result.is_ok();
+            reply(game, result, tx);
+            abandon
+        }
+        PlayOp::OfferDraw(tx) => {
+            let result = game.offer_draw();
+            let abandon = thinking && matches!(result, Ok(true));
+            _ = tx.send(result.map_err(|e| e.to_string()));
+            abandon
+        }
+        PlayOp::State(tx) => {
+            _ = tx.send(Ok(game.state()));
+            false
+        }
+    }
+}
+
+async fn save(db: &Database, book: &OpeningBook, game: &PlayGame) -> Result<()> {
+    if !game.is_over() {
+        bail!("the game is not over");
+    }
+    let id = db.insert_pgn(&game.pgn(), book).await?;
+    debug!(id, "saved game");
+    Ok(())
+}
+
+/// Runs the engine's turns and the player's requests until the game is dropped.
+async fn controller(
+    mut engine: Engine,
+    mut game: PlayGame,
+    mut rx: mpsc::Receiver<PlayOp>,
+    chan: Channel<PlayState>,
+    db: Database,
+    book: Arc<OpeningBook>,
+) -> Result<()> {
+    let mut saved = false;
+    // Number of moves played when the engine started pondering.
+    let mut ponder_ply = 0;
+    loop {
+        if game.is_over() {
+            engine.cancel_ponder().await?;
+            if !saved {
+                saved = true;
+                if let Err(e) = save(&db, &book, &game).await {
+                    error!(cause = %e, "failed to save game");
+                }
+                chan.send(game.state())?;
+            }
+            match rx.recv().await {
+                Some(op) => _ = apply(&mut game, op),
+                None => break,
+            }
+            continue;
+        }
+
+        if game.is_engine_turn() {
+            // A takeback since the ponder search started leaves it on a stale position.
+            if game.history.len() != ponder_ply + 1 {
+                engine.cancel_ponder().await?;
+            }
+            if let Some(m) = game.book_move() {
+                engine.cancel_ponder().await?;
+                game.play(&m)?;
+                chan.send(game.state())?;
+                continue;
+            }
+            let played = game.history.last().map_or("", |p| p.uci.as_str());
+            engine.resume(played, game.go()).await?;
+            chan.send(game.state())?;
+            loop {
+                select! {
+                    Some(line) = engine.rx.recv() => match search(&line)? {
+                        Some(Search::Info(i)) if i.multipv <= 1 && !i.pv.is_empty() => {
+                            game.engine_score = Some(i.score);
+                        }
+                        Some(Search::BestMove(best)) => {
+                            if let Err(e) = game.play(&best.best) {
+                                error!(best = best.best, cause = %e, "illegal engine move");
+                                game.finish(GameResult::win(game.player()), "illegal move");
+                            }
+                            if let Some(expected) = best.ponder.filter(|_| game.config.ponder && !game.is_over()) {
+                                ponder_ply = game.history.len();
+                                engine.ponder(game.go(), &expected).await?;
+                            }
+                            chan.send(game.state())?;
+                            break;
+                        }
+                        _ => continue,
+                    },
+                    op = rx.recv() => match op {
+                        Some(op) => if apply(&mut game, op) {
+                            engine.stop().await?;
+                            chan.send(game.state())?;
+                            break;
+                        },
+                        None => return engine.kill().await,
+                    },
+                }
+            }
+            continue;
+        }
+
+        let flag_in = game
+            .clock
+            .as_ref()
+            .filter(|c| c.running().is_some())
+            .map(|c| c.remaining_now(game.pos.turn()));
+        select! {
+            op = rx.recv() => match op {
+                Some(op) => _ = apply(&mut game, op),
+                None => break,
+            },
+            _ = tokio::time::sleep(flag_in.unwrap_or_default()), if flag_in.is_some() => {
+                game.check_flag();
+                chan.send(game.state())?;
+            }
+            // Ponder output is not shown, but must be drained so the engine does not block.
+            Some(_) = engine.rx.recv(), if engine.pondering().is_some() => {}
+        }
+    }
+    engine.kill().await
+}
+
+async fn request<T>(state: &AppState, op: impl FnOnce(Reply<T>) -> PlayOp) -> Result<T, String> {
+    let tx = state
+        .play
+        .lock()
+        .await
+        .clone()
+        .ok_or("no game in progress")?;
+    let (reply, rx) = oneshot::channel();
+    tx.send(op(reply))
+        .await
+        .map_err(|_| "the game has ended".to_string())?;
+    rx.await.map_err(|e| e.to_string())?
+}
+
+async fn start(
+    config: PlayConfig,
+    chan: Channel<PlayState>,
+    db: Database,
+    book: Arc<OpeningBook>,
+) -> Result<mpsc::Sender<PlayOp>> {
+    let game = PlayGame::new(config)?;
+    let mut engine = game.config.engine.start().await?;
+    engine.opts(&game.config.strength.options()).await?;
+    if game.config.ponder {
+        engine.opts(&[("Ponder", "true")]).await?;
+    }
+    engine.tx.send("ucinewgame".into()).await?;
+    engine.isready().await?;
+
+    let (tx, rx) = mpsc::channel(8);
+    tauri::async_runtime::spawn(
+        async move {
+            if let Err(e) = controller(engine, game, rx, chan, db, book).await {
+                error!(cause = %e, "play controller error");
+            }
+        }
+        .instrument(tracing::trace_span!("play")),
+    );
+    Ok(tx)
+}
+
+/// Starts a new game against the engine, abandoning the current one.
+#[tauri::command]
+pub async fn play_start(
+    config: PlayConfig,
+    chan: Channel<PlayState>,
+    state: State<'_, AppState>,
+) -> Result<PlayState, String> {
+    let book = state.openings.read().unwrap().clone();
+    let tx = start(config, chan, state.db.clone(), book)
+        .await
+        .map_err(|e| e.to_string())?;
+    // Dropping the previous sender ends its controller and engine.
+    *state.play.lock().await = Some(tx);
+    request(&state, PlayOp::State).await
+}
+
+#[tauri::command]
+pub async fn play_move(uci: String, state: State<'_, AppState>) -> Result<PlayState, String> {
+    request(&state, |tx| PlayOp::Move(uci, tx)).await
+}
+
+#[tauri::command]
+pub async fn play_takeback(state: State<'_, AppState>) -> Result<PlayState, String> {
+    request(&state, PlayOp::Takeback).await
+}
+
+#[tauri::command]
+pub async fn play_resign(state: State<'_, AppState>) -> Result<PlayState, String> {
+    request(&state, PlayOp::Resign).await
+}
+
+/// Returns whether the engine accepted the draw.
+#[tauri::command]
+pub async fn play_offer_draw(state: State<'_, AppState>) -> Result<bool, String> {
+    request(&state, PlayOp::OfferDraw).await
+}
+
+#[tauri::command]
+pub async fn play_state(state: State<'_, AppState>) -> Result<PlayState, String> {
+    request(&state, PlayOp::State).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(time_control: Option<TimeControl>) -> PlayGame {
+        PlayGame::new(PlayConfig {
+            engine: EngineConfig {
+                name: "Stockfish".into(),
+                path: "stockfish".into(),
+                options: vec![],
+            },
+            strength: Strength::Elo { elo: 1500 },
+            time_control,
+            movetime: None,
+            player_is_white: true,
+            fen: None,
+            player_name: None,
+            ponder: false,
+            book: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn takeback_and_repetition() {
+        let mut g = game(None);
+        for m in ["g1f3", "g8f6", "f3g1", "f6g8"] {
+            g.play(m).unwrap();
+        }
+        g.takeback().unwrap();
+        assert_eq!(g.state().moves, ["Nf3", "Nf6"]);
+        assert!(!g.is_engine_turn());
+
+        for m in ["f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"] {
+            g.play(m).unwrap();
+        }
+        let state = g.state();
+        assert_eq!(state.result, Some(GameResult::Draw));
+        assert_eq!(state.termination.as_deref(), Some("threefold repetition"));
+        assert!(g.pgn().contains("Termination \"threefold repetition\""));
+    }
+
+    #[test]
+    fn resign_and_clock_comments() {
+        let mut g = game(Some(TimeControl {
+            base: 60_000,
+            increment: 1_000,
+            mode: Default::default(),
+        }));
+        g.play("e2e4").unwrap();
+        g.resign().unwrap();
+        assert_eq!(g.state().result, Some(GameResult::BlackWins));
+        assert!(g.play("e7e5").is_err());
+        let pgn = g.pgn();
+        assert!(pgn.contains("1. e4 { [%clk 0:01:00] } 0-1"), "{pgn}");
+        assert!(pgn.contains("[Black \"Stockfish (1500)\"]"));
+    }
+}
@@ -0,0 +1,474 @@
+//! Polyglot opening books (`.bin`).
+//!
+//! A book is a sorted list of 16-byte big-endian entries: the position key, the move, its
+//! weight and a learn field that is kept but not used. Keys are shakmaty's Zobrist hashes,
+//! which use the Polyglot random numbers, with en passant counted whenever a pawn could
+//! capture.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "desktop")]
+use shakmaty::fen::Fen;
+use shakmaty::{
+    san::San,
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    CastlingMode, Chess, Color, EnPassantMode, Move, Position, Role, Square,
+};
+#[cfg(feature = "desktop")]
+use tauri::State;
+use tracing::{debug, warn};
+
+use super::pgn::{read_games, Game};
+use crate::db::Database;
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// The Polyglot key of a position.
+pub fn polyglot_key(pos: &Chess) -> u64 {
+    let key: Zobrist64 = pos.zobrist_hash(EnPassantMode::PseudoLegal);
+    key.0
+}
+
+/// Encodes a move the Polyglot way, castling as the king capturing its rook.
+pub fn encode_move(m: &Move) -> u16 {
+    let (from, to) = match *m {
+        Move::Castle { king, rook } => (king, rook),
+        _ => (m.from().unwrap_or(m.to()), m.to()),
+    };
+    let promotion = match m.promotion() {
+        Some(Role::Knight) => 1,
+        Some(Role::Bishop) => 2,
+        Some(Role::Rook) => 3,
+        Some(Role::Queen) => 4,
+        _ => 0,
+    };
+    to as u16 | (from as u16) << 6 | promotion << 12
+}
+
+/// Decodes a book move in the context of `pos`, if it is legal there.
+pub fn decode_move(pos: &Chess, encoded: u16) -> Option<Move> {
+    let square = |bits: u16| Square::new(u32::from(bits & 0x3f));
+    let promotion = match encoded >> 12 & 0x7 {
+        1 => Some(Role::Knight),
+        2 => Some(Role::Bishop),
+        3 => Some(Role::Rook),
+        4 => Some(Role::Queen),
+        _ => None,
+    };
+    let (from, to) = (square(encoded >> 6), square(encoded));
+    pos.legal_moves().into_iter().find(|m| match *m {
+        Move::Castle { king, rook } => king == from && rook == to,
+        _ => m.from() == Some(from) && m.to() == to && m.promotion() == promotion,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookEntry {
+    fn from_bytes(b: [u8; 16]) -> Self {
+        Self {
+            key: u64::from_be_bytes(b[0..8].try_into().unwrap()),
+            mv: u16::from_be_bytes([b[8], b[9]]),
+            weight: u16::from_be_bytes([b[10], b[11]]),
+            learn: u32::from_be_bytes(b[12..16].try_into().unwrap()),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut b = [0; 16];
+        b[0..8].copy_from_slice(&self.key.to_be_bytes());
+        b[8..10].copy_from_slice(&self.mv.to_be_bytes());
+        b[10..12].copy_from_slice(&self.weight.to_be_bytes());
+        b[12..16].copy_from_slice(&self.learn.to_be_bytes());
+        b
+    }
+}
+
+/// A book move for a position.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BookMove {
+    pub uci: String,
+    pub san: String,
+    pub weight: u16,
+    /// Share of the position's total weight.
+    pub probability: f64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PolyglotBook {
+    /// Sorted by key, then by descending weight.
+    entries: Vec<BookEntry>,
+}
+
+impl PolyglotBook {
+    pub fn read(mut reader: impl Read) -> Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() % 16 != 0 {
+            bail!("book size {} is not a multiple of 16 bytes", bytes.len());
+        }
+        let mut entries = bytes
+            .chunks_exact(16)
+            .map(|chunk| BookEntry::from_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        // Books should already be sorted, but lookups depend on it.
+        entries.sort_by(|a, b| a.key.cmp(&b.key).then(b.weight.cmp(&a.weight)));
+        Ok(Self { entries })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        Self::read(BufReader::new(file))
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<()> {
+        for entry in &self.entries {
+            writer.write_all(&entry.to_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        self.write(BufWriter::new(file))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entries_for(&self, key: u64) -> &[BookEntry] {
+        let start = self.entries.partition_point(|e| e.key < key);
+        let end = self.entries.partition_point(|e| e.key <= key);
+        &self.entries[start..end]
+    }
+
+    /// The legal book moves of a position, heaviest first.
+    pub fn moves(&self, pos: &Chess) -> Vec<BookMove> {
+        let entries = self.entries_for(polyglot_key(pos));
+        let total = entries
+            .iter()
+            .map(|e| u32::from(e.weight))
+            .sum::<u32>()
+            .max(1);
+        entries
+            .iter()
+            .filter_map(|e| {
+                let m = decode_move(pos, e.mv)?;
+                Some(BookMove {
+                    uci: m.to_uci(CastlingMode::Standard).to_string(),
+                    san: San::from_move(pos, m).to_string(),
+                    weight: e.weight,
+                    probability: f64::from(e.weight) / f64::from(total),
+                })
+            })
+            .collect()
+    }
+
+    /// Picks a book move at random, in proportion to the weights. `roll` is any random number.
+    pub fn pick(&self, pos: &Chess, roll: u64) -> Option<Move> {
+        let moves = self
+            .entries_for(polyglot_key(pos))
+            .iter()
+            .filter(|e| e.weight > 0)
+            .filter_map(|e| Some((decode_move(pos, e.mv)?, u64::from(e.weight))))
+            .collect::<Vec<_>>();
+        let total = moves.iter().map(|(_, w)| w).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = roll % total;
+        for (m, weight) in moves {
+            if roll < weight {
+                return Some(m);
+            }
+            roll -= weight;
+        }
+        None
+    }
+}
+
+/// Points scored with a move, counted in half points.
+#[derive(Debug, Default, Clone, Copy)]
+struct MoveStats {
+    games: u32,
+    half_points: u64,
+}
+
+/// Builds a book from games, weighting each move by the points its side scored with it.
+#[derive(Debug)]
+pub struct BookBuilder {
+    max_ply: usize,
+    min_games: u32,
+    stats: HashMap<(u64, u16), MoveStats>,
+    /// Games that could not be read or replayed.
+    skipped: usize,
+}
+
+impl BookBuilder {
+    pub fn new(max_ply: usize, min_games: u32) -> Self {
+        Self {
+            max_ply,
+            min_games: min_games.max(1),
+            stats: HashMap::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Adds the first `max_ply` moves of a game. Games without a result are skipped. A game
+    /// with an illegal move adds nothing.
+    pub fn add_game(&mut self, game: &Game) -> Result<()> {
+        let white_half_points = match game.tag("Result") {
+            Some("1-0") => 2,
+            Some("1/2-1/2") => 1,
+            Some("0-1") => 0,
+            _ => return Ok(()),
+        };
+        let mut pos = game.start_position()?;
+        let mut played = Vec::with_capacity(self.max_ply.min(game.moves.len()));
+        for m in game.moves.iter().take(self.max_ply) {
+            let m = m.uci.parse::<UciMove>()?.to_move(&pos)?;
+            played.push((polyglot_key(&pos), encode_move(&m), pos.turn()));
+            pos.play_unchecked(m);
+        }
+        for (key, mv, turn) in played {
+            let stats = self.stats.entry((key, mv)).or_default();
+            stats.games += 1;
+            stats.half_points += match turn {
+                Color::White => white_half_points,
+                Color::Black => 2 - white_half_points,
+            };
+        }
+        Ok(())
+    }
+
+    /// Adds a game as read from PGN, counting it as skipped if it is unreadable or illegal.
+    pub fn add_read(&mut self, game: Result<Game>) {
+        if let Err(e) = game.and_then(|game| self.add_game(&game)) {
+            debug!(cause = %e, "game left out of the book");
+            self.skipped += 1;
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn build(self) -> PolyglotBook {
+        let mut by_key = HashMap::<u64, Vec<(u16, u64)>>::new();
+        for ((key, mv), stats) in self.stats {
+            if stats.games >= self.min_games {
+                by_key.entry(key).or_default().push((mv, stats.half_points));
+            }
+        }
+
+        let mut entries = Vec::new();
+        for (key, moves) in by_key {
+            // Weights are 16-bit, so scale down positions with many games.
+            let max = moves.iter().map(|(_, w)| *w).max().unwrap_or_default();
+            let scale = |w: u64| match max > u64::from(u16::MAX) {
+                true => (w * u64::from(u16::MAX) / max) as u16,
+                false => w as u16,
+            };
+            entries.extend(
+                moves
+                    .into_iter()
+                    .map(|(mv, w)| BookEntry {
+                        key,
+                        mv,
+                        weight: scale(w),
+                        learn: 0,
+                    })
+                    // A move that never scored is not worth suggesting.
+                    .filter(|e| e.weight > 0),
+            );
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key).then(b.weight.cmp(&a.weight)));
+        PolyglotBook { entries }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BookSource {
+    Pgn { path: PathBuf },
+    Database,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BuildBook {
+    pub source: BookSource,
+    pub output: PathBuf,
+    /// Only the first this many plies of every game are included.
+    pub max_ply: usize,
+    /// Moves played in fewer games are left out.
+    pub min_games: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookReport {
+    pub entries: usize,
+    /// Games left out because they could not be read or had an illegal move.
+    pub skipped: usize,
+}
+
+/// Builds a book and writes it to `request.output`. Parsing and replaying run on blocking
+/// threads.
+pub async fn build_book(request: BuildBook, db: &Database) -> Result<(PolyglotBook, usize)> {
+    let mut builder = BookBuilder::new(request.max_ply, request.min_games);
+    match &request.source {
+        BookSource::Pgn { path } => {
+            let file =
+                File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+            builder = tokio::task::spawn_blocking(move || {
+                for game in read_games(BufReader::new(file)) {
+                    builder.add_read(game);
+                }
+                builder
+            })
+            .await?;
+        }
+        BookSource::Database => {
+            let mut after = 0;
+            loop {
+                let batch = db.pgn_batch(after, 500).await?;
+                let Some(&(last, _)) = batch.last() else {
+                    break;
+                };
+                after = last;
+                builder = tokio::task::spawn_blocking(move || {
+                    for (_, pgn) in batch {
+                        for game in read_games(pgn.as_bytes()) {
+                            builder.add_read(game);
+                        }
+                    }
+                    builder
+                })
+                .await?;
+            }
+        }
+    }
+    let skipped = builder.skipped();
+    if skipped > 0 {
+        warn!(skipped, "games left out of the book");
+    }
+    let output = request.output.clone();
+    let book = tokio::task::spawn_blocking(move || -> Result<PolyglotBook> {
+        let book = builder.build();
+        book.save(&output)?;
+        Ok(book)
+    })
+    .await??;
+    Ok((book, skipped))
+}
+
+/// Writes a Polyglot book built from a PGN file or the games database. Games that cannot be
+/// read are left out and counted.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn build_polyglot_book(
+    request: BuildBook,
+    state: State<'_, AppState>,
+) -> Result<BookReport, String> {
+    let (book, skipped) = build_book(request, &state.db)
+        .await
+        .map_err(|e| format!("{e:#}"))?;
+    Ok(BookReport {
+        entries: book.len(),
+        skipped,
+    })
+}
+
+/// The moves of a Polyglot book for a position.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn polyglot_moves(path: PathBuf, fen: &str) -> Result<Vec<BookMove>, String> {
+    let book = PolyglotBook::open(path).map_err(|e| format!("{e:#}"))?;
+    let pos: Chess = fen
+        .parse::<Fen>()
+        .map_err(|e| e.to_string())?
+        .into_position(CastlingMode::Standard)
+        .map_err(|e| e.to_string())?;
+    Ok(book.moves(&pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(moves: &[&str]) -> Chess {
+        let mut pos = Chess::default();
+        for m in moves {
+            let m = m.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+            pos.play_unchecked(m);
+        }
+        pos
+    }
+
+    #[test]
+    fn keys() {
+        // From the Polyglot format description.
+        assert_eq!(polyglot_key(&play(&[])), 0x463b96181691fc9c);
+        assert_eq!(polyglot_key(&play(&["e2e4"])), 0x823c9b50fd114196);
+        assert_eq!(polyglot_key(&play(&["e2e4", "d7d5"])), 0x0756b94461c50fb0);
+        assert_eq!(
+            polyglot_key(&play(&["e2e4", "d7d5", "e4e5", "f7f5"])),
+            0x22a48b5a8e47ff78
+        );
+    }
+
+    #[test]
+    fn build_and_round_trip() {
+        let pgn = "[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bc4 Nf6 4. O-O 1-0\n\n\
+                   [Result \"1/2-1/2\"]\n\n1. e4 c5 1/2-1/2\n\n\
+                   [Result \"0-1\"]\n\n1. d4 d5 0-1\n\n\
+                   [Result \"1-0\"]\n\n1. e4 e5 2. Ke3 1-0\n";
+        let mut builder = BookBuilder::new(7, 1);
+        for game in read_games(pgn.as_bytes()) {
+            builder.add_read(game);
+        }
+        // The illegal game is counted and adds nothing, not even 1. e4.
+        assert_eq!(builder.skipped(), 1);
+        let book = builder.build();
+
+        let moves = book.moves(&Chess::default());
+        // 1. d4 lost, so it has no weight and is left out.
+        assert_eq!(moves.len(), 1);
+        assert_eq!((moves[0].san.as_str(), moves[0].weight), ("e4", 3));
+
+        let mut bytes = Vec::new();
+        book.write(&mut bytes).unwrap();
+        let read = PolyglotBook::read(bytes.as_slice()).unwrap();
+        assert_eq!(read.entries, book.entries);
+
+        let castle = play(&["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6"]);
+        let castling = read.moves(&castle);
+        assert_eq!(castling[0].uci, "e1g1");
+        assert_eq!(read.pick(&castle, 12345).map(|m| m.is_castle()), Some(true));
+
+        assert!(PolyglotBook::read(&[0u8; 15][..]).is_err());
+    }
+}
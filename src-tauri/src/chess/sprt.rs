@@ -0,0 +1,190 @@
+//! Match statistics: Elo difference, likelihood of superiority and SPRT.
+
+/// Wins, draws and losses from the point of view of the first engine.
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct Wdl {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Wdl {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// Average points per game.
+    pub fn ratio(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.0) / self.games() as f64
+    }
+
+    /// Per-game variance of the result around the mean.
+    fn variance(&self) -> f64 {
+        let n = self.games() as f64;
+        let s = self.ratio();
+        (self.wins as f64 * (1.0 - s).powi(2)
+            + self.draws as f64 * (0.5 - s).powi(2)
+            + self.losses as f64 * s.powi(2))
+            / n
+    }
+
+    /// Elo difference and its 95% confidence margin.
+    pub fn elo(&self) -> Option<(f64, f64)> {
+        if self.games() == 0 {
+            return None;
+        }
+        let s = self.ratio();
+        let margin = 1.959964 * (self.variance() / self.games() as f64).sqrt();
+        let elo = score_to_elo(s);
+        let high = score_to_elo(s + margin);
+        let low = score_to_elo(s - margin);
+        Some((elo, (high - low) / 2.0))
+    }
+
+    /// Likelihood of superiority, the probability that the first engine is stronger.
+    pub fn los(&self) -> Option<f64> {
+        let decisive = (self.wins + self.losses) as f64;
+        if decisive == 0.0 {
+            return None;
+        }
+        let x = (self.wins as f64 - self.losses as f64) / (2.0 * decisive).sqrt();
+        Some(0.5 * (1.0 + erf(x)))
+    }
+}
+
+pub fn score_to_elo(score: f64) -> f64 {
+    let score = score.clamp(1e-6, 1.0 - 1e-6);
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+pub fn elo_to_score(elo: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-elo / 400.0))
+}
+
+/// Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let y = 1.0
+        - (((((1.061405429 * t - 1.453152027) * t) + 1.421413741) * t - 0.284496736) * t
+            + 0.254829592)
+            * t
+            * (-x * x).exp();
+    y.copysign(x)
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct SprtConfig {
+    pub elo0: f64,
+    pub elo1: f64,
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl Default for SprtConfig {
+    fn default() -> Self {
+        Self {
+            elo0: 0.0,
+            elo1: 5.0,
+            alpha: 0.05,
+            beta: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SprtDecision {
+    /// The Elo difference is at most `elo0`.
+    H0,
+    /// The Elo difference is at least `elo1`.
+    H1,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Sprt {
+    pub llr: f64,
+    pub lower: f64,
+    pub upper: f64,
+    pub decision: Option<SprtDecision>,
+}
+
+impl SprtConfig {
+    /// Log-likelihood ratio of H1 against H0 with the trinomial GSPRT approximation.
+    pub fn test(&self, wdl: &Wdl) -> Sprt {
+        let lower = (self.beta / (1.0 - self.alpha)).ln();
+        let upper = ((1.0 - self.beta) / self.alpha).ln();
+
+        let llr = if wdl.games() == 0 {
+            0.0
+        } else {
+            // Half a game in every bucket keeps the variance positive while a side has not won
+            // yet, so that e.g. 40-0-10 can still be decided.
+            let [w, d, l] = [wdl.wins, wdl.draws, wdl.losses].map(|n| n as f64 + 0.5);
+            let n = w + d + l;
+            let s = (w + d / 2.0) / n;
+            let variance = (w * (1.0 - s).powi(2) + d * (0.5 - s).powi(2) + l * s.powi(2)) / n;
+            let s0 = elo_to_score(self.elo0);
+            let s1 = elo_to_score(self.elo1);
+            n * (s1 - s0) * (2.0 * s - s0 - s1) / (2.0 * variance)
+        };
+
+        let decision = if llr >= upper {
+            Some(SprtDecision::H1)
+        } else if llr <= lower {
+            Some(SprtDecision::H0)
+        } else {
+            None
+        };
+
+        Sprt {
+            llr,
+            lower,
+            upper,
+            decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elo_and_los() {
+        let even = Wdl {
+            wins: 10,
+            draws: 10,
+            losses: 10,
+        };
+        assert!(even.elo().unwrap().0.abs() < 1e-9);
+        assert!((even.los().unwrap() - 0.5).abs() < 1e-9);
+
+        let ahead = Wdl {
+            wins: 60,
+            draws: 20,
+            losses: 20,
+        };
+        let (elo, margin) = ahead.elo().unwrap();
+        assert!((elo - 147.2).abs() < 0.5);
+        assert!(margin > 0.0);
+        assert!(ahead.los().unwrap() > 0.99);
+    }
+
+    #[test]
+    fn sprt_accepts_h1() {
+        let sprt = SprtConfig::default().test(&Wdl {
+            wins: 600,
+            draws: 800,
+            losses: 400,
+        });
+        assert_eq!(sprt.decision, Some(SprtDecision::H1));
+        let unbeaten = SprtConfig::default().test(&Wdl {
+            wins: 40,
+            draws: 10,
+            losses: 0,
+        });
+        assert_eq!(unbeaten.decision, Some(SprtDecision::H1));
+        assert_eq!(SprtConfig::default().test(&Wdl::default()).llr, 0.0);
+        assert!((erf(0.5) - 0.5204999).abs() < 1e-6);
+    }
+}
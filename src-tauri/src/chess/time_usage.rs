@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+#[cfg(feature = "desktop")]
+use anyhow::Context;
+use anyhow::Result;
+use shakmaty::{uci::UciMove, Chess, Color, Position};
+
+#[cfg(feature = "desktop")]
+use super::{analysis::analyse_game, pgn::read_games, Engine, Go};
+use super::{
+    analysis::{win_pct, Judgement, MoveEval},
+    pgn::Game,
+};
+
+/// Thresholds used to flag questionable time usage.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct TimeUsageConfig {
+    /// A move is a long think when it takes this many times the player's average.
+    pub long_think_factor: f64,
+    /// ... and at least this many seconds.
+    pub long_think_min_secs: f64,
+    /// Blunders played faster than this are reported.
+    pub fast_secs: f64,
+    /// Positions evaluated beyond this many centipawns are considered decided.
+    pub decisive_cp: i32,
+}
+
+impl Default for TimeUsageConfig {
+    fn default() -> Self {
+        Self {
+            long_think_factor: 2.0,
+            long_think_min_secs: 20.0,
+            fast_secs: 10.0,
+            decisive_cp: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TimedMove {
+    pub ply: usize,
+    pub san: String,
+    pub is_white: bool,
+    /// Time spent on the move in milliseconds.
+    pub spent: Option<u64>,
+    /// Remaining clock after the move in milliseconds.
+    pub clock: Option<u64>,
+    /// Evaluation after the move in centipawns, from White's point of view.
+    pub eval: i32,
+    /// White's win percentage after the move.
+    pub win_pct: f64,
+    pub judgement: Judgement,
+    /// Whether the move was obvious: the only legal move, a recapture, or a decided position.
+    pub easy: bool,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct TimeUsageReport {
+    /// One point per move, for plotting time spent against the evaluation.
+    pub series: Vec<TimedMove>,
+    /// Long thinks on easy positions.
+    pub wasted: Vec<TimedMove>,
+    /// Blunders played under the fast threshold.
+    pub hasty_blunders: Vec<TimedMove>,
+}
+
+/// Parses the base time and increment of a `TimeControl` tag such as `180+2` or `40/7200:3600`.
+pub fn parse_time_control(tc: &str) -> Option<(Duration, Duration)> {
+    let first = tc.split(':').next()?;
+    let first = first.rsplit('/').next()?;
+    let (base, inc) = first.split_once('+').unwrap_or((first, "0"));
+    Some((
+        Duration::from_secs(base.parse().ok()?),
+        Duration::from_secs(inc.parse().ok()?),
+    ))
+}
+
+/// Time spent on every move, from `[%emt]` or from consecutive `[%clk]` readings.
+pub fn time_spent(game: &Game) -> Vec<Option<Duration>> {
+    let tc = game.tag("TimeControl").and_then(parse_time_control);
+    let (mut white, mut black) = match tc {
+        Some((base, _)) => (Some(base), Some(base)),
+        None => (None, None),
+    };
+    let inc = tc.map(|(_, inc)| inc).unwrap_or_default();
+
+    game.moves
+        .iter()
+        .map(|m| {
+            let prev = match m.color {
+                Color::White => &mut white,
+                Color::Black => &mut black,
+            };
+            let spent = match (m.emt, *prev, m.clock) {
+                (Some(emt), _, _) => Some(emt),
+                (None, Some(prev), Some(clock)) => Some((prev + inc).saturating_sub(clock)),
+                _ => None,
+            };
+            if m.clock.is_some() {
+                *prev = m.clock;
+            }
+            spent
+        })
+        .collect()
+}
+
+fn is_easy(
+    pos: &Chess,
+    m: &shakmaty::Move,
+    last: Option<&shakmaty::Move>,
+    cp: i32,
+    config: &TimeUsageConfig,
+) -> bool {
+    let only_move = pos.legal_moves().len() == 1;
+    let recapture = m.is_capture() && last.is_some_and(|l| l.is_capture() && l.to() == m.to());
+    only_move || recapture || cp.abs() >= config.decisive_cp
+}
+
+/// Correlates time spent with the engine grading of each move.
+pub fn time_usage(
+    game: &Game,
+    evals: &[MoveEval],
+    config: &TimeUsageConfig,
+) -> Result<TimeUsageReport> {
+    let spent = time_spent(game);
+    let mut pos = game.start_position()?;
+    let mut last = None;
+    let mut report = TimeUsageReport::default();
+
+    for ((m, eval), spent) in game.moves.iter().zip(evals).zip(&spent) {
+        let mv = m.uci.parse::<UciMove>()?.to_move(&pos)?;
+        let easy = is_easy(&pos, &mv, last.as_ref(), eval.before.as_cp(), config);
+        pos.play_unchecked(mv);
+        last = Some(mv);
+
+        report.series.push(TimedMove {
+            ply: m.ply,
+            san: m.san.clone(),
+            is_white: m.color.is_white(),
+            spent: spent.map(|d| d.as_millis() as u64),
+            clock: m.clock.map(|d| d.as_millis() as u64),
+            eval: eval.after.as_cp(),
+            win_pct: win_pct(eval.after.as_cp()),
+            judgement: eval.judgement,
+            easy,
+        });
+    }
+
+    for color in [Color::White, Color::Black] {
+        let own = report
+            .series
+            .iter()
+            .filter(|t| t.is_white == color.is_white())
+            .filter_map(|t| t.spent)
+            .collect::<Vec<_>>();
+        if own.is_empty() {
+            continue;
+        }
+        let avg = own.iter().sum::<u64>() as f64 / own.len() as f64;
+        let long = (avg * config.long_think_factor).max(config.long_think_min_secs * 1000.0);
+
+        for t in report
+            .series
+            .iter()
+            .filter(|t| t.is_white == color.is_white())
+        {
+            let Some(spent) = t.spent.map(|s| s as f64) else {
+                continue;
+            };
+            if t.easy && spent >= long {
+                report.wasted.push(t.clone());
+            }
+            if t.judgement == Judgement::Blunder && spent < config.fast_secs * 1000.0 {
+                report.hasty_blunders.push(t.clone());
+            }
+        }
+    }
+    report.wasted.sort_by_key(|t| t.ply);
+    report.hasty_blunders.sort_by_key(|t| t.ply);
+
+    Ok(report)
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn analyse_time_usage(
+    pgn: String,
+    depth: Option<u32>,
+    config: Option<TimeUsageConfig>,
+) -> Result<TimeUsageReport, String> {
+    let run = async {
+        let game = read_games(pgn.as_bytes())
+            .next()
+            .context("no game in pgn")??;
+
+        let mut engine = Engine::new("stockfish")?;
+        engine.uci().await?;
+        engine.isready().await?;
+
+        let evals = analyse_game(&mut engine, &game, &Go::new().depth(depth.unwrap_or(16))).await;
+        engine.kill().await?;
+
+        time_usage(&game, &evals?, &config.unwrap_or_default())
+    };
+    run.await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::pgn::read_games;
+
+    #[test]
+    fn spent_from_clocks() {
+        let pgn = r#"[TimeControl "60+1"]
+
+1. e4 { [%clk 0:01:00] } e5 { [%clk 0:00:55] } 2. Nf3 { [%clk 0:00:50] } Nc6 { [%emt 0:00:03] } *"#;
+        let game = read_games(pgn.as_bytes()).next().unwrap().unwrap();
+        let spent = time_spent(&game);
+        assert_eq!(
+            spent,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(6)),
+                Some(Duration::from_secs(11)),
+                Some(Duration::from_secs(3)),
+            ]
+        );
+        assert_eq!(
+            parse_time_control("40/7200:3600"),
+            Some((Duration::from_secs(7200), Duration::ZERO))
+        );
+    }
+}
@@ -0,0 +1,590 @@
+//! Move trees of studies and games, with variations and annotations.
+//!
+//! The frontend keeps a tree as lines of nodes, `MoveNode[][]`: line 0 is the mainline, and a
+//! node's `variations` lists the lines that replace it. [`GameTree`] serializes to and from
+//! that format, validating every move on the way in.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Move,
+    Position, Role, Square,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Brush {
+    Green,
+    Red,
+    Blue,
+    Yellow,
+}
+
+impl Brush {
+    /// The colour letter of `[%csl]` and `[%cal]` entries.
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'G' => Some(Self::Green),
+            'R' => Some(Self::Red),
+            'B' => Some(Self::Blue),
+            'Y' => Some(Self::Yellow),
+            _ => None,
+        }
+    }
+
+    pub fn char(self) -> char {
+        match self {
+            Self::Green => 'G',
+            Self::Red => 'R',
+            Self::Blue => 'B',
+            Self::Yellow => 'Y',
+        }
+    }
+}
+
+/// A highlighted square, or an arrow when it has a destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "RawShape", into = "RawShape")]
+pub struct Shape {
+    pub orig: Square,
+    pub dest: Option<Square>,
+    pub brush: Brush,
+}
+
+impl Shape {
+    /// Parses a `[%csl]` highlight like `Ge4` or a `[%cal]` arrow like `Re2e4`.
+    pub fn from_pgn(s: &str) -> Option<Self> {
+        let brush = Brush::from_char(s.chars().next()?)?;
+        let orig = s.get(1..3)?.parse().ok()?;
+        let dest = match s.len() {
+            3 => None,
+            5 => Some(s.get(3..5)?.parse().ok()?),
+            _ => return None,
+        };
+        Some(Self { orig, dest, brush })
+    }
+
+    pub fn to_pgn(&self) -> String {
+        let dest = self.dest.map(|dest| dest.to_string()).unwrap_or_default();
+        format!("{}{}{dest}", self.brush.char(), self.orig)
+    }
+}
+
+/// A chessground shape, with squares as names.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RawShape {
+    orig: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dest: Option<String>,
+    brush: Brush,
+}
+
+impl TryFrom<RawShape> for Shape {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawShape) -> Result<Self> {
+        Ok(Self {
+            orig: raw.orig.parse()?,
+            dest: raw.dest.map(|dest| dest.parse()).transpose()?,
+            brush: raw.brush,
+        })
+    }
+}
+
+impl From<Shape> for RawShape {
+    fn from(shape: Shape) -> Self {
+        Self {
+            orig: shape.orig.to_string(),
+            dest: shape.dest.map(|dest| dest.to_string()),
+            brush: shape.brush,
+        }
+    }
+}
+
+/// A move with its annotations and the moves that can follow it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub mv: Move,
+    /// The move in SAN, with its check or mate suffix.
+    pub san: String,
+    /// Comment before the move, e.g. at the start of a variation. Its commands are kept in the
+    /// text, as they describe the position before the move.
+    pub comment_before: Option<String>,
+    /// Comment after the move.
+    pub comment: Option<String>,
+    /// Numeric annotation glyphs, e.g. 1 for `!`.
+    pub nags: Vec<u8>,
+    pub shapes: Vec<Shape>,
+    /// Remaining time on the mover's clock.
+    pub clock: Option<Duration>,
+    /// The mainline continuation first, then its alternatives.
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Plays `mv` in `pos`, which is left at the position after the move.
+    pub fn new(pos: &mut Chess, mv: Move) -> Self {
+        let san = SanPlus::from_move_and_play_unchecked(pos, mv).to_string();
+        Self {
+            mv,
+            san,
+            comment_before: None,
+            comment: None,
+            nags: Vec::new(),
+            shapes: Vec::new(),
+            clock: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn uci(&self) -> String {
+        self.mv.to_uci(CastlingMode::Standard).to_string()
+    }
+
+    /// Adds the annotations of `other`, the same move, keeping those of `self` first.
+    fn merge(&mut self, other: &Node) {
+        merge_comment(&mut self.comment_before, &other.comment_before);
+        merge_comment(&mut self.comment, &other.comment);
+        for nag in &other.nags {
+            if !self.nags.contains(nag) {
+                self.nags.push(*nag);
+            }
+        }
+        for shape in &other.shapes {
+            if !self.shapes.contains(shape) {
+                self.shapes.push(*shape);
+            }
+        }
+        self.clock = self.clock.or(other.clock);
+        merge_children(&mut self.children, &other.children);
+    }
+}
+
+/// Appends `extra` to `comment` unless it says it already.
+fn merge_comment(comment: &mut Option<String>, extra: &Option<String>) {
+    match (comment, extra) {
+        (Some(comment), Some(extra)) if !comment.contains(extra.as_str()) => {
+            comment.push(' ');
+            comment.push_str(extra);
+        }
+        (comment @ None, Some(extra)) => *comment = Some(extra.clone()),
+        _ => {}
+    }
+}
+
+/// Merges `other` into `children` move by move, adding its other moves as variations.
+fn merge_children(children: &mut Vec<Node>, other: &[Node]) {
+    for node in other {
+        match children.iter_mut().find(|child| child.mv == node.mv) {
+            Some(child) => child.merge(node),
+            None => children.push(node.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "Vec<Vec<MoveNode>>", into = "Vec<Vec<MoveNode>>")]
+pub struct GameTree {
+    pub start: Chess,
+    /// The first move of the mainline, then its alternatives.
+    pub children: Vec<Node>,
+}
+
+impl Default for GameTree {
+    fn default() -> Self {
+        Self::new(Chess::default())
+    }
+}
+
+impl GameTree {
+    pub fn new(start: Chess) -> Self {
+        Self {
+            start,
+            children: Vec::new(),
+        }
+    }
+
+    /// The mainline nodes, following the first child of every node.
+    pub fn mainline(&self) -> impl Iterator<Item = &Node> {
+        std::iter::successors(self.children.first(), |node| node.children.first())
+    }
+
+    /// Number of moves in the tree, variations included.
+    pub fn len(&self) -> usize {
+        fn count(children: &[Node]) -> usize {
+            children.iter().map(|n| 1 + count(&n.children)).sum()
+        }
+        count(&self.children)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Adds the moves and annotations of `other`, a tree from the same start, keeping the
+    /// mainline and annotations of `self` first.
+    pub fn merge(&mut self, other: &GameTree) {
+        if self.start == other.start {
+            merge_children(&mut self.children, &other.children);
+        }
+    }
+
+    /// Reads the frontend format, checking that every move is legal.
+    pub fn from_nodes(nodes: &[Vec<MoveNode>]) -> Result<Self> {
+        let start = match nodes.first().and_then(|line| line.first()) {
+            Some(first) => first
+                .mv
+                .before
+                .parse::<Fen>()?
+                .into_position(CastlingMode::Standard)?,
+            None => Chess::default(),
+        };
+        let children = children(nodes, 0, 0, &start)?;
+        Ok(Self { start, children })
+    }
+
+    /// Writes the frontend format. Variations are numbered depth first.
+    pub fn to_nodes(&self) -> Vec<Vec<MoveNode>> {
+        let mut nodes = vec![Vec::new()];
+        let root = Cursor { var: 0, num: -1 };
+        write_line(&self.children, 0, root, self.start.clone(), &mut nodes);
+        nodes
+    }
+}
+
+/// The node at `nodes[var][num]` followed by its variations, all played from `pos`.
+fn children(nodes: &[Vec<MoveNode>], var: usize, num: usize, pos: &Chess) -> Result<Vec<Node>> {
+    let Some(first) = nodes.get(var).and_then(|line| line.get(num)) else {
+        return Ok(Vec::new());
+    };
+    let mut children = vec![node(nodes, var, num, pos)?];
+    for &v in first.variations.iter().flatten() {
+        // New lines are always appended, so a variation can't point back to an earlier one.
+        ensure!(
+            v > var && nodes.get(v).is_some_and(|line| !line.is_empty()),
+            "line {var} has a bad variation {v}"
+        );
+        children.push(node(nodes, v, 0, pos)?);
+    }
+    Ok(children)
+}
+
+fn node(nodes: &[Vec<MoveNode>], var: usize, num: usize, pos: &Chess) -> Result<Node> {
+    let front = &nodes[var][num];
+    let mv = front
+        .mv
+        .lan
+        .parse::<UciMove>()
+        .map_err(anyhow::Error::from)
+        .and_then(|uci| Ok(uci.to_move(pos)?))
+        .with_context(|| format!("illegal move {} at line {var}, move {num}", front.mv.san))?;
+    let mut after = pos.clone();
+    let mut node = Node::new(&mut after, mv);
+    node.comment_before = front.comment_before.clone();
+    node.comment = front.comment.clone();
+    // The frontend only edits `nag`, so it decides the move assessment and `nags` keeps the
+    // other NAGs.
+    node.nags = front.nags.clone().unwrap_or_default();
+    node.nags.retain(|&nag| !GLYPHS.iter().any(|g| g.0 == nag));
+    if let Some(nag) = front.nag.as_ref().and_then(|nag| glyph_nag(&nag.text)) {
+        node.nags.insert(0, nag);
+    }
+    node.shapes = front.shapes.clone().unwrap_or_default();
+    node.clock = front.clock.map(Duration::from_millis);
+    node.children = children(nodes, var, num + 1, &after)?;
+    Ok(node)
+}
+
+/// Writes `children[0]` and its mainline into line `var`, and every alternative into a new line.
+fn write_line(
+    mut children: &[Node],
+    var: usize,
+    mut prev: Cursor,
+    mut pos: Chess,
+    nodes: &mut Vec<Vec<MoveNode>>,
+) {
+    while let Some(main) = children.first() {
+        let id = Cursor {
+            var,
+            num: nodes[var].len() as i64,
+        };
+        let mut front = MoveNode::new(&pos, main, id, prev);
+        for alt in &children[1..] {
+            let v = nodes.len();
+            nodes.push(Vec::new());
+            front.variations.get_or_insert_with(Vec::new).push(v);
+            write_line(std::slice::from_ref(alt), v, prev, pos.clone(), nodes);
+        }
+        nodes[var].push(front);
+        pos.play_unchecked(main.mv);
+        prev = id;
+        children = &main.children;
+    }
+}
+
+impl TryFrom<Vec<Vec<MoveNode>>> for GameTree {
+    type Error = anyhow::Error;
+
+    fn try_from(nodes: Vec<Vec<MoveNode>>) -> Result<Self> {
+        Self::from_nodes(&nodes)
+    }
+}
+
+impl From<GameTree> for Vec<Vec<MoveNode>> {
+    fn from(tree: GameTree) -> Self {
+        tree.to_nodes()
+    }
+}
+
+/// Move assessment glyphs the frontend can show, by NAG.
+const GLYPHS: [(u8, &str, &str); 6] = [
+    (1, "!", "teal"),
+    (2, "?", "orange"),
+    (3, "!!", "cyan"),
+    (4, "??", "red"),
+    (5, "!?", "lime"),
+    (6, "?!", "yellow"),
+];
+
+fn glyph_nag(text: &str) -> Option<u8> {
+    GLYPHS.iter().find(|g| g.1 == text).map(|g| g.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Cursor {
+    pub var: usize,
+    /// Index in the line, -1 before its first move.
+    pub num: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Glyph {
+    pub text: String,
+    pub color: String,
+}
+
+/// A chess.js `Move`, as the frontend stores it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct JsMove {
+    pub color: String,
+    pub from: String,
+    pub to: String,
+    pub piece: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub promotion: Option<String>,
+    pub flags: String,
+    pub san: String,
+    pub lan: String,
+    pub before: String,
+    pub after: String,
+}
+
+impl JsMove {
+    fn new(pos: &Chess, mv: Move) -> Self {
+        let uci = mv.to_uci(CastlingMode::Standard);
+        let (from, to) = match uci {
+            UciMove::Normal { from, to, .. } => (from.to_string(), to.to_string()),
+            _ => (String::new(), String::new()),
+        };
+        // chess.js flags, in the order chess.js writes them.
+        let big_pawn =
+            mv.role() == Role::Pawn && mv.from().is_some_and(|from| from.distance(mv.to()) == 2);
+        let mut flags = String::new();
+        if !mv.is_capture() && !big_pawn && !mv.is_castle() {
+            flags.push('n');
+        }
+        if mv.is_capture() && !mv.is_en_passant() {
+            flags.push('c');
+        }
+        if big_pawn {
+            flags.push('b');
+        }
+        if mv.is_en_passant() {
+            flags.push('e');
+        }
+        if mv.is_promotion() {
+            flags.push('p');
+        }
+        if let Move::Castle { king, rook } = mv {
+            flags.push(if rook.file() > king.file() { 'k' } else { 'q' });
+        }
+
+        let mut after = pos.clone();
+        let san = SanPlus::from_move_and_play_unchecked(&mut after, mv);
+        Self {
+            color: if pos.turn() == Color::White { "w" } else { "b" }.to_string(),
+            from,
+            to,
+            piece: mv.role().char().to_string(),
+            captured: mv.capture().map(|role| role.char().to_string()),
+            promotion: mv.promotion().map(|role| role.char().to_string()),
+            flags,
+            san: san.to_string(),
+            lan: uci.to_string(),
+            before: Fen::from_position(pos, EnPassantMode::Legal).to_string(),
+            after: Fen::from_position(&after, EnPassantMode::Legal).to_string(),
+        }
+    }
+}
+
+/// A node of the frontend tree.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveNode {
+    pub id: Cursor,
+    #[serde(rename = "move")]
+    pub mv: JsMove,
+    pub ply: u32,
+    pub move_number: u32,
+    pub is_white: bool,
+    pub prev: Cursor,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variations: Option<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment_before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// The move assessment the frontend shows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nag: Option<Glyph>,
+    /// Every NAG of the move, which the frontend passes through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nags: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shapes: Option<Vec<Shape>>,
+    /// Remaining clock time in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<u64>,
+}
+
+impl MoveNode {
+    fn new(pos: &Chess, node: &Node, id: Cursor, prev: Cursor) -> Self {
+        let ply = 2 * (pos.fullmoves().get() - 1) + u32::from(pos.turn() == Color::Black) + 1;
+        let glyph = node
+            .nags
+            .iter()
+            .find_map(|&nag| GLYPHS.iter().find(|g| g.0 == nag))
+            .map(|g| Glyph {
+                text: g.1.to_string(),
+                color: g.2.to_string(),
+            });
+        Self {
+            id,
+            mv: JsMove::new(pos, node.mv),
+            ply,
+            move_number: pos.fullmoves().get(),
+            is_white: pos.turn() == Color::White,
+            prev,
+            variations: None,
+            comment_before: node.comment_before.clone(),
+            comment: node.comment.clone(),
+            nag: glyph,
+            nags: (!node.nags.is_empty()).then(|| node.nags.clone()),
+            shapes: (!node.shapes.is_empty()).then(|| node.shapes.clone()),
+            clock: node.clock.map(|clock| clock.as_millis() as u64),
+        }
+    }
+}
+
+/// Parses a study's `tree_json`.
+pub fn parse_tree(json: &str) -> Result<GameTree> {
+    match serde_json::from_str(json) {
+        Ok(tree) => Ok(tree),
+        Err(e) if e.is_data() => bail!("invalid move tree: {e}"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1. e4 e5 (1... c5 2. Nf3 (2. c3)) 2. Nf3! { main } Nc6 3. Bc4
+    fn sample() -> GameTree {
+        let play = |pos: &mut Chess, uci: &str| {
+            let mv = uci.parse::<UciMove>().unwrap().to_move(pos).unwrap();
+            Node::new(pos, mv)
+        };
+        let mut pos = Chess::default();
+        let mut e4 = play(&mut pos, "e2e4");
+        let mut sicilian = pos.clone();
+        let mut e5 = play(&mut pos, "e7e5");
+        let mut nf3 = play(&mut pos, "g1f3");
+        nf3.nags = vec![1];
+        nf3.comment = Some("main".into());
+        nf3.shapes = vec![Shape {
+            orig: Square::E4,
+            dest: Some(Square::E5),
+            brush: Brush::Red,
+        }];
+        let mut nc6 = play(&mut pos, "b8c6");
+        nc6.clock = Some(Duration::from_secs(170));
+        nc6.children = vec![play(&mut pos, "f1c4")];
+        nf3.children = vec![nc6];
+        e5.children = vec![nf3];
+
+        let mut c5 = play(&mut sicilian, "c7c5");
+        c5.children = vec![
+            play(&mut sicilian.clone(), "g1f3"),
+            play(&mut sicilian, "c2c3"),
+        ];
+        e4.children = vec![e5, c5];
+        GameTree {
+            start: Chess::default(),
+            children: vec![e4],
+        }
+    }
+
+    #[test]
+    fn frontend_round_trip() {
+        let tree = sample();
+        assert_eq!(tree.len(), 8);
+        let sans = tree.mainline().map(|n| n.san.as_str()).collect::<Vec<_>>();
+        assert_eq!(sans, ["e4", "e5", "Nf3", "Nc6", "Bc4"]);
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let nodes: Vec<Vec<MoveNode>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0][1].variations, Some(vec![1]));
+        assert_eq!(nodes[1][0].prev, Cursor { var: 0, num: 0 });
+        assert_eq!(nodes[1][1].variations, Some(vec![2]));
+        assert_eq!(nodes[0][2].nag.as_ref().unwrap().text, "!");
+        assert_eq!(nodes[0][2].mv.flags, "n");
+        assert_eq!(nodes[0][0].mv.flags, "b");
+        assert_eq!((nodes[0][3].ply, nodes[0][3].move_number), (4, 2));
+        assert!(!nodes[0][3].is_white);
+
+        assert_eq!(parse_tree(&json).unwrap(), tree);
+        assert_eq!(GameTree::from_nodes(&nodes).unwrap().to_nodes(), nodes);
+
+        // Changing the glyph replaces the assessment but keeps the other NAGs.
+        let mut edited = nodes.clone();
+        edited[0][2].nags = Some(vec![1, 14]);
+        edited[0][2].nag = Some(Glyph {
+            text: "?!".into(),
+            color: "yellow".into(),
+        });
+        let tree = GameTree::from_nodes(&edited).unwrap();
+        assert_eq!(tree.children[0].children[0].children[0].nags, [6, 14]);
+        edited[0][2].nag = None;
+        let tree = GameTree::from_nodes(&edited).unwrap();
+        assert_eq!(tree.children[0].children[0].children[0].nags, [14]);
+    }
+
+    #[test]
+    fn frontend_input() {
+        // As saved by the notation panel, with chess.js moves and no extra fields.
+        let json = r#"[[{"id":{"var":0,"num":0},"move":{"color":"w","piece":"p","from":"e2","to":"e4","san":"e4","flags":"b","lan":"e2e4","before":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","after":"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"},"prev":{"var":0,"num":-1},"ply":1,"moveNumber":1,"isWhite":true,"variations":[1],"nag":{"text":"!?","color":"lime"}}],[{"id":{"var":1,"num":0},"move":{"color":"w","piece":"p","from":"d2","to":"d4","san":"d4","flags":"b","lan":"d2d4","before":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","after":"rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1"},"prev":{"var":0,"num":-1},"ply":1,"moveNumber":1,"isWhite":true,"comment":"solid"}]]"#;
+        let tree = parse_tree(json).unwrap();
+        assert_eq!(tree.children.len(), 2);
+        assert_eq!(tree.children[0].nags, [5]);
+        assert_eq!(tree.children[1].comment.as_deref(), Some("solid"));
+
+        let illegal = json.replace("\"lan\":\"d2d4\"", "\"lan\":\"d2d5\"");
+        assert!(parse_tree(&illegal).is_err());
+        assert!(parse_tree("[[]]").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,625 @@
+//! Training on a repertoire: the opponent's moves are played for the user, who has to find
+//! their prepared replies. Lines are scheduled with SM-2.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use shakmaty::{
+    fen::Fen, san::San, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Position,
+};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::{
+    position_hash,
+    repertoire::{RepertoireMove, RepertoireTree},
+    Database,
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Lines are cut after this many plies, which also guards against repetition cycles.
+const MAX_LINE_PLY: usize = 60;
+
+/// SM-2 state of a line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    pub ease: f64,
+    /// Days until the next review.
+    pub interval: i64,
+    /// Successful reviews in a row.
+    pub repetitions: i64,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            ease: 2.5,
+            interval: 0,
+            repetitions: 0,
+        }
+    }
+}
+
+impl Schedule {
+    /// The schedule after a review graded `quality`, from 0 (forgotten) to 5 (perfect).
+    pub fn review(self, quality: u8) -> Self {
+        let q = 5.0 - quality.min(5) as f64;
+        let ease = (self.ease + 0.1 - q * (0.08 + q * 0.02)).max(1.3);
+        if quality < 3 {
+            return Self {
+                ease,
+                interval: 1,
+                repetitions: 0,
+            };
+        }
+        let interval = match self.repetitions {
+            0 => 1,
+            1 => 6,
+            _ => (self.interval as f64 * ease).round() as i64,
+        };
+        Self {
+            ease,
+            interval,
+            repetitions: self.repetitions + 1,
+        }
+    }
+}
+
+/// Review grade of a line played with `mistakes` wrong moves. More than one counts as a lapse.
+pub fn quality(mistakes: u32) -> u8 {
+    match mistakes {
+        0 => 5,
+        1 => 3,
+        _ => 1,
+    }
+}
+
+/// Every line of the repertoire from the initial position, as UCI moves ending with one of
+/// the user's moves.
+pub fn lines(tree: &RepertoireTree) -> Vec<Vec<String>> {
+    fn walk(
+        tree: &RepertoireTree,
+        pos: &Chess,
+        path: &mut Vec<String>,
+        on_path: &mut HashSet<i64>,
+        lines: &mut Vec<Vec<String>>,
+    ) {
+        let mut extended = false;
+        if path.len() < MAX_LINE_PLY {
+            for rm in tree.moves(pos) {
+                let Some(m) = rm
+                    .uci
+                    .parse::<UciMove>()
+                    .ok()
+                    .and_then(|m| m.to_move(pos).ok())
+                else {
+                    continue;
+                };
+                let mut after = pos.clone();
+                after.play_unchecked(m);
+                let hash = position_hash(&after);
+                if !on_path.insert(hash) {
+                    continue;
+                }
+                path.push(rm.uci.clone());
+                walk(tree, &after, path, on_path, lines);
+                path.pop();
+                on_path.remove(&hash);
+                extended = true;
+            }
+        }
+        if !extended {
+            lines.push(path.clone());
+        }
+    }
+
+    let start = Chess::default();
+    let mut lines = Vec::new();
+    walk(
+        tree,
+        &start,
+        &mut Vec::new(),
+        &mut HashSet::from([position_hash(&start)]),
+        &mut lines,
+    );
+
+    // White moves on even plies. Trailing opponent moves have nothing to answer.
+    let ours = if tree.color.color().is_white() { 0 } else { 1 };
+    for line in &mut lines {
+        while !line.is_empty() && (line.len() - 1) % 2 != ours {
+            line.pop();
+        }
+    }
+    lines.retain(|line| !line.is_empty());
+    lines.sort();
+    lines.dedup();
+    lines
+}
+
+/// Scheduling state and statistics of a line.
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineStats {
+    pub id: i64,
+    /// UCI moves from the initial position, separated by spaces.
+    pub moves: String,
+    pub ease: f64,
+    pub interval: i64,
+    pub repetitions: i64,
+    pub due: String,
+    pub reviews: i64,
+    pub lapses: i64,
+    pub last_reviewed: Option<String>,
+    /// Wrong moves over all reviews.
+    pub mistakes: i64,
+}
+
+const LINE_STATS: &str = r#"
+    select l.id, l.moves, l.ease, l.interval, l.repetitions, l.due, l.reviews, l.lapses,
+      l.last_reviewed,
+      (select count(*) from drill_mistake m where m.line_id = l.id) as mistakes
+    from drill_line l
+    where l.repertoire_id = $1
+"#;
+
+impl Database {
+    /// Brings the drill lines in line with the repertoire. Lines that are gone lose their
+    /// statistics; new ones are due at once.
+    pub async fn sync_drill_lines(&self, id: i64, tree: &RepertoireTree) -> Result<()> {
+        let lines = lines(tree)
+            .into_iter()
+            .map(|line| line.join(" "))
+            .collect::<HashSet<_>>();
+        let existing: Vec<(i64, String)> =
+            sqlx::query_as("select id, moves from drill_line where repertoire_id = $1")
+                .bind(id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut tx = self.pool.begin().await?;
+        for (line_id, moves) in &existing {
+            if !lines.contains(moves) {
+                sqlx::query("delete from drill_line where id = $1")
+                    .bind(line_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        let mut new = lines
+            .iter()
+            .filter(|moves| !existing.iter().any(|(_, m)| m == *moves))
+            .collect::<Vec<_>>();
+        new.sort();
+        for moves in new {
+            sqlx::query("insert into drill_line (repertoire_id, moves) values ($1, $2)")
+                .bind(id)
+                .bind(moves)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The lines of a repertoire, soonest due first, or only those due now.
+    pub async fn drill_lines(&self, id: i64, due_only: bool) -> Result<Vec<LineStats>> {
+        let mut sql = LINE_STATS.to_string();
+        if due_only {
+            sql.push_str(" and l.due <= datetime('now')");
+        }
+        sql.push_str(" order by l.due, l.id");
+        let lines = sqlx::query_as(&sql).bind(id).fetch_all(&self.pool).await?;
+        Ok(lines)
+    }
+
+    async fn drill_line(&self, line_id: i64) -> Result<LineStats> {
+        let sql = LINE_STATS.replace("l.repertoire_id = $1", "l.id = $1");
+        let line = sqlx::query_as(&sql)
+            .bind(line_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .with_context(|| format!("no drill line with id {line_id}"))?;
+        Ok(line)
+    }
+
+    /// Reschedules a line after it was played through with `mistakes` wrong moves.
+    pub async fn review_drill_line(&self, line_id: i64, mistakes: u32) -> Result<LineStats> {
+        let line = self.drill_line(line_id).await?;
+        let quality = quality(mistakes);
+        let next = Schedule {
+            ease: line.ease,
+            interval: line.interval,
+            repetitions: line.repetitions,
+        }
+        .review(quality);
+        sqlx::query(
+            r#"
+            update drill_line set
+              ease = $1,
+              interval = $2,
+              repetitions = $3,
+              due = datetime('now', printf('+%d days', $2)),
+              reviews = reviews + 1,
+              lapses = lapses + $4,
+              last_reviewed = current_timestamp
+            where id = $5
+            "#,
+        )
+        .bind(next.ease)
+        .bind(next.interval)
+        .bind(next.repetitions)
+        .bind(i64::from(quality < 3))
+        .bind(line_id)
+        .execute(&self.pool)
+        .await?;
+        self.drill_line(line_id).await
+    }
+
+    pub async fn log_drill_mistake(&self, mistake: &Mistake) -> Result<()> {
+        sqlx::query(
+            r#"
+            insert into drill_mistake (line_id, ply, fen, expected, played)
+            values ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(mistake.line_id)
+        .bind(mistake.ply as i64)
+        .bind(&mistake.fen)
+        .bind(&mistake.expected)
+        .bind(&mistake.played)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// The move of the line.
+    Correct,
+    /// Another repertoire move, which is not what this line is about. Not a mistake.
+    Alternative,
+    Mistake,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mistake {
+    pub line_id: i64,
+    pub ply: usize,
+    pub fen: String,
+    pub expected: String,
+    pub played: String,
+}
+
+/// What a submitted move did to the session.
+#[derive(Debug)]
+pub struct Outcome {
+    pub verdict: Verdict,
+    /// The line's move in SAN, after a mistake.
+    pub expected: Option<String>,
+    pub mistake: Option<Mistake>,
+    /// The line that was just finished and the mistakes made in it.
+    pub completed: Option<(i64, u32)>,
+}
+
+/// A run through the due lines of a repertoire.
+pub struct DrillSession {
+    tree: RepertoireTree,
+    lines: Vec<(i64, Vec<String>)>,
+    line: usize,
+    ply: usize,
+    pos: Chess,
+    mistakes: u32,
+    /// The opponent's last move, in SAN.
+    last: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrillState {
+    pub line_index: usize,
+    pub lines: usize,
+    pub line_id: Option<i64>,
+    /// Position the user has to move from.
+    pub fen: String,
+    /// UCI moves of the line played so far.
+    pub moves: Vec<String>,
+    pub last_move: Option<String>,
+    pub mistakes: u32,
+    pub finished: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrillFeedback {
+    pub verdict: Verdict,
+    pub expected: Option<String>,
+    /// The new schedule of a line the move finished.
+    pub review: Option<LineStats>,
+    pub state: DrillState,
+}
+
+impl DrillSession {
+    pub fn new(tree: RepertoireTree, lines: Vec<(i64, Vec<String>)>) -> Self {
+        let mut session = Self {
+            tree,
+            lines,
+            line: 0,
+            ply: 0,
+            pos: Chess::default(),
+            mistakes: 0,
+            last: None,
+        };
+        session.begin_line();
+        session
+    }
+
+    fn color(&self) -> Color {
+        self.tree.color.color()
+    }
+
+    fn begin_line(&mut self) {
+        self.ply = 0;
+        self.pos = Chess::default();
+        self.mistakes = 0;
+        self.last = None;
+        self.play_opponent();
+    }
+
+    /// Plays the line's moves until it is the user's turn.
+    fn play_opponent(&mut self) {
+        let Some((_, line)) = self.lines.get(self.line) else {
+            return;
+        };
+        while self.pos.turn() != self.color() {
+            let Some(m) = line
+                .get(self.ply)
+                .and_then(|uci| uci.parse::<UciMove>().ok())
+                .and_then(|m| m.to_move(&self.pos).ok())
+            else {
+                return;
+            };
+            self.last = Some(San::from_move(&self.pos, m).to_string());
+            self.pos.play_unchecked(m);
+            self.ply += 1;
+        }
+    }
+
+    pub fn state(&self) -> DrillState {
+        let current = self.lines.get(self.line);
+        DrillState {
+            line_index: self.line,
+            lines: self.lines.len(),
+            line_id: current.map(|(id, _)| *id),
+            fen: Fen::from_position(&self.pos, EnPassantMode::Legal).to_string(),
+            moves: current
+                .map(|(_, line)| line[..self.ply].to_vec())
+                .unwrap_or_default(),
+            last_move: self.last.clone(),
+            mistakes: self.mistakes,
+            finished: current.is_none(),
+        }
+    }
+
+    /// Checks the user's move against the line and moves on if it is right.
+    pub fn play(&mut self, uci: &str) -> Result<Outcome> {
+        let (line_id, line) = self.lines.get(self.line).context("the drill is over")?;
+        let line_id = *line_id;
+        let m = uci.parse::<UciMove>()?.to_move(&self.pos)?;
+        let expected = line
+            .get(self.ply)
+            .context("the line is over")?
+            .parse::<UciMove>()?
+            .to_move(&self.pos)?;
+        let len = line.len();
+
+        if m == expected {
+            self.pos.play_unchecked(m);
+            self.ply += 1;
+            self.play_opponent();
+            let mut completed = None;
+            if self.ply >= len {
+                completed = Some((line_id, self.mistakes));
+                self.line += 1;
+                self.begin_line();
+            }
+            return Ok(Outcome {
+                verdict: Verdict::Correct,
+                expected: None,
+                mistake: None,
+                completed,
+            });
+        }
+
+        let played = m.to_uci(CastlingMode::Standard).to_string();
+        let moves: &[RepertoireMove] = self.tree.moves(&self.pos);
+        if moves.iter().any(|rm| rm.uci == played) {
+            return Ok(Outcome {
+                verdict: Verdict::Alternative,
+                expected: None,
+                mistake: None,
+                completed: None,
+            });
+        }
+
+        self.mistakes += 1;
+        Ok(Outcome {
+            verdict: Verdict::Mistake,
+            expected: Some(San::from_move(&self.pos, expected).to_string()),
+            mistake: Some(Mistake {
+                line_id,
+                ply: self.ply,
+                fen: Fen::from_position(&self.pos, EnPassantMode::Legal).to_string(),
+                expected: expected.to_uci(CastlingMode::Standard).to_string(),
+                played,
+            }),
+            completed: None,
+        })
+    }
+}
+
+impl Database {
+    /// A session over the lines due now, at most `limit` of them.
+    pub async fn start_drill(&self, id: i64, limit: Option<usize>) -> Result<DrillSession> {
+        let tree = self.repertoire_tree(id).await?;
+        self.sync_drill_lines(id, &tree).await?;
+        let lines = self
+            .drill_lines(id, true)
+            .await?
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|line| {
+                let moves = line.moves.split(' ').map(str::to_string).collect();
+                (line.id, moves)
+            })
+            .collect();
+        Ok(DrillSession::new(tree, lines))
+    }
+
+    /// Plays the user's move in the session, logging mistakes and rescheduling finished lines.
+    pub async fn drill_move(&self, session: &mut DrillSession, uci: &str) -> Result<DrillFeedback> {
+        let outcome = session.play(uci)?;
+        if let Some(mistake) = &outcome.mistake {
+            self.log_drill_mistake(mistake).await?;
+        }
+        let review = match outcome.completed {
+            Some((line_id, mistakes)) => Some(self.review_drill_line(line_id, mistakes).await?),
+            None => None,
+        };
+        Ok(DrillFeedback {
+            verdict: outcome.verdict,
+            expected: outcome.expected,
+            review,
+            state: session.state(),
+        })
+    }
+}
+
+/// Starts drilling the due lines of a repertoire, replacing any running session.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn start_drill(
+    id: i64,
+    limit: Option<usize>,
+    state: State<'_, AppState>,
+) -> Result<DrillState, String> {
+    let session = state
+        .db
+        .start_drill(id, limit)
+        .await
+        .map_err(|e| e.to_string())?;
+    let drill = session.state();
+    *state.drill.lock().await = Some(session);
+    Ok(drill)
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn drill_move(uci: &str, state: State<'_, AppState>) -> Result<DrillFeedback, String> {
+    let mut drill = state.drill.lock().await;
+    let session = drill.as_mut().ok_or("no drill session")?;
+    state
+        .db
+        .drill_move(session, uci)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The lines of a repertoire due for review now.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn due_reviews(id: i64, state: State<'_, AppState>) -> Result<Vec<LineStats>, String> {
+    let tree = state
+        .db
+        .repertoire_tree(id)
+        .await
+        .map_err(|e| e.to_string())?;
+    state
+        .db
+        .sync_drill_lines(id, &tree)
+        .await
+        .map_err(|e| e.to_string())?;
+    state
+        .db
+        .drill_lines(id, true)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Scheduling and statistics of every line of a repertoire.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn drill_line_stats(
+    id: i64,
+    state: State<'_, AppState>,
+) -> Result<Vec<LineStats>, String> {
+    state
+        .db
+        .drill_lines(id, false)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::explorer::Side;
+
+    #[test]
+    fn sm2() {
+        let s = Schedule::default().review(5);
+        assert_eq!((s.interval, s.repetitions), (1, 1));
+        let s = s.review(5);
+        assert_eq!(s.interval, 6);
+        let s = s.review(5);
+        assert_eq!(s.interval, 17);
+        assert!((s.ease - 2.8).abs() < 1e-9);
+        let lapsed = s.review(quality(2));
+        assert_eq!((lapsed.interval, lapsed.repetitions), (1, 0));
+        assert!(lapsed.ease < s.ease);
+    }
+
+    #[tokio::test]
+    async fn drill() {
+        let db = Database::memory().await.unwrap();
+        let id = db.create_repertoire("Sicilian", Side::Black).await.unwrap();
+        db.import_repertoire(
+            id,
+            "1. e4 c5 2. Nf3 d6 (2... Nc6) *\n\n[Event \"?\"]\n\n1. d4 Nf6 *\n",
+        )
+        .await
+        .unwrap();
+
+        let mut session = db.start_drill(id, None).await.unwrap();
+        let state = session.state();
+        assert_eq!(state.lines, 3);
+        assert_eq!(state.last_move.as_deref(), Some("d4"));
+
+        let wrong = db.drill_move(&mut session, "e7e5").await.unwrap();
+        assert_eq!(wrong.verdict, Verdict::Mistake);
+        assert_eq!(wrong.expected.as_deref(), Some("Nf6"));
+        assert!(db.drill_move(&mut session, "e7e4").await.is_err());
+
+        let done = db.drill_move(&mut session, "g8f6").await.unwrap();
+        assert_eq!(done.verdict, Verdict::Correct);
+        let review = done.review.unwrap();
+        assert_eq!(
+            (review.reviews, review.mistakes, review.interval),
+            (1, 1, 1)
+        );
+        assert_eq!(done.state.last_move.as_deref(), Some("e4"));
+
+        db.drill_move(&mut session, "c7c5").await.unwrap();
+        let other = db.drill_move(&mut session, "d7d6").await.unwrap();
+        assert_eq!(other.verdict, Verdict::Alternative);
+        let done = db.drill_move(&mut session, "b8c6").await.unwrap();
+        assert_eq!(done.review.unwrap().lapses, 0);
+        assert_eq!(done.state.line_index, 2);
+
+        let due = db.drill_lines(id, true).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].moves, "e2e4 c7c5 g1f3 d7d6");
+    }
+}
@@ -0,0 +1,360 @@
+//! Copies of the same game, as left behind by importing overlapping files or merging club
+//! databases.
+//!
+//! Two games are copies when their normalized players, date and event match and the moves of
+//! one start the moves of the other, so truncated and annotated copies are found too.
+
+use anyhow::Result;
+use sqlx::SqliteConnection;
+#[cfg(feature = "desktop")]
+use tauri::State;
+use tracing::{debug, warn};
+
+use super::{
+    games::{normalize_name, replace_game},
+    Database,
+};
+use crate::chess::{
+    openings::OpeningBook,
+    pgn::{read_annotated, AnnotatedGame, Game},
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Games keyed per transaction when filling in keys.
+const KEY_BATCH: i64 = 500;
+
+/// A stored game's id, movetext and PGN.
+type Stored = (i64, String, String);
+
+/// What to do with a game that is stored already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicatePolicy {
+    /// Keep the stored copy as it is.
+    #[default]
+    Skip,
+    /// Keep the copy with the most moves.
+    Longest,
+    /// Keep the most moves, with the variations, annotations and tags of every copy.
+    Merge,
+}
+
+/// What was done with a duplicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicateAction {
+    Skipped,
+    /// The stored copy was replaced by the longer new one.
+    Replaced,
+    Merged,
+}
+
+/// FNV-1a over the normalized players, date and event.
+pub(super) fn match_key(white: &str, black: &str, date: Option<&str>, event: Option<&str>) -> i64 {
+    let parts = [
+        normalize_name(white),
+        normalize_name(black),
+        date.unwrap_or_default().to_string(),
+        normalize_name(event.unwrap_or_default()),
+    ];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in &parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+    hash as i64
+}
+
+/// The [`match_key`] of a game, with its players stored as `?` if missing.
+pub(super) fn game_key(game: &Game) -> i64 {
+    match_key(
+        game.tag("White").unwrap_or("?"),
+        game.tag("Black").unwrap_or("?"),
+        game.tag("Date"),
+        game.tag("Event"),
+    )
+}
+
+/// Whether two movetexts are the same or one starts the other, move for move. A game without
+/// moves only matches another without moves.
+pub fn same_moves(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if short.is_empty() {
+        return long.is_empty();
+    }
+    long.strip_prefix(short)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+fn ply_count(movetext: &str) -> usize {
+    movetext.split_whitespace().count()
+}
+
+fn parse(pgn: &str) -> Option<AnnotatedGame> {
+    read_annotated(pgn.as_bytes()).next()?.ok()
+}
+
+/// The oldest stored copy of `game` with its movetext, if any.
+pub(super) async fn find_duplicate(
+    conn: &mut SqliteConnection,
+    game: &Game,
+) -> Result<Option<(i64, String)>> {
+    let movetext = game.movetext();
+    let candidates: Vec<(i64, String)> = sqlx::query_as(
+        "select id, movetext from game \
+         where match_key = $1 and movetext is not null order by id",
+    )
+    .bind(game_key(game))
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(candidates
+        .into_iter()
+        .find(|(_, stored)| same_moves(stored, &movetext)))
+}
+
+/// Applies `policy` to `game`, a copy of the stored game `existing`.
+pub(super) async fn resolve(
+    conn: &mut SqliteConnection,
+    existing: (i64, &str),
+    game: &AnnotatedGame,
+    policy: DuplicatePolicy,
+    book: &OpeningBook,
+) -> Result<DuplicateAction> {
+    let (id, movetext) = existing;
+    match policy {
+        DuplicatePolicy::Skip => Ok(DuplicateAction::Skipped),
+        DuplicatePolicy::Longest if game.tree.mainline().count() <= ply_count(movetext) => {
+            Ok(DuplicateAction::Skipped)
+        }
+        DuplicatePolicy::Longest => {
+            replace_game(conn, id, game, book).await?;
+            Ok(DuplicateAction::Replaced)
+        }
+        DuplicatePolicy::Merge => {
+            let pgn: String = sqlx::query_scalar("select pgn from game where id = $1")
+                .bind(id)
+                .fetch_one(&mut *conn)
+                .await?;
+            let Some(stored) = parse(&pgn) else {
+                warn!(id, "stored game could not be read, keeping it");
+                return Ok(DuplicateAction::Skipped);
+            };
+            replace_game(conn, id, &stored.merged(game), book).await?;
+            Ok(DuplicateAction::Merged)
+        }
+    }
+}
+
+/// Copies of one game that were merged into the oldest.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    pub kept: i64,
+    pub removed: Vec<i64>,
+    pub white: String,
+    pub black: String,
+    pub date: Option<String>,
+}
+
+impl Database {
+    /// Computes the match key of games stored before keys existed, and their movetext if they
+    /// predate the importer.
+    pub(super) async fn fill_match_keys(&self) -> Result<()> {
+        let mut after = 0;
+        loop {
+            #[allow(clippy::type_complexity)]
+            let rows: Vec<(
+                i64,
+                String,
+                String,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                String,
+            )> = sqlx::query_as(
+                "select id, white, black, date, event, movetext, pgn from game \
+                 where match_key is null and id > $1 order by id limit $2",
+            )
+            .bind(after)
+            .bind(KEY_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+            let Some(&(last, ..)) = rows.last() else {
+                return Ok(());
+            };
+            after = last;
+            let mut tx = self.pool.begin().await?;
+            for (id, white, black, date, event, movetext, pgn) in rows {
+                let Some(movetext) =
+                    movetext.or_else(|| parse(&pgn).map(|g| g.mainline().movetext()))
+                else {
+                    continue;
+                };
+                sqlx::query(
+                    "update game set match_key = $1, movetext = $2, \
+                     ply_count = coalesce(ply_count, $3) where id = $4",
+                )
+                .bind(match_key(&white, &black, date.as_deref(), event.as_deref()))
+                .bind(&movetext)
+                .bind(ply_count(&movetext) as i64)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+        }
+    }
+
+    /// Merges every game stored more than once into its oldest copy, following `policy`, and
+    /// deletes the other copies. Merged games are classified again with `book`.
+    pub async fn merge_duplicates(
+        &self,
+        policy: DuplicatePolicy,
+        book: &OpeningBook,
+    ) -> Result<Vec<DuplicateGroup>> {
+        self.fill_match_keys().await?;
+        let keys: Vec<i64> = sqlx::query_scalar(
+            "select match_key from game where match_key is not null \
+             group by match_key having count(*) > 1",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut groups = Vec::new();
+        let mut tx = self.pool.begin().await?;
+        for key in keys {
+            let rows: Vec<Stored> = sqlx::query_as(
+                "select id, movetext, pgn from game \
+                 where match_key = $1 and movetext is not null order by id",
+            )
+            .bind(key)
+            .fetch_all(&mut *tx)
+            .await?;
+            // Copies of one game, compared by their longest moves so that games sharing only
+            // an opening stay apart.
+            let mut copies: Vec<(usize, Vec<Stored>)> = Vec::new();
+            for row in rows {
+                match copies
+                    .iter_mut()
+                    .find(|(longest, copies)| same_moves(&copies[*longest].1, &row.1))
+                {
+                    Some((longest, copies)) => {
+                        if ply_count(&row.1) > ply_count(&copies[*longest].1) {
+                            *longest = copies.len();
+                        }
+                        copies.push(row);
+                    }
+                    None => copies.push((0, vec![row])),
+                }
+            }
+
+            for (longest, copies) in copies.into_iter().filter(|(_, c)| c.len() > 1) {
+                let kept = copies[0].0;
+                let game = match policy {
+                    DuplicatePolicy::Skip => None,
+                    DuplicatePolicy::Longest => Some(longest)
+                        .filter(|&i| i > 0)
+                        .and_then(|i| parse(&copies[i].2)),
+                    DuplicatePolicy::Merge => copies
+                        .iter()
+                        .filter_map(|(_, _, pgn)| parse(pgn))
+                        .reduce(|merged, copy| merged.merged(&copy)),
+                };
+                let removed = copies[1..].iter().map(|(id, ..)| *id).collect::<Vec<_>>();
+                for id in &removed {
+                    sqlx::query("delete from game where id = $1")
+                        .bind(id)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                if let Some(game) = &game {
+                    replace_game(&mut tx, kept, game, book).await?;
+                }
+                let game = game.or_else(|| parse(&copies[0].2)).unwrap_or_default();
+                let tag = |name| game.tag(name).unwrap_or("?").to_string();
+                groups.push(DuplicateGroup {
+                    kept,
+                    removed,
+                    white: tag("White"),
+                    black: tag("Black"),
+                    date: game.tag("Date").map(str::to_string),
+                });
+            }
+        }
+        tx.commit().await?;
+        debug!(groups = groups.len(), "duplicates merged");
+        Ok(groups)
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn merge_duplicate_games(
+    policy: DuplicatePolicy,
+    state: State<'_, AppState>,
+) -> Result<Vec<DuplicateGroup>, String> {
+    let book = state.openings.read().unwrap().clone();
+    state
+        .db
+        .merge_duplicates(policy, &book)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn merge_copies() {
+        let db = Database::memory().await.unwrap();
+        let full = "[Event \"Club  Championship\"]\n[White \"Doe, Jane\"]\n[Black \"Roe, Ann\"]\n\
+                    [Date \"2023.03.01\"]\n[Result \"1-0\"]\n\n\
+                    1. e4 $1 e5 ( 1... c5 ) 2. Nf3 Nc6 3. Bc4 1-0\n";
+        let annotated =
+            "[Event \"club championship\"]\n[White \"doe, jane\"]\n[Black \"Roe, Ann\"]\n\
+                         [Date \"2023.03.01\"]\n[Annotator \"Smith\"]\n[Result \"*\"]\n\n\
+                         1. e4 { Best by test } 1... e5 2. Nf3 *\n";
+        let other = "[Event \"Club Championship\"]\n[White \"Doe, Jane\"]\n[Black \"Roe, Ann\"]\n\
+                     [Date \"2023.03.01\"]\n[Result \"0-1\"]\n\n1. e4 c5 0-1\n";
+        let full_id = db.insert_pgn(full, &OpeningBook::bundled()).await.unwrap();
+        db.insert_pgn(other, &OpeningBook::bundled()).await.unwrap();
+        let annotated_id = db
+            .insert_pgn(annotated, &OpeningBook::bundled())
+            .await
+            .unwrap();
+        assert!(same_moves("e4 e5 Nf3", "e4 e5"));
+        assert!(!same_moves("e4 e5 Nf3", "e4 e"));
+        assert!(!same_moves("e4 e5", ""));
+        assert!(same_moves("", ""));
+
+        let groups = db
+            .merge_duplicates(DuplicatePolicy::Merge, &OpeningBook::bundled())
+            .await
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            (groups[0].kept, &groups[0].removed[..]),
+            (full_id, &[annotated_id][..])
+        );
+
+        let (movetext, pgn): (String, String) =
+            sqlx::query_as("select movetext, pgn from game where id = $1")
+                .bind(full_id)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(movetext, "e4 e5 Nf3 Nc6 Bc4");
+        assert!(pgn.contains("[Annotator \"Smith\"]"));
+        assert!(pgn.contains("[Result \"1-0\"]"));
+        assert!(pgn.contains("1. e4 $1 { Best by test } 1... e5 ( 1... c5 ) 2. Nf3"));
+        let games: i64 = sqlx::query_scalar("select count(*) from game")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(games, 2);
+    }
+}
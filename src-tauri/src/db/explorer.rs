@@ -0,0 +1,410 @@
+//! Opening explorer over the stored games.
+
+#[cfg(feature = "desktop")]
+use shakmaty::{fen::Fen, CastlingMode};
+use shakmaty::{san::San, uci::UciMove, Chess, Color};
+use sqlx::{QueryBuilder, Sqlite};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::{position_hash, search::like_escape, Database};
+use crate::chess::clock::Speed;
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Number of recent games returned when the filter does not say.
+const RECENT_GAMES: i64 = 8;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Side {
+    White,
+    Black,
+}
+
+impl Side {
+    pub fn color(self) -> Color {
+        match self {
+            Self::White => Color::White,
+            Self::Black => Color::Black,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExplorerFilter {
+    /// Only games of this player, compared case-insensitively.
+    pub player: Option<String>,
+    /// The colour `player` had, either colour if unset.
+    pub color: Option<Side>,
+    /// Only games of these speeds, all games if empty.
+    pub speeds: Vec<Speed>,
+    /// Earliest date, in the PGN `YYYY.MM.DD` format.
+    pub since: Option<String>,
+    /// Latest date, in the PGN `YYYY.MM.DD` format.
+    pub until: Option<String>,
+    /// Only games whose ECO code starts with this, e.g. `B9`.
+    pub eco: Option<String>,
+    pub recent: Option<i64>,
+}
+
+/// Conditions on the stored games.
+pub trait GameFilter {
+    /// Appends the filter as `and ...` conditions on the game table aliased `g`, or fails if
+    /// the filter is invalid.
+    fn push(&self, q: &mut QueryBuilder<'_, Sqlite>) -> anyhow::Result<()>;
+}
+
+impl GameFilter for ExplorerFilter {
+    fn push(&self, q: &mut QueryBuilder<'_, Sqlite>) -> anyhow::Result<()> {
+        if let Some(player) = &self.player {
+            match self.color {
+                Some(Side::White) => {
+                    q.push(" and g.white = ").push_bind(player.clone());
+                    q.push(" collate nocase");
+                }
+                Some(Side::Black) => {
+                    q.push(" and g.black = ").push_bind(player.clone());
+                    q.push(" collate nocase");
+                }
+                None => {
+                    q.push(" and (g.white = ").push_bind(player.clone());
+                    q.push(" collate nocase or g.black = ")
+                        .push_bind(player.clone());
+                    q.push(" collate nocase)");
+                }
+            }
+        }
+        if !self.speeds.is_empty() {
+            q.push(" and g.speed in (");
+            let mut list = q.separated(", ");
+            for speed in &self.speeds {
+                list.push_bind(speed.as_str());
+            }
+            q.push(")");
+        }
+        if let Some(since) = &self.since {
+            q.push(" and g.date >= ").push_bind(since.clone());
+        }
+        if let Some(until) = &self.until {
+            q.push(" and g.date <= ").push_bind(until.clone());
+        }
+        if let Some(eco) = &self.eco {
+            q.push(" and g.eco like ")
+                .push_bind(format!("{}%", like_escape(eco)));
+            q.push(" escape '\\'");
+        }
+        Ok(())
+    }
+}
+
+/// Results of the games that reached a position or played a move from it.
+#[derive(Debug, Default, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tally {
+    pub games: i64,
+    pub white: i64,
+    pub draws: i64,
+    pub black: i64,
+    /// Average of both players' ratings, over games where both are known.
+    pub average_rating: Option<f64>,
+}
+
+impl Tally {
+    /// White wins, draws and Black wins as percentages.
+    pub fn percentages(&self) -> [f64; 3] {
+        let games = self.games.max(1) as f64;
+        [self.white, self.draws, self.black].map(|n| n as f64 * 100.0 / games)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExplorerMove {
+    pub uci: String,
+    pub san: String,
+    #[serde(flatten)]
+    pub tally: Tally,
+    /// White, draw and Black percentages.
+    pub percentages: [f64; 3],
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExplorerGame {
+    pub id: i64,
+    pub white: String,
+    pub black: String,
+    pub white_elo: Option<i64>,
+    pub black_elo: Option<i64>,
+    pub result: String,
+    pub date: Option<String>,
+    pub event: Option<String>,
+    /// The move played from the position, unless the game ended there.
+    pub uci: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Explorer {
+    #[serde(flatten)]
+    pub tally: Tally,
+    pub percentages: [f64; 3],
+    pub moves: Vec<ExplorerMove>,
+    pub recent: Vec<ExplorerGame>,
+}
+
+/// The first time each game reached the position, so repetitions count once.
+const HITS: &str = r#"
+    with hits as (
+      select game_id, min(ply) as ply from position where hash = "#;
+
+const TALLY: &str = r#"
+    count(*) as games,
+    coalesce(sum(g.result = '1-0'), 0) as white,
+    coalesce(sum(g.result = '1/2-1/2'), 0) as draws,
+    coalesce(sum(g.result = '0-1'), 0) as black,
+    avg((g.white_elo + g.black_elo) / 2.0) as average_rating
+"#;
+
+/// Selects `select` from the games reaching the position `key` that pass `filter`, joined as
+/// `h` (the first hit), `p` (the position then) and `g` (the game).
+fn hits<'a>(
+    key: i64,
+    select: &str,
+    filter: &impl GameFilter,
+) -> anyhow::Result<QueryBuilder<'a, Sqlite>> {
+    let mut q = QueryBuilder::<Sqlite>::new(HITS);
+    q.push_bind(key);
+    q.push(" group by game_id)");
+    q.push(select);
+    q.push(
+        r#"
+        from hits h
+        join position p on p.game_id = h.game_id and p.ply = h.ply
+        join game g on g.id = h.game_id
+        where true"#,
+    );
+    filter.push(&mut q)?;
+    Ok(q)
+}
+
+impl Database {
+    /// Results of the games reaching `pos` that pass `filter`, overall and by the move played
+    /// next, most played first.
+    pub async fn next_moves(
+        &self,
+        pos: &Chess,
+        filter: &impl GameFilter,
+    ) -> anyhow::Result<(Tally, Vec<ExplorerMove>)> {
+        let key = position_hash(pos);
+        let tally = hits(key, &format!("select {TALLY}"), filter)?
+            .build_query_as::<Tally>()
+            .fetch_one(&self.pool)
+            .await?;
+
+        let mut q = hits(key, &format!("select p.move as uci, {TALLY}"), filter)?;
+        q.push(" and p.move is not null group by p.move order by games desc, uci");
+        let rows = q.build_query_as::<MoveRow>().fetch_all(&self.pool).await?;
+        let moves = rows
+            .into_iter()
+            .map(|row| {
+                let san = row
+                    .uci
+                    .parse::<UciMove>()
+                    .ok()
+                    .and_then(|m| m.to_move(pos).ok())
+                    .map(|m| San::from_move(pos, m).to_string())
+                    .unwrap_or_else(|| row.uci.clone());
+                ExplorerMove {
+                    uci: row.uci,
+                    san,
+                    percentages: row.tally.percentages(),
+                    tally: row.tally,
+                }
+            })
+            .collect();
+        Ok((tally, moves))
+    }
+
+    pub async fn explore(&self, pos: &Chess, filter: &ExplorerFilter) -> anyhow::Result<Explorer> {
+        let (tally, moves) = self.next_moves(pos, filter).await?;
+        let mut q = hits(
+            position_hash(pos),
+            "select g.id, g.white, g.black, g.white_elo, g.black_elo, g.result, g.date, g.event, \
+             p.move as uci",
+            filter,
+        )?;
+        q.push(" order by g.date desc, g.id desc limit ")
+            .push_bind(filter.recent.unwrap_or(RECENT_GAMES));
+        let recent = q
+            .build_query_as::<ExplorerGame>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(Explorer {
+            percentages: tally.percentages(),
+            tally,
+            moves,
+            recent,
+        })
+    }
+}
+
+/// Results of the stored games in one opening.
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningTally {
+    pub eco: String,
+    pub opening: String,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub tally: Tally,
+}
+
+impl Database {
+    /// The classified games grouped by opening, most played first.
+    pub async fn games_by_opening(
+        &self,
+        filter: &ExplorerFilter,
+    ) -> anyhow::Result<Vec<OpeningTally>> {
+        let mut q = QueryBuilder::<Sqlite>::new(format!(
+            "select g.eco, g.opening, {TALLY} from game g where g.eco is not null"
+        ));
+        filter.push(&mut q)?;
+        q.push(" group by g.eco, g.opening order by games desc, g.eco");
+        Ok(q.build_query_as().fetch_all(&self.pool).await?)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct MoveRow {
+    uci: String,
+    #[sqlx(flatten)]
+    tally: Tally,
+}
+
+/// Moves played from a position in the stored games, with results and the latest games.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn explore_position(
+    fen: &str,
+    filter: ExplorerFilter,
+    state: State<'_, AppState>,
+) -> Result<Explorer, String> {
+    let pos: Chess = fen
+        .parse::<Fen>()
+        .map_err(|e| e.to_string())?
+        .into_position(CastlingMode::Standard)
+        .map_err(|e| e.to_string())?;
+    state
+        .db
+        .explore(&pos, &filter)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn games_by_opening(
+    filter: ExplorerFilter,
+    state: State<'_, AppState>,
+) -> Result<Vec<OpeningTally>, String> {
+    state
+        .db
+        .games_by_opening(&filter)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::openings::OpeningBook;
+
+    const GAMES: [&str; 3] = [
+        "[White \"Alice\"]\n[Black \"Bob\"]\n[Result \"1-0\"]\n[Date \"2024.01.01\"]\n[WhiteElo \"2000\"]\n[BlackElo \"1800\"]\n[TimeControl \"180+2\"]\n\n1. e4 e5 2. Nf3 Nc6 1-0\n",
+        "[White \"Bob\"]\n[Black \"Alice\"]\n[Result \"1/2-1/2\"]\n[Date \"2024.02.01\"]\n[TimeControl \"600+0\"]\n\n1. Nf3 Nc6 2. e4 e5 1/2-1/2\n",
+        "[White \"Carol\"]\n[Black \"Bob\"]\n[Result \"0-1\"]\n[Date \"2024.03.01\"]\n\n1. d4 d5 0-1\n",
+    ];
+
+    #[tokio::test]
+    async fn explore() {
+        let db = Database::memory().await.unwrap();
+        for pgn in GAMES {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+
+        let start = db
+            .explore(&Chess::default(), &ExplorerFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(start.tally.games, 3);
+        let sans = start
+            .moves
+            .iter()
+            .map(|m| m.san.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sans, ["d4", "e4", "Nf3"]);
+        assert_eq!(start.recent[0].white, "Carol");
+        assert_eq!(start.tally.average_rating, Some(1900.0));
+
+        // Both move orders reach 1. e4 e5 2. Nf3 Nc6.
+        let game = read_first(GAMES[0]);
+        let transposed = db
+            .explore(
+                game.positions().unwrap().last().unwrap(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(transposed.tally.games, 2);
+        assert_eq!((transposed.tally.white, transposed.tally.draws), (1, 1));
+
+        let filter = ExplorerFilter {
+            player: Some("alice".into()),
+            color: Some(Side::Black),
+            ..Default::default()
+        };
+        let alice = db.explore(&Chess::default(), &filter).await.unwrap();
+        assert_eq!(alice.tally.games, 1);
+        assert_eq!(alice.moves[0].san, "Nf3");
+
+        let blitz = ExplorerFilter {
+            speeds: vec![Speed::Blitz],
+            since: Some("2024.01.01".into()),
+            ..Default::default()
+        };
+        let blitz = db.explore(&Chess::default(), &blitz).await.unwrap();
+        assert_eq!(blitz.tally.games, 1);
+        assert_eq!(blitz.percentages, [100.0, 0.0, 0.0]);
+
+        let openings = db.games_by_opening(&Default::default()).await.unwrap();
+        assert_eq!(openings[0].eco, "C44");
+        assert_eq!(openings[0].tally.games, 2);
+        let queens = ExplorerFilter {
+            eco: Some("D".into()),
+            ..Default::default()
+        };
+        let queens = db.explore(&Chess::default(), &queens).await.unwrap();
+        assert_eq!(queens.moves[0].san, "d4");
+        // `_` is not a wildcard.
+        let wildcard = ExplorerFilter {
+            eco: Some("C_4".into()),
+            ..Default::default()
+        };
+        let wildcard = db.explore(&Chess::default(), &wildcard).await.unwrap();
+        assert_eq!(wildcard.tally.games, 0);
+    }
+
+    fn read_first(pgn: &str) -> crate::chess::pgn::Game {
+        crate::chess::pgn::read_games(pgn.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+}
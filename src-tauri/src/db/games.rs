@@ -0,0 +1,592 @@
+//! Stored games and the bulk importer for large PGN files like TWIC weeklies or Lichess dumps.
+//!
+//! The importer parses on a blocking thread and stores games in batches, one transaction each,
+//! reporting progress after every batch. Cancelling keeps the batches already committed.
+
+#[cfg(feature = "desktop")]
+use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use pgn_reader::Reader;
+use shakmaty::Chess;
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection};
+#[cfg(feature = "desktop")]
+use tauri::{ipc::Channel, State};
+use tokio::sync::mpsc;
+use tracing::debug;
+#[cfg(feature = "desktop")]
+use tracing::warn;
+
+use super::{
+    duplicates::{find_duplicate, game_key, resolve, DuplicateAction, DuplicatePolicy},
+    players::record_player,
+    position_hash, Database,
+};
+use crate::chess::{
+    clock::Speed,
+    openings::OpeningBook,
+    pgn::{read_games, set_tags, AnnotatedGame, Game, TreeVisitor},
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Games stored per transaction by the importer.
+pub const IMPORT_BATCH: usize = 1000;
+
+/// At most this many skipped games are listed in an import report; all of them are counted.
+const SKIPPED_LIMIT: usize = 1000;
+
+/// A name with single spaces, as players and events are stored.
+pub fn clean_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A name for comparisons: cleaned and lowercase.
+pub fn normalize_name(name: &str) -> String {
+    clean_name(name).to_lowercase()
+}
+
+/// The id of the player called `name`, added if new.
+async fn player_id(conn: &mut SqliteConnection, name: &str) -> Result<i64> {
+    let name = clean_name(name);
+    let id = sqlx::query_scalar(
+        "insert into player (name) values ($1) \
+         on conflict (name) do update set name = excluded.name returning id",
+    )
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(id)
+}
+
+async fn event_id(conn: &mut SqliteConnection, name: &str) -> Result<i64> {
+    let name = clean_name(name);
+    let id = sqlx::query_scalar(
+        "insert into event (name) values ($1) \
+         on conflict (name) do update set name = excluded.name returning id",
+    )
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(id)
+}
+
+/// Stores a game with its headers and indexes its `positions`, as `id` if given.
+///
+/// The game is classified with `book`, which fills in its `ECO` and `Opening` tags.
+pub(super) async fn add_game(
+    conn: &mut SqliteConnection,
+    id: Option<i64>,
+    game: &Game,
+    positions: &[Chess],
+    pgn: &str,
+    book: &OpeningBook,
+    source: Option<&str>,
+) -> Result<i64> {
+    let class = book.classify(positions);
+    let pgn = match &class {
+        Some(c) => set_tags(pgn, &[("ECO", &c.eco), ("Opening", &c.name)]),
+        None => pgn.to_string(),
+    };
+    let tag = |name| game.tag(name).map(str::to_string);
+    let elo = |name| game.tag(name).and_then(|v| v.parse::<i64>().ok());
+    let white = tag("White").unwrap_or("?".into());
+    let black = tag("Black").unwrap_or("?".into());
+    let white_id = player_id(conn, &white).await?;
+    let black_id = player_id(conn, &black).await?;
+    for (id, side) in [(white_id, "White"), (black_id, "Black")] {
+        let fide_id = game
+            .tag(&format!("{side}FideId"))
+            .and_then(|v| v.parse().ok());
+        record_player(conn, id, fide_id, game.tag(&format!("{side}Fed"))).await?;
+    }
+    let event_id = match game.tag("Event") {
+        Some(event) => Some(event_id(conn, event).await?),
+        None => None,
+    };
+    let movetext = game.movetext();
+
+    let id = sqlx::query(
+        r#"
+        insert into game (
+          white, black, result, termination, event, site, date, round,
+          white_elo, black_elo, time_control, speed, eco, opening, book_ply, pgn,
+          white_id, black_id, event_id, movetext, ply_count, source, match_key, id
+        )
+        values (
+          $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16,
+          $17, $18, $19, $20, $21, $22, $23, $24
+        )
+        "#,
+    )
+    .bind(&white)
+    .bind(&black)
+    .bind(tag("Result").unwrap_or("*".into()))
+    .bind(tag("Termination"))
+    .bind(tag("Event"))
+    .bind(tag("Site"))
+    .bind(tag("Date"))
+    .bind(tag("Round"))
+    .bind(elo("WhiteElo"))
+    .bind(elo("BlackElo"))
+    .bind(tag("TimeControl"))
+    .bind(
+        game.tag("TimeControl")
+            .and_then(Speed::from_tag)
+            .map(|s| s.as_str()),
+    )
+    .bind(class.as_ref().map(|c| c.eco.as_str()))
+    .bind(class.as_ref().map(|c| c.name.as_str()))
+    .bind(
+        class
+            .as_ref()
+            .and_then(|c| c.left_book)
+            .map(|ply| ply as i64),
+    )
+    .bind(&pgn)
+    .bind(white_id)
+    .bind(black_id)
+    .bind(event_id)
+    .bind(&movetext)
+    .bind(game.moves.len() as i64)
+    .bind(source)
+    .bind(game_key(game))
+    .bind(id)
+    .execute(&mut *conn)
+    .await?
+    .last_insert_rowid();
+
+    // Four parameters a row keeps each statement well below SQLite's limit.
+    let rows = positions.iter().enumerate().collect::<Vec<_>>();
+    for chunk in rows.chunks(200) {
+        QueryBuilder::<Sqlite>::new("insert into position (game_id, ply, hash, move) ")
+            .push_values(chunk, |mut row, &(ply, pos)| {
+                row.push_bind(id)
+                    .push_bind(ply as i64)
+                    .push_bind(position_hash(pos))
+                    .push_bind(game.moves.get(ply).map(|m| m.uci.clone()));
+            })
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(id)
+}
+
+/// Replaces the stored game `id` with `game`, keeping its id and source.
+pub(super) async fn replace_game(
+    conn: &mut SqliteConnection,
+    id: i64,
+    game: &AnnotatedGame,
+    book: &OpeningBook,
+) -> Result<()> {
+    let mainline = game.mainline();
+    let positions = mainline.positions()?;
+    let source: Option<String> = sqlx::query_scalar("select source from game where id = $1")
+        .bind(id)
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query("delete from game where id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    add_game(
+        conn,
+        Some(id),
+        &mainline,
+        &positions,
+        &game.to_pgn(),
+        book,
+        source.as_deref(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportProgress {
+    /// Games read so far, skipped ones included.
+    pub read: usize,
+    pub imported: usize,
+    pub duplicates: usize,
+    pub illegal: usize,
+    /// Bytes of PGN read so far, out of `total_bytes` if the size is known.
+    pub bytes: u64,
+    pub total_bytes: Option<u64>,
+}
+
+/// A game the importer left out.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(
+    tag = "reason",
+    rename_all = "lowercase",
+    rename_all_fields = "camelCase"
+)]
+pub enum SkippedGame {
+    /// The game could not be read or has an illegal move or position.
+    Illegal { index: usize, error: String },
+    /// A copy of the game is stored already, as `existing`, and was kept, replaced or merged
+    /// with this one.
+    Duplicate {
+        index: usize,
+        white: String,
+        black: String,
+        date: Option<String>,
+        existing: i64,
+        action: DuplicateAction,
+    },
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    #[serde(flatten)]
+    pub progress: ImportProgress,
+    /// Whether the import was stopped before the end of the file.
+    pub cancelled: bool,
+    /// The first skipped games, by their index in the file from 1.
+    pub skipped: Vec<SkippedGame>,
+}
+
+impl ImportReport {
+    fn skip(&mut self, game: SkippedGame) {
+        if self.skipped.len() < SKIPPED_LIMIT {
+            self.skipped.push(game);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// Stored with every game, e.g. the file name.
+    pub source: Option<String>,
+    /// Games stored per transaction.
+    pub batch: usize,
+    pub duplicates: DuplicatePolicy,
+    /// Classifies the imported games.
+    pub book: Arc<OpeningBook>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            source: None,
+            batch: IMPORT_BATCH,
+            duplicates: DuplicatePolicy::default(),
+            book: OpeningBook::bundled(),
+        }
+    }
+}
+
+/// Counts the bytes read through it, for progress reports.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl Database {
+    /// Stores the first game of `pgn`, classified with `book`.
+    pub async fn insert_pgn(&self, pgn: &str, book: &OpeningBook) -> Result<i64> {
+        let game = read_games(pgn.as_bytes())
+            .next()
+            .context("no game in PGN")??;
+        self.insert_game(&game, pgn, book).await
+    }
+
+    /// Stores a game with its headers and indexes its positions, classified with `book`.
+    pub async fn insert_game(&self, game: &Game, pgn: &str, book: &OpeningBook) -> Result<i64> {
+        let positions = game.positions()?;
+        let mut tx = self.pool.begin().await?;
+        let id = add_game(&mut tx, None, game, &positions, pgn, book, None).await?;
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    /// Imports every game of `reader`, skipping illegal games. Games stored already are
+    /// skipped, replaced or merged as `options.duplicates` says.
+    ///
+    /// `on_progress` is called after each batch. Setting `stop` ends the import after the
+    /// current batch.
+    pub async fn import_games<R: Read + Send + 'static>(
+        &self,
+        reader: R,
+        total_bytes: Option<u64>,
+        options: &ImportOptions,
+        stop: &AtomicBool,
+        mut on_progress: impl FnMut(&ImportProgress),
+    ) -> Result<ImportReport> {
+        self.fill_match_keys().await?;
+        let bytes = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            inner: reader,
+            count: bytes.clone(),
+        };
+        let batch_size = options.batch.max(1);
+        let (game_tx, mut game_rx) = mpsc::channel(batch_size);
+        // Parsing is blocking, so read on a separate thread. It stops once the receiver is gone.
+        // Games are read whole, so that their variations and annotations are stored too.
+        let parser = tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut reader = Reader::new(reader);
+            while let Some(game) = reader.read_game(&mut TreeVisitor)? {
+                if game_tx.blocking_send(game).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let book: &OpeningBook = &options.book;
+        let source = options.source.as_deref();
+        let mut report = ImportReport {
+            progress: ImportProgress {
+                total_bytes,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                report.cancelled = true;
+                break;
+            }
+            // Fill the batch, unless the file ends first.
+            while batch.len() < batch_size {
+                let missing = batch_size - batch.len();
+                if game_rx.recv_many(&mut batch, missing).await == 0 {
+                    break;
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut tx = self.pool.begin().await?;
+            for game in batch.drain(..) {
+                let progress = &mut report.progress;
+                progress.read += 1;
+                let index = progress.read;
+                let parsed = game.and_then(|annotated| {
+                    let game = annotated.mainline();
+                    Ok((game.positions()?, game, annotated))
+                });
+                let (positions, game, annotated) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        progress.illegal += 1;
+                        let error = e.to_string();
+                        report.skip(SkippedGame::Illegal { index, error });
+                        continue;
+                    }
+                };
+                if let Some((existing, movetext)) = find_duplicate(&mut tx, &game).await? {
+                    progress.duplicates += 1;
+                    let action = resolve(
+                        &mut tx,
+                        (existing, &movetext),
+                        &annotated,
+                        options.duplicates,
+                        book,
+                    )
+                    .await?;
+                    let tag = |name| game.tag(name).unwrap_or("?").to_string();
+                    report.skip(SkippedGame::Duplicate {
+                        index,
+                        white: tag("White"),
+                        black: tag("Black"),
+                        date: game.tag("Date").map(str::to_string),
+                        existing,
+                        action,
+                    });
+                    continue;
+                }
+                add_game(
+                    &mut tx,
+                    None,
+                    &game,
+                    &positions,
+                    &annotated.to_pgn(),
+                    book,
+                    source,
+                )
+                .await?;
+                progress.imported += 1;
+            }
+            tx.commit().await?;
+            report.progress.bytes = bytes.load(Ordering::Relaxed);
+            on_progress(&report.progress);
+        }
+        drop(game_rx);
+        parser.await??;
+
+        let p = &report.progress;
+        debug!(
+            imported = p.imported,
+            duplicates = p.duplicates,
+            illegal = p.illegal,
+            cancelled = report.cancelled,
+            "games imported"
+        );
+        Ok(report)
+    }
+}
+
+/// Imports the games of a PGN file, reporting progress on `chan` after every batch.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn import_games(
+    path: PathBuf,
+    source: Option<String>,
+    duplicates: Option<DuplicatePolicy>,
+    chan: Channel<ImportProgress>,
+    state: State<'_, AppState>,
+) -> Result<ImportReport, String> {
+    let stop = state.import_stop.clone();
+    stop.store(false, Ordering::SeqCst);
+    let file = File::open(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let total_bytes = file.metadata().ok().map(|m| m.len());
+    let options = ImportOptions {
+        source: source.or_else(|| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        }),
+        duplicates: duplicates.unwrap_or_default(),
+        book: state.openings.read().unwrap().clone(),
+        ..Default::default()
+    };
+    state
+        .db
+        .import_games(BufReader::new(file), total_bytes, &options, &stop, |p| {
+            if let Err(e) = chan.send(p.clone()) {
+                warn!(cause = %e, "failed to send import progress");
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn stop_import(state: State<'_, AppState>) {
+    state.import_stop.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PGN: &str = r#"[Event "Weekly"]
+[White "Carlsen,  Magnus"]
+[Black "Nakamura, Hikaru"]
+[Date "2024.01.05"]
+[Round "1"]
+[Result "1-0"]
+
+1. e4 $1 { [%clk 0:03:00] } 1... e5 ( 1... c5 { Sicilian } ) 2. Nf3 Nc6 3. Bb5 a6 1-0
+
+[Event "Weekly"]
+[White "Nakamura, Hikaru"]
+[Black "Carlsen, Magnus"]
+[Result "0-1"]
+
+1. e4 e5 2. Ke3 0-1
+
+[Event "Weekly"]
+[White "Nakamura, Hikaru"]
+[Black "Carlsen, Magnus"]
+[Result "1/2-1/2"]
+
+1. d4 d5 1/2-1/2
+
+[Event "weekly"]
+[White "carlsen, magnus"]
+[Black "Nakamura, Hikaru"]
+[Date "2024.01.05"]
+[Round "1"]
+[Result "1-0"]
+
+1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 1-0
+"#;
+
+    #[tokio::test]
+    async fn bulk_import() {
+        let db = Database::memory().await.unwrap();
+        let options = ImportOptions {
+            source: Some("weekly.pgn".into()),
+            batch: 2,
+            ..Default::default()
+        };
+        let mut updates = Vec::new();
+        let report = db
+            .import_games(
+                PGN.as_bytes(),
+                Some(PGN.len() as u64),
+                &options,
+                &AtomicBool::new(false),
+                |p| updates.push(p.clone()),
+            )
+            .await
+            .unwrap();
+        let p = &report.progress;
+        assert_eq!((p.read, p.imported, p.duplicates, p.illegal), (4, 2, 1, 1));
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates.last().unwrap().bytes, PGN.len() as u64);
+        assert!(matches!(
+            report.skipped[..],
+            [
+                SkippedGame::Illegal { index: 2, .. },
+                SkippedGame::Duplicate {
+                    index: 4,
+                    existing: 1,
+                    action: DuplicateAction::Skipped,
+                    ..
+                }
+            ]
+        ));
+
+        let (movetext, ply_count, source, pgn): (String, i64, String, String) =
+            sqlx::query_as("select movetext, ply_count, source, pgn from game where id = 1")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(movetext, "e4 e5 Nf3 Nc6 Bb5 a6");
+        assert_eq!((ply_count, source.as_str()), (6, "weekly.pgn"));
+        assert!(pgn.contains("1. e4 $1 { [%clk 0:03:00] } 1... e5 ( 1... c5 { Sicilian } ) 2. Nf3"));
+        assert!(pgn.contains("[ECO \"C"));
+        let players: i64 = sqlx::query_scalar("select count(*) from player")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(players, 2);
+
+        // Cancelled before the first batch, nothing is stored.
+        let report = db
+            .import_games(
+                PGN.as_bytes(),
+                None,
+                &options,
+                &AtomicBool::new(true),
+                |_| {},
+            )
+            .await
+            .unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.progress.read, 0);
+    }
+}
@@ -1,13 +1,42 @@
 use anyhow::Context;
+use shakmaty::Chess;
 use sqlx::{
-    migrate::MigrateDatabase, sqlite::SqliteQueryResult, Executor, Pool, Sqlite, SqlitePool,
+    migrate::{MigrateDatabase, Migrator},
+    sqlite::SqliteQueryResult,
+    Executor, Pool, Sqlite, SqlitePool,
 };
 use std::path::Path;
+#[cfg(feature = "desktop")]
 use tauri::State;
 use tracing::{debug, trace};
 
+use crate::chess::{
+    openings::{position_key, OpeningBook},
+    pgn::{read_games, set_tags},
+};
+#[cfg(feature = "desktop")]
 use crate::AppState;
 
+pub mod drill;
+pub mod duplicates;
+pub mod explorer;
+pub mod games;
+pub mod pattern;
+pub mod players;
+pub mod repertoire;
+pub mod search;
+pub mod study;
+
+/// The migrations in `migrations/`, embedded at build time. Applied versions are recorded in
+/// the `_sqlx_migrations` table.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The position hash stored in the `position` table.
+pub fn position_hash(pos: &Chess) -> i64 {
+    position_key(pos).0 as i64
+}
+
+#[derive(Clone)]
 pub struct Database {
     pool: Pool<Sqlite>,
 }
@@ -28,82 +57,153 @@ impl Database {
         Ok(Self { pool })
     }
 
+    /// A private in-memory database, migrated.
+    #[cfg(test)]
+    pub async fn memory() -> anyhow::Result<Self> {
+        // Every connection to `:memory:` opens a new database, so keep to one.
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        let db = Self { pool };
+        db.migrate().await?;
+        Ok(db)
+    }
+
     pub async fn connect_and_migrate(path: impl AsRef<Path>) -> anyhow::Result<Self> {
         let db = Self::connect(path).await?;
-        for file in std::fs::read_dir("migrations")?
-            .filter_map(|e| e.ok().map(|e| e.path()))
-            .filter(|path| {
-                path.file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .chars()
-                    .next()
-                    .unwrap()
-                    .is_ascii_digit()
-            })
-        {
-            sqlx::raw_sql(std::fs::read_to_string(&file)?.as_str())
-                .execute(&db.pool)
-                .await?;
-            trace!(?file, "migrated");
-        }
+        db.migrate().await?;
         Ok(db)
     }
 
+    /// Applies the migrations the database does not have yet, each once and in a transaction.
+    async fn migrate(&self) -> anyhow::Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        debug!("database migrated");
+        Ok(())
+    }
+
     pub async fn execute_raw(&self, raw_sql: &str) -> anyhow::Result<SqliteQueryResult> {
         Ok(sqlx::raw_sql(raw_sql).execute(&self.pool).await?)
     }
-}
 
-#[derive(Debug, sqlx::FromRow, serde::Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Study {
-    id: i32,
-    name: String,
-    tree_json: String,
-    created_at: String,
-    updated_at: String,
+    /// Up to `limit` stored games with an id above `after`, as `(id, pgn)` in id order.
+    pub async fn pgn_batch(&self, after: i64, limit: i64) -> anyhow::Result<Vec<(i64, String)>> {
+        let rows = sqlx::query_as("select id, pgn from game where id > $1 order by id limit $2")
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows)
+    }
+
+    /// Classifies the stored games with `book`, only those without an opening unless `all`.
+    /// Returns the number of games classified.
+    pub async fn classify_games(&self, book: &OpeningBook, all: bool) -> anyhow::Result<u64> {
+        let mut after = 0;
+        let mut classified = 0;
+        loop {
+            let batch: Vec<(i64, String)> = sqlx::query_as(
+                "select id, pgn from game where id > $1 and ($2 or eco is null) order by id limit 500",
+            )
+            .bind(after)
+            .bind(all)
+            .fetch_all(&self.pool)
+            .await?;
+            let Some(&(last, _)) = batch.last() else {
+                break;
+            };
+            after = last;
+
+            let mut tx = self.pool.begin().await?;
+            for (id, pgn) in batch {
+                let Some(Ok(game)) = read_games(pgn.as_bytes()).next() else {
+                    continue;
+                };
+                let Some(c) = book.classify_game(&game) else {
+                    continue;
+                };
+                sqlx::query(
+                    "update game set eco = $1, opening = $2, book_ply = $3, pgn = $4 where id = $5",
+                )
+                .bind(&c.eco)
+                .bind(&c.name)
+                .bind(c.left_book.map(|ply| ply as i64))
+                .bind(set_tags(&pgn, &[("ECO", &c.eco), ("Opening", &c.name)]))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+                classified += 1;
+            }
+            tx.commit().await?;
+        }
+        Ok(classified)
+    }
 }
 
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct NewStudy {
-    name: String,
-    tree_json: String,
+/// Classifies the stored games with the current opening book, all of them or only those
+/// without an opening yet.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn classify_games(all: bool, state: State<'_, AppState>) -> Result<u64, String> {
+    let book = state.openings.read().unwrap().clone();
+    state
+        .db
+        .classify_games(&book, all)
+        .await
+        .map_err(|e| e.to_string())
 }
 
 pub type Json = serde_json::Map<String, serde_json::Value>;
 
-#[tauri::command]
-pub async fn insert_study(study: NewStudy, state: State<'_, AppState>) -> Result<i64, String> {
-    let id = sqlx::query(
-        r#"
-        insert into study (name, tree_json)
-        values ($1, $2)
-        "#,
-    )
-    .bind(study.name)
-    .bind(study.tree_json)
-    .execute(&state.db.pool)
-    .await
-    .unwrap()
-    .last_insert_rowid();
-
-    Ok(id)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[tauri::command]
-pub async fn get_studies(state: State<'_, AppState>) -> Result<Vec<Study>, String> {
-    let studies = sqlx::query_as::<_, Study>("select * from study")
-        .fetch_all(&state.db.pool)
+    #[tokio::test]
+    async fn upgrade_old_database() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        // The schema the old runner left behind, with no record of what was applied.
+        sqlx::raw_sql(
+            r#"
+            create table study (
+              id integer primary key,
+              name text not null,
+              tree_json text not null,
+              created_at text default current_timestamp,
+              updated_at text default current_timestamp
+            ) strict;
+            insert into study (name, tree_json) values ('old', '[]');
+            "#,
+        )
+        .execute(&pool)
         .await
         .unwrap();
-    Ok(studies)
-}
 
-#[tauri::command]
-pub async fn update_study(state: State<'_, AppState>) -> Result<(), String> {
-    state.db.execute_raw("insert").await.unwrap();
-    Ok(())
+        let db = Database { pool };
+        db.migrate().await.unwrap();
+        let studies: i64 = sqlx::query_scalar("select count(*) from study")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(studies, 1);
+        let applied = || async {
+            sqlx::query_scalar::<_, i64>("select count(*) from _sqlx_migrations where success")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(applied().await, MIGRATOR.iter().count() as i64);
+
+        // Nothing is run twice.
+        db.migrate().await.unwrap();
+        assert_eq!(applied().await, MIGRATOR.iter().count() as i64);
+        db.insert_pgn("1. e4 e5 *", &OpeningBook::bundled())
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,211 @@
+//! Material and pattern search over the stored games, replaying each one.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use shakmaty::{fen::Fen, EnPassantMode};
+use sqlx::{QueryBuilder, Sqlite};
+#[cfg(feature = "desktop")]
+use tauri::{ipc::Channel, State};
+#[cfg(feature = "desktop")]
+use tracing::warn;
+
+use super::{
+    explorer::GameFilter,
+    search::{GameQuery, GameRow, ROW},
+    Database,
+};
+use crate::chess::{pattern::Matcher, pgn::read_games};
+#[cfg(feature = "desktop")]
+use crate::{chess::pattern::Pattern, AppState};
+
+/// Games replayed per batch, between progress events.
+const BATCH: i64 = 500;
+
+#[derive(sqlx::FromRow)]
+struct ScanRow {
+    #[sqlx(flatten)]
+    game: GameRow,
+    pgn: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(
+    tag = "event",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum PatternEvent {
+    /// A game with the pattern, first reached at `ply` in the position `fen`.
+    Hit {
+        game: Box<GameRow>,
+        ply: usize,
+        fen: String,
+    },
+    /// Sent after every batch of games.
+    Progress {
+        scanned: u64,
+        total: u64,
+        found: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatternReport {
+    pub scanned: u64,
+    pub found: u64,
+    /// Whether the search was stopped before the last game.
+    pub cancelled: bool,
+}
+
+impl Database {
+    /// Replays the games matching `query` in id order, reporting each one where `matcher` finds
+    /// its pattern. The query's sorting and paging are ignored. Setting `stop` ends the search
+    /// after the current batch.
+    pub async fn pattern_search(
+        &self,
+        matcher: &Matcher,
+        query: &GameQuery,
+        stop: &AtomicBool,
+        mut on_event: impl FnMut(PatternEvent),
+    ) -> Result<PatternReport> {
+        let total = self.count_games(query).await? as u64;
+        let mut report = PatternReport::default();
+        let mut after = 0;
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                report.cancelled = true;
+                break;
+            }
+            let mut q = QueryBuilder::<Sqlite>::new(format!(
+                "select {ROW}, null as ply, g.pgn from game g where g.id > "
+            ));
+            q.push_bind(after);
+            query.push(&mut q)?;
+            q.push(" order by g.id limit ").push_bind(BATCH);
+            let rows: Vec<ScanRow> = q.build_query_as().fetch_all(&self.pool).await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            after = last.game.id;
+            report.scanned += rows.len() as u64;
+
+            // Replaying is CPU-bound, so keep it off the async workers.
+            let matcher = matcher.clone();
+            let hits = tokio::task::spawn_blocking(move || {
+                rows.into_iter()
+                    .filter_map(|row| {
+                        let game = read_games(row.pgn.as_bytes()).next()?.ok()?;
+                        let positions = game.positions().ok()?;
+                        let ply = matcher.find(&positions)?;
+                        let fen = Fen::from_position(&positions[ply], EnPassantMode::Legal);
+                        Some(PatternEvent::Hit {
+                            game: Box::new(row.game),
+                            ply,
+                            fen: fen.to_string(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await?;
+            report.found += hits.len() as u64;
+            hits.into_iter().for_each(&mut on_event);
+            on_event(PatternEvent::Progress {
+                scanned: report.scanned,
+                total,
+                found: report.found,
+            });
+        }
+        Ok(report)
+    }
+}
+
+/// Streams the games matching `query` that reach `pattern` on `chan`, with progress after every
+/// batch.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn search_pattern(
+    pattern: Pattern,
+    query: GameQuery,
+    chan: Channel<PatternEvent>,
+    state: State<'_, AppState>,
+) -> Result<PatternReport, String> {
+    let matcher = Matcher::new(&pattern).map_err(|e| e.to_string())?;
+    let stop = state.pattern_stop.clone();
+    stop.store(false, Ordering::SeqCst);
+    state
+        .db
+        .pattern_search(&matcher, &query, &stop, |event| {
+            if let Err(e) = chan.send(event) {
+                warn!(cause = %e, "failed to send pattern search event");
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub fn stop_pattern_search(state: State<'_, AppState>) {
+    state.pattern_stop.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::{
+        openings::OpeningBook,
+        pattern::{Condition, Pattern},
+    };
+
+    #[tokio::test]
+    async fn rook_endings() {
+        let db = Database::memory().await.unwrap();
+        for pgn in [
+            "[White \"Alice\"]\n[Black \"Bob\"]\n\n1. e4 e5 2. Nf3 Nc6 *\n",
+            "[White \"Alice\"]\n[Black \"Carol\"]\n[SetUp \"1\"]\n[FEN \"8/8/4k3/8/3P4/8/1r6/3RK3 w - - 0 50\"]\n\n50. d5+ Kd6 51. Rd3 Rb5 52. Kf2 Rxd5 53. Rxd5+ Kxd5 *\n",
+            "[White \"Bob\"]\n[Black \"Carol\"]\n[SetUp \"1\"]\n[FEN \"8/8/4k3/8/3P4/8/1r6/3RKB2 w - - 0 50\"]\n\n50. d5+ Kd6 51. Rd3 *\n",
+        ] {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+        let pattern = Pattern {
+            conditions: vec![Condition::Material {
+                material: "RPvR".into(),
+                either_color: true,
+            }],
+            min_plies: 2,
+        };
+        let matcher = Matcher::new(&pattern).unwrap();
+        let mut events = Vec::new();
+        let report = db
+            .pattern_search(
+                &matcher,
+                &GameQuery::default(),
+                &AtomicBool::new(false),
+                |event| events.push(event),
+            )
+            .await
+            .unwrap();
+        assert_eq!((report.scanned, report.found), (3, 1));
+        let [PatternEvent::Hit { game, ply, .. }, PatternEvent::Progress { total, .. }] =
+            &events[..]
+        else {
+            panic!("unexpected events {events:?}");
+        };
+        assert_eq!(
+            (game.id, game.black.as_str(), *ply, *total),
+            (2, "Carol", 0, 3)
+        );
+
+        let bob = GameQuery {
+            player: Some("bob".into()),
+            ..Default::default()
+        };
+        let report = db
+            .pattern_search(&matcher, &bob, &AtomicBool::new(false), |_| {})
+            .await
+            .unwrap();
+        assert_eq!((report.scanned, report.found), (2, 0));
+    }
+}
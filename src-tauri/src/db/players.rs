@@ -0,0 +1,566 @@
+//! Player profiles: the names a player appears under, their FIDE ID, federation and ratings
+//! from game headers, and their results from the stored games.
+//!
+//! Every distinct name is a player. Other names of the same person, like "Carlsen, M" or an
+//! online handle, are aliases of one profile. Names sharing a FIDE ID are linked on import;
+//! others are linked by hand, helped by [`Database::alias_suggestions`].
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::{
+    explorer::{GameFilter, Side},
+    games::normalize_name,
+    search::{like_escape, GameQuery},
+    Database,
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Players found by name when the caller does not say.
+const FOUND: i64 = 50;
+/// Openings listed in player statistics.
+const OPENINGS: usize = 30;
+
+/// The ids of the profile `$1` and its aliases.
+const NAMES: &str = "(select id from player where id = $1 or alias_of = $1)";
+
+/// Surname and first initial, so that "Carlsen, M", "Carlsen, Magnus" and "Magnus Carlsen"
+/// agree. A single word, like an online handle, is its own key.
+pub fn name_key(name: &str) -> String {
+    let name = normalize_name(name);
+    let (surname, given) = match name.split_once(',') {
+        Some((surname, given)) => (surname.trim(), given.trim()),
+        None => match name.rsplit_once(' ') {
+            Some((given, surname)) => (surname, given),
+            None => (name.as_str(), ""),
+        },
+    };
+    match given.chars().next() {
+        Some(initial) => format!("{surname} {initial}"),
+        None => surname.to_string(),
+    }
+}
+
+/// Records the FIDE ID and federation from the headers of one of `id`'s games. A new name with
+/// the FIDE ID of an older profile becomes its alias.
+pub(super) async fn record_player(
+    conn: &mut SqliteConnection,
+    id: i64,
+    fide_id: Option<i64>,
+    federation: Option<&str>,
+) -> Result<()> {
+    let federation = federation.filter(|f| !f.is_empty() && *f != "?");
+    if fide_id.is_none() && federation.is_none() {
+        return Ok(());
+    }
+    // Players change federation, so the latest one is kept.
+    sqlx::query(
+        "update player set fide_id = coalesce(fide_id, $1), federation = coalesce($2, federation) \
+         where id = $3",
+    )
+    .bind(fide_id)
+    .bind(federation)
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+    if let Some(fide_id) = fide_id {
+        sqlx::query(
+            "update player set alias_of = \
+               (select coalesce(alias_of, id) from player where fide_id = $1 and id < $2 \
+                order by id limit 1) \
+             where id = $2 and alias_of is null \
+               and not exists (select 1 from player where alias_of = $2)",
+        )
+        .bind(fide_id)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerName {
+    pub id: i64,
+    pub name: String,
+}
+
+/// A player's rating in the headers of their games on `date`, the highest if several.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RatingPoint {
+    pub date: String,
+    pub rating: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Player {
+    pub id: i64,
+    pub name: String,
+    pub fide_id: Option<i64>,
+    pub federation: Option<String>,
+    pub aliases: Vec<PlayerName>,
+    /// Games under any of the player's names.
+    pub games: i64,
+    pub ratings: Vec<RatingPoint>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Score {
+    pub games: i64,
+    pub wins: i64,
+    pub draws: i64,
+    pub losses: i64,
+    /// Wins plus half the draws.
+    pub points: f64,
+}
+
+impl Score {
+    fn add(&mut self, points: f64) {
+        self.games += 1;
+        self.points += points;
+        match points {
+            1.0 => self.wins += 1,
+            0.5 => self.draws += 1,
+            _ => self.losses += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningScore {
+    pub color: Side,
+    pub eco: Option<String>,
+    pub opening: Option<String>,
+    #[serde(flatten)]
+    pub score: Score,
+}
+
+/// The rating the player scored at, over the games with a rated opponent.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Performance {
+    pub rating: i64,
+    pub games: i64,
+    pub average_opponent: i64,
+}
+
+impl Performance {
+    /// The average opponent plus the rating difference the score is expected at, at most 800
+    /// either way for a perfect or zero score.
+    fn new(points: f64, opponents: &[i64]) -> Option<Self> {
+        let games = opponents.len() as i64;
+        if games == 0 {
+            return None;
+        }
+        let average = opponents.iter().sum::<i64>() as f64 / games as f64;
+        let score = points / games as f64;
+        let difference = match score {
+            s if s >= 1.0 => 800.0,
+            s if s <= 0.0 => -800.0,
+            s => (400.0 * (s / (1.0 - s)).log10()).clamp(-800.0, 800.0),
+        };
+        Some(Self {
+            rating: (average + difference).round() as i64,
+            games,
+            average_opponent: average.round() as i64,
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStats {
+    pub white: Score,
+    pub black: Score,
+    /// The player's most played openings with either colour, most played first.
+    pub openings: Vec<OpeningScore>,
+    pub performance: Option<Performance>,
+}
+
+/// A finished game from the player's side.
+#[derive(Debug, sqlx::FromRow)]
+struct Played {
+    color: Side,
+    eco: Option<String>,
+    opening: Option<String>,
+    result: String,
+    opponent: Option<i64>,
+}
+
+impl Played {
+    fn points(&self) -> Option<f64> {
+        match (self.result.as_str(), self.color) {
+            ("1/2-1/2", _) => Some(0.5),
+            ("1-0", Side::White) | ("0-1", Side::Black) => Some(1.0),
+            ("1-0", Side::Black) | ("0-1", Side::White) => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+impl Database {
+    /// The profile `id` belongs to, itself if it has no other.
+    async fn profile_id(&self, id: i64) -> Result<i64> {
+        let profile = sqlx::query_scalar("select coalesce(alias_of, id) from player where id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        match profile {
+            Some(profile) => Ok(profile),
+            None => bail!("no player {id}"),
+        }
+    }
+
+    /// Profiles with a name or alias containing `name`, compared case-insensitively.
+    pub async fn find_players(&self, name: &str, limit: Option<i64>) -> Result<Vec<PlayerName>> {
+        let players = sqlx::query_as(
+            "select id, name from player where alias_of is null and id in \
+               (select coalesce(alias_of, id) from player where name like $1 escape '\\') \
+             order by name collate nocase limit $2",
+        )
+        .bind(format!("%{}%", like_escape(name.trim())))
+        .bind(limit.unwrap_or(FOUND).max(1))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(players)
+    }
+
+    pub async fn player(&self, id: i64) -> Result<Player> {
+        let id = self.profile_id(id).await?;
+        let (name, fide_id, federation): (String, Option<i64>, Option<String>) =
+            sqlx::query_as("select name, fide_id, federation from player where id = $1")
+                .bind(id)
+                .fetch_one(&self.pool)
+                .await?;
+        let aliases = sqlx::query_as(
+            "select id, name from player where alias_of = $1 order by name collate nocase",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        let games = sqlx::query_scalar(&format!(
+            "select count(*) from game where white_id in {NAMES} or black_id in {NAMES}"
+        ))
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        let ratings = sqlx::query_as(&format!(
+            "select date, max(rating) as rating from ( \
+               select date, white_elo as rating from game where white_id in {NAMES} \
+               union all \
+               select date, black_elo from game where black_id in {NAMES} \
+             ) where date is not null and rating is not null group by date order by date"
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Player {
+            id,
+            name,
+            fide_id,
+            federation,
+            aliases,
+            games,
+            ratings,
+        })
+    }
+
+    /// Updates what the headers may not say, e.g. for an online handle.
+    pub async fn set_player_details(
+        &self,
+        id: i64,
+        fide_id: Option<i64>,
+        federation: Option<&str>,
+    ) -> Result<()> {
+        let id = self.profile_id(id).await?;
+        sqlx::query("update player set fide_id = $1, federation = $2 where id = $3")
+            .bind(fide_id)
+            .bind(federation)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Makes `id` and its aliases aliases of `profile`'s profile, or a profile of its own
+    /// again if `profile` is `None`.
+    pub async fn set_player_alias(&self, id: i64, profile: Option<i64>) -> Result<()> {
+        match profile {
+            Some(profile) => {
+                let profile = self.profile_id(profile).await?;
+                if profile == self.profile_id(id).await? {
+                    bail!("player {id} is already an alias of {profile}");
+                }
+                sqlx::query("update player set alias_of = $1 where id = $2 or alias_of = $2")
+                    .bind(profile)
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            None => {
+                sqlx::query("update player set alias_of = null where id = $1")
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Other players whose name has the same [`name_key`] as one of the profile's names.
+    pub async fn alias_suggestions(&self, id: i64) -> Result<Vec<PlayerName>> {
+        let id = self.profile_id(id).await?;
+        let names: Vec<PlayerName> =
+            sqlx::query_as("select id, name from player where id = $1 or alias_of = $1")
+                .bind(id)
+                .fetch_all(&self.pool)
+                .await?;
+        let mut suggestions: Vec<PlayerName> = Vec::new();
+        for name in &names {
+            let key = name_key(&name.name);
+            let surname = key.split(' ').next().unwrap_or_default();
+            let candidates: Vec<PlayerName> = sqlx::query_as(
+                "select id, name from player \
+                 where name like $1 escape '\\' and coalesce(alias_of, id) != $2 order by id",
+            )
+            .bind(format!("%{}%", like_escape(surname)))
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+            for candidate in candidates {
+                if name_key(&candidate.name) == key && !suggestions.contains(&candidate) {
+                    suggestions.push(candidate);
+                }
+            }
+        }
+        Ok(suggestions)
+    }
+
+    /// Results of the player under any of their names in the games matching `query`, whose
+    /// player and colour are ignored.
+    pub async fn player_stats(&self, id: i64, query: &GameQuery) -> Result<PlayerStats> {
+        let id = self.profile_id(id).await?;
+        let query = GameQuery {
+            player: None,
+            color: None,
+            ..query.clone()
+        };
+        let mut q = QueryBuilder::<Sqlite>::new("");
+        for (i, (side, player, opponent)) in
+            [("white", "white", "black"), ("black", "black", "white")]
+                .into_iter()
+                .enumerate()
+        {
+            if i > 0 {
+                q.push(" union all ");
+            }
+            q.push(format!(
+                "select '{side}' as color, g.eco, g.opening, g.result, \
+                 g.{opponent}_elo as opponent from game g where g.{player}_id in \
+                 (select id from player where id = "
+            ));
+            q.push_bind(id)
+                .push(" or alias_of = ")
+                .push_bind(id)
+                .push(")");
+            query.push(&mut q)?;
+        }
+        let games: Vec<Played> = q.build_query_as().fetch_all(&self.pool).await?;
+
+        let (mut white, mut black) = (Score::default(), Score::default());
+        let mut openings: HashMap<(Side, Option<String>, Option<String>), Score> = HashMap::new();
+        let mut points = 0.0;
+        let mut opponents = Vec::new();
+        for game in games {
+            let Some(p) = game.points() else {
+                continue;
+            };
+            match game.color {
+                Side::White => white.add(p),
+                Side::Black => black.add(p),
+            }
+            if let Some(opponent) = game.opponent {
+                points += p;
+                opponents.push(opponent);
+            }
+            openings
+                .entry((game.color, game.eco, game.opening))
+                .or_default()
+                .add(p);
+        }
+        let mut openings = openings
+            .into_iter()
+            .map(|((color, eco, opening), score)| OpeningScore {
+                color,
+                eco,
+                opening,
+                score,
+            })
+            .collect::<Vec<_>>();
+        openings.sort_by(|a, b| {
+            b.score
+                .games
+                .cmp(&a.score.games)
+                .then_with(|| a.eco.cmp(&b.eco))
+        });
+        openings.truncate(OPENINGS);
+        Ok(PlayerStats {
+            white,
+            black,
+            openings,
+            performance: Performance::new(points, &opponents),
+        })
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn find_players(
+    name: String,
+    limit: Option<i64>,
+    state: State<'_, AppState>,
+) -> Result<Vec<PlayerName>, String> {
+    state
+        .db
+        .find_players(&name, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn get_player(id: i64, state: State<'_, AppState>) -> Result<Player, String> {
+    state.db.player(id).await.map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn set_player_details(
+    id: i64,
+    fide_id: Option<i64>,
+    federation: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .db
+        .set_player_details(id, fide_id, federation.as_deref())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn set_player_alias(
+    id: i64,
+    profile: Option<i64>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .db
+        .set_player_alias(id, profile)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn player_alias_suggestions(
+    id: i64,
+    state: State<'_, AppState>,
+) -> Result<Vec<PlayerName>, String> {
+    state
+        .db
+        .alias_suggestions(id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn player_stats(
+    id: i64,
+    query: GameQuery,
+    state: State<'_, AppState>,
+) -> Result<PlayerStats, String> {
+    state
+        .db
+        .player_stats(id, &query)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::openings::OpeningBook;
+
+    const GAMES: [&str; 4] = [
+        "[Event \"Norway Chess\"]\n[White \"Carlsen, Magnus\"]\n[Black \"Caruana, Fabiano\"]\n[Date \"2023.06.01\"]\n[Result \"1-0\"]\n[WhiteElo \"2853\"]\n[BlackElo \"2764\"]\n[WhiteFideId \"1503014\"]\n[WhiteFed \"NOR\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bb5 1-0\n",
+        "[Event \"Olympiad\"]\n[White \"Caruana, Fabiano\"]\n[Black \"Carlsen, M\"]\n[Date \"2024.01.10\"]\n[Result \"1/2-1/2\"]\n[WhiteElo \"2804\"]\n[BlackElo \"2830\"]\n[BlackFideId \"1503014\"]\n\n1. d4 Nf6 1/2-1/2\n",
+        "[Event \"Titled Arena\"]\n[White \"Firouzja2003\"]\n[Black \"DrNykterstein\"]\n[Date \"2024.02.01\"]\n[Result \"0-1\"]\n[WhiteElo \"3100\"]\n[BlackElo \"3200\"]\n\n1. e4 c5 0-1\n",
+        "[Event \"Simul\"]\n[White \"Magnus Carlsen\"]\n[Black \"Doe, Jane\"]\n[Date \"2024.03.01\"]\n[Result \"*\"]\n\n1. e4 *\n",
+    ];
+
+    #[tokio::test]
+    async fn profiles() {
+        let db = Database::memory().await.unwrap();
+        for pgn in GAMES {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+        assert_eq!(name_key("Carlsen,  M."), name_key("Magnus Carlsen"));
+        assert_eq!(name_key("DrNykterstein"), "drnykterstein");
+
+        let found = db.find_players("carlsen, m", None).await.unwrap();
+        assert_eq!(found.len(), 1, "the FIDE ID links both names: {found:?}");
+        let carlsen = found[0].id;
+        let suggested = db.alias_suggestions(carlsen).await.unwrap();
+        assert_eq!(suggested.len(), 1);
+        assert_eq!(suggested[0].name, "Magnus Carlsen");
+        let handle = db.find_players("nykter", None).await.unwrap()[0].id;
+        db.set_player_alias(handle, Some(carlsen)).await.unwrap();
+        assert!(db.set_player_alias(handle, Some(carlsen)).await.is_err());
+
+        let player = db.player(handle).await.unwrap();
+        assert_eq!(
+            (player.id, player.name.as_str()),
+            (carlsen, "Carlsen, Magnus")
+        );
+        assert_eq!(
+            (player.fide_id, player.federation.as_deref()),
+            (Some(1_503_014), Some("NOR"))
+        );
+        assert_eq!(player.aliases.len(), 2);
+        assert_eq!(player.games, 3);
+        let ratings = player.ratings.iter().map(|r| r.rating).collect::<Vec<_>>();
+        assert_eq!(ratings, [2853, 2830, 3200]);
+
+        let stats = db
+            .player_stats(carlsen, &GameQuery::default())
+            .await
+            .unwrap();
+        assert_eq!((stats.white.games, stats.white.wins), (1, 1));
+        assert_eq!((stats.black.games, stats.black.points), (2, 1.5));
+        assert_eq!(stats.openings.len(), 3);
+        let performance = stats.performance.unwrap();
+        assert_eq!(performance.average_opponent, 2889);
+        // A score of 2.5 out of 3 is about 280 points above the opponents.
+        assert_eq!(performance.rating, 2889 + 280);
+
+        let since_2024 = GameQuery {
+            since: Some("2024.01.01".into()),
+            ..Default::default()
+        };
+        let stats = db.player_stats(carlsen, &since_2024).await.unwrap();
+        assert_eq!(stats.white.games + stats.black.games, 2);
+    }
+}
@@ -0,0 +1,826 @@
+//! Opening repertoires: the moves a player has prepared for one colour.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::ControlFlow,
+};
+
+use anyhow::{anyhow, Result};
+use pgn_reader::{RawComment, RawTag, Reader, SanPlus, Skip};
+use shakmaty::{
+    fen::Fen, san::San, uci::UciMove, CastlingMode, Chess, EnPassantMode, Move, Position,
+};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::{
+    explorer::{ExplorerFilter, Side},
+    position_hash, Database,
+};
+use crate::chess::{
+    analysis::{terminal_score, Judgement, CP_CEILING},
+    openings::OpeningBook,
+    pgn::strip_commands,
+    Engine, Go,
+};
+#[cfg(feature = "desktop")]
+use crate::{chess::matches::EngineConfig, AppState};
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Repertoire {
+    pub id: i64,
+    pub name: String,
+    pub color: Side,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepertoireMove {
+    pub id: i64,
+    /// Position the move is played from.
+    pub fen: String,
+    pub uci: String,
+    pub san: String,
+    pub comment: Option<String>,
+}
+
+/// The moves of a repertoire by the hash of the position they are played from.
+pub struct RepertoireTree {
+    pub color: Side,
+    moves: HashMap<i64, Vec<RepertoireMove>>,
+}
+
+impl RepertoireTree {
+    pub fn moves(&self, pos: &Chess) -> &[RepertoireMove] {
+        self.moves
+            .get(&position_hash(pos))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn contains(&self, pos: &Chess) -> bool {
+        self.moves.contains_key(&position_hash(pos))
+    }
+
+    pub fn len(&self) -> usize {
+        self.moves.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    /// Every move of the repertoire, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &RepertoireMove> {
+        self.moves.values().flatten()
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepertoireImport {
+    pub games: usize,
+    /// Moves that were not in the repertoire yet.
+    pub added: u64,
+}
+
+/// A move of a PGN line with the position it was played from.
+struct LineMove {
+    before: Chess,
+    m: Move,
+    comment: Option<String>,
+}
+
+/// Collects every move of a game, variations included.
+struct LineVisitor;
+
+struct Lines {
+    pos: Chess,
+    /// Position before the last move, where a variation branches off.
+    prev: Chess,
+    /// Index of the last move of the current line, for comments.
+    last: Option<usize>,
+    /// `(pos, prev, last)` of the enclosing lines.
+    stack: Vec<(Chess, Chess, Option<usize>)>,
+    moves: Vec<LineMove>,
+}
+
+impl pgn_reader::Visitor for LineVisitor {
+    type Tags = Option<String>;
+    type Movetext = Lines;
+    type Output = Result<Vec<LineMove>>;
+
+    fn begin_tags(&mut self) -> ControlFlow<Self::Output, Self::Tags> {
+        ControlFlow::Continue(None)
+    }
+
+    fn tag(
+        &mut self,
+        fen: &mut Self::Tags,
+        name: &[u8],
+        value: RawTag<'_>,
+    ) -> ControlFlow<Self::Output> {
+        if name == b"FEN" {
+            *fen = Some(value.decode_utf8_lossy().into_owned());
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn begin_movetext(&mut self, fen: Self::Tags) -> ControlFlow<Self::Output, Self::Movetext> {
+        let pos = match fen {
+            Some(fen) => fen
+                .parse::<Fen>()
+                .map_err(anyhow::Error::from)
+                .and_then(|fen| Ok(fen.into_position(CastlingMode::Standard)?)),
+            None => Ok(Chess::default()),
+        };
+        match pos {
+            Ok(pos) => ControlFlow::Continue(Lines {
+                prev: pos.clone(),
+                pos,
+                last: None,
+                stack: Vec::new(),
+                moves: Vec::new(),
+            }),
+            Err(e) => ControlFlow::Break(Err(e)),
+        }
+    }
+
+    fn san(&mut self, lines: &mut Self::Movetext, san_plus: SanPlus) -> ControlFlow<Self::Output> {
+        match san_plus.san.to_move(&lines.pos) {
+            Ok(m) => {
+                lines.prev = lines.pos.clone();
+                lines.pos.play_unchecked(m);
+                lines.last = Some(lines.moves.len());
+                lines.moves.push(LineMove {
+                    before: lines.prev.clone(),
+                    m,
+                    comment: None,
+                });
+                ControlFlow::Continue(())
+            }
+            Err(e) => ControlFlow::Break(Err(anyhow!("illegal move {san_plus}: {e}"))),
+        }
+    }
+
+    fn comment(
+        &mut self,
+        lines: &mut Self::Movetext,
+        comment: RawComment<'_>,
+    ) -> ControlFlow<Self::Output> {
+        if let Some(m) = lines.last.and_then(|i| lines.moves.get_mut(i)) {
+            let text = strip_commands(&String::from_utf8_lossy(comment.as_bytes()));
+            if !text.is_empty() {
+                m.comment = Some(text);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn begin_variation(&mut self, lines: &mut Self::Movetext) -> ControlFlow<Self::Output, Skip> {
+        lines
+            .stack
+            .push((lines.pos.clone(), lines.prev.clone(), lines.last));
+        lines.pos = lines.prev.clone();
+        lines.last = None;
+        ControlFlow::Continue(Skip(false))
+    }
+
+    fn end_variation(&mut self, lines: &mut Self::Movetext) -> ControlFlow<Self::Output> {
+        if let Some((pos, prev, last)) = lines.stack.pop() {
+            lines.pos = pos;
+            lines.prev = prev;
+            lines.last = last;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn end_game(&mut self, lines: Self::Movetext) -> Self::Output {
+        Ok(lines.moves)
+    }
+}
+
+impl Database {
+    pub async fn create_repertoire(&self, name: &str, color: Side) -> Result<i64> {
+        let id = sqlx::query("insert into repertoire (name, color) values ($1, $2)")
+            .bind(name)
+            .bind(color)
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+        Ok(id)
+    }
+
+    pub async fn repertoires(&self) -> Result<Vec<Repertoire>> {
+        let repertoires = sqlx::query_as("select * from repertoire order by name, id")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(repertoires)
+    }
+
+    pub async fn repertoire(&self, id: i64) -> Result<Repertoire> {
+        sqlx::query_as("select * from repertoire where id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow!("no repertoire with id {id}"))
+    }
+
+    pub async fn delete_repertoire(&self, id: i64) -> Result<()> {
+        sqlx::query("delete from repertoire where id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn repertoire_tree(&self, id: i64) -> Result<RepertoireTree> {
+        let color = self.repertoire(id).await?.color;
+        let rows: Vec<(i64, RepertoireMove)> = sqlx::query_as::<_, HashedMove>(
+            "select hash, id, fen, uci, san, comment from repertoire_move \
+             where repertoire_id = $1 order by id",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.hash, row.m))
+        .collect();
+        let mut moves = HashMap::<_, Vec<_>>::new();
+        for (hash, m) in rows {
+            moves.entry(hash).or_default().push(m);
+        }
+        Ok(RepertoireTree { color, moves })
+    }
+
+    /// Adds `m` from `pos`. Returns `false` if the repertoire already had it.
+    pub async fn add_repertoire_move(
+        &self,
+        id: i64,
+        pos: &Chess,
+        m: &Move,
+        comment: Option<&str>,
+    ) -> Result<bool> {
+        let added = self.add_repertoire_moves(id, [(pos, m, comment)]).await?;
+        Ok(added > 0)
+    }
+
+    async fn add_repertoire_moves<'a>(
+        &self,
+        id: i64,
+        moves: impl IntoIterator<Item = (&'a Chess, &'a Move, Option<&'a str>)>,
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut added = 0;
+        for (pos, m, comment) in moves {
+            added += sqlx::query(
+                r#"
+                insert into repertoire_move (repertoire_id, hash, fen, uci, san, comment)
+                values ($1, $2, $3, $4, $5, $6)
+                on conflict (repertoire_id, hash, uci) do nothing
+                "#,
+            )
+            .bind(id)
+            .bind(position_hash(pos))
+            .bind(Fen::from_position(pos, EnPassantMode::Legal).to_string())
+            .bind(m.to_uci(CastlingMode::Standard).to_string())
+            .bind(San::from_move(pos, *m).to_string())
+            .bind(comment)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        sqlx::query("update repertoire set updated_at = current_timestamp where id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    /// Removes the move `uci` from `pos`. Its continuations stay, as other move orders may
+    /// still reach them.
+    pub async fn remove_repertoire_move(&self, id: i64, pos: &Chess, uci: &str) -> Result<()> {
+        sqlx::query(
+            "delete from repertoire_move where repertoire_id = $1 and hash = $2 and uci = $3",
+        )
+        .bind(id)
+        .bind(position_hash(pos))
+        .bind(uci)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("update repertoire set updated_at = current_timestamp where id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Adds every move of every game in `pgn`, variations included.
+    pub async fn import_repertoire(&self, id: i64, pgn: &str) -> Result<RepertoireImport> {
+        let mut reader = Reader::new(pgn.as_bytes());
+        let mut import = RepertoireImport::default();
+        while let Some(lines) = reader.read_game(&mut LineVisitor)? {
+            let lines = lines.map_err(|e| anyhow!("game {}: {e}", import.games + 1))?;
+            import.added += self
+                .add_repertoire_moves(
+                    id,
+                    lines
+                        .iter()
+                        .map(|l| (&l.before, &l.m, l.comment.as_deref())),
+                )
+                .await?;
+            import.games += 1;
+        }
+        Ok(import)
+    }
+
+    /// Finds the popular opponent replies the repertoire has no answer to, walking the
+    /// repertoire from the initial position.
+    pub async fn repertoire_coverage(
+        &self,
+        id: i64,
+        book: &OpeningBook,
+        options: &CoverageOptions,
+    ) -> Result<CoverageReport> {
+        let tree = self.repertoire_tree(id).await?;
+        let us = tree.color.color();
+        let mut report = CoverageReport::default();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([(Chess::default(), Vec::<String>::new())]);
+
+        while let Some((pos, line)) = queue.pop_front() {
+            if !seen.insert(position_hash(&pos)) || line.len() >= options.max_ply {
+                continue;
+            }
+            let mut push = |m: Move, san: String| {
+                let mut after = pos.clone();
+                after.play_unchecked(m);
+                let mut line = line.clone();
+                line.push(san);
+                queue.push_back((after, line));
+            };
+
+            if pos.turn() == us {
+                for m in tree.moves(&pos) {
+                    if let Ok(m) = m.uci.parse::<UciMove>()?.to_move(&pos) {
+                        push(m, to_san(&pos, &m));
+                    }
+                }
+                continue;
+            }
+
+            report.positions += 1;
+            let mut replies: Vec<Reply> = Vec::new();
+            let explorer = self.explore(&pos, &options.filter).await?;
+            for m in &explorer.moves {
+                let share = m.tally.games as f64 / explorer.tally.games.max(1) as f64;
+                if m.tally.games >= options.min_games && share >= options.min_share {
+                    replies.push(Reply {
+                        uci: m.uci.clone(),
+                        games: m.tally.games,
+                        share,
+                        source: GapSource::Database,
+                    });
+                }
+            }
+            if options.book {
+                for c in book.continuations(&pos) {
+                    if !replies.iter().any(|r| r.uci == c.uci) {
+                        replies.push(Reply {
+                            uci: c.uci,
+                            games: 0,
+                            share: 0.0,
+                            source: GapSource::Book,
+                        });
+                    }
+                }
+            }
+            // Prepared opponent moves are followed even when nobody plays them.
+            for m in tree.moves(&pos) {
+                if let Ok(m) = m.uci.parse::<UciMove>()?.to_move(&pos) {
+                    if !replies
+                        .iter()
+                        .any(|r| r.uci == m.to_uci(CastlingMode::Standard).to_string())
+                    {
+                        push(m, to_san(&pos, &m));
+                    }
+                }
+            }
+
+            for reply in replies {
+                let Ok(m) = reply.uci.parse::<UciMove>()?.to_move(&pos) else {
+                    continue;
+                };
+                let san = to_san(&pos, &m);
+                let mut after = pos.clone();
+                after.play_unchecked(m);
+                report.games += reply.games;
+                if tree.contains(&after) || after.is_game_over() {
+                    report.covered += reply.games;
+                    push(m, san);
+                } else {
+                    report.gaps.push(Gap {
+                        fen: Fen::from_position(&pos, EnPassantMode::Legal).to_string(),
+                        line: line.clone(),
+                        uci: reply.uci,
+                        san,
+                        games: reply.games,
+                        share: reply.share,
+                        source: reply.source,
+                        opening: book.lookup(&after).map(|o| o.name().to_string()),
+                    });
+                }
+            }
+        }
+
+        report
+            .gaps
+            .sort_by(|a, b| b.games.cmp(&a.games).then(a.line.len().cmp(&b.line.len())));
+        Ok(report)
+    }
+}
+
+fn to_san(pos: &Chess, m: &Move) -> String {
+    San::from_move(pos, *m).to_string()
+}
+
+#[derive(sqlx::FromRow)]
+struct HashedMove {
+    hash: i64,
+    #[sqlx(flatten)]
+    m: RepertoireMove,
+}
+
+struct Reply {
+    uci: String,
+    games: i64,
+    share: f64,
+    source: GapSource,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CoverageOptions {
+    /// Replies played in fewer stored games are ignored.
+    pub min_games: i64,
+    /// Replies played in a smaller fraction of the games reaching the position are ignored.
+    pub min_share: f64,
+    /// How deep to follow the repertoire, in plies from the initial position.
+    pub max_ply: usize,
+    /// Whether the named lines of the opening book count as replies too.
+    pub book: bool,
+    pub filter: ExplorerFilter,
+}
+
+impl Default for CoverageOptions {
+    fn default() -> Self {
+        Self {
+            min_games: 5,
+            min_share: 0.05,
+            max_ply: 20,
+            book: true,
+            filter: ExplorerFilter::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GapSource {
+    Database,
+    Book,
+}
+
+/// An opponent reply the repertoire has no answer to.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gap {
+    /// Position the opponent replies from.
+    pub fen: String,
+    /// Moves leading to `fen`, in SAN.
+    pub line: Vec<String>,
+    pub uci: String,
+    pub san: String,
+    /// Stored games with the reply, 0 for book moves.
+    pub games: i64,
+    pub share: f64,
+    pub source: GapSource,
+    /// The opening the reply leads to, if it is named.
+    pub opening: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoverageReport {
+    /// Opponent decisions examined.
+    pub positions: usize,
+    /// Stored games over all examined replies, and those the repertoire answers.
+    pub games: i64,
+    pub covered: i64,
+    pub gaps: Vec<Gap>,
+}
+
+/// A repertoire move the engine thinks loses too much.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DubiousMove {
+    pub fen: String,
+    pub uci: String,
+    pub san: String,
+    /// The engine's preferred move, in SAN.
+    pub best: Option<String>,
+    pub cp_loss: i32,
+    pub judgement: Judgement,
+}
+
+/// Evaluates the repertoire's own moves and returns those losing more than `threshold`
+/// centipawns against the engine's choice, worst first.
+///
+/// `job` carries the search limits; its position is replaced for every search.
+pub async fn dubious_moves(
+    engine: &mut Engine,
+    tree: &RepertoireTree,
+    job: &Go,
+    threshold: i32,
+) -> Result<Vec<DubiousMove>> {
+    let us = tree.color.color();
+    // Best evaluation of each position for the side to move, with the engine's move.
+    let mut best = HashMap::new();
+    let mut dubious = Vec::new();
+
+    for rm in tree.iter() {
+        let pos: Chess = rm
+            .fen
+            .parse::<Fen>()?
+            .into_position(CastlingMode::Standard)?;
+        if pos.turn() != us {
+            continue;
+        }
+        let m = rm.uci.parse::<UciMove>()?.to_move(&pos)?;
+
+        if !best.contains_key(&rm.fen) {
+            let mut search = job.clone();
+            search.fen = Some(rm.fen.clone());
+            search.moves = Vec::new();
+            let (info, bm) = engine.go(search).await?;
+            let san = bm
+                .best
+                .parse::<UciMove>()
+                .ok()
+                .and_then(|m| m.to_move(&pos).ok())
+                .map(|m| to_san(&pos, &m));
+            best.insert(rm.fen.clone(), (info.score.as_cp(), san));
+        }
+        let (before, best_san) = best[&rm.fen].clone();
+
+        let mut after = pos.clone();
+        after.play_unchecked(m);
+        // Scores are from the side to move, the opponent after our move.
+        let reply = match terminal_score(&after) {
+            Some(score) => after.turn().fold_wb(1, -1) * score.as_cp(),
+            None => {
+                let mut search = job.clone();
+                search.fen = Some(rm.fen.clone());
+                search.moves = vec![rm.uci.clone()];
+                engine.go(search).await?.0.score.as_cp()
+            }
+        };
+
+        let cp_loss =
+            (before.clamp(-CP_CEILING, CP_CEILING) + reply.clamp(-CP_CEILING, CP_CEILING)).max(0);
+        if cp_loss > threshold {
+            dubious.push(DubiousMove {
+                fen: rm.fen.clone(),
+                uci: rm.uci.clone(),
+                san: rm.san.clone(),
+                best: best_san,
+                cp_loss,
+                judgement: Judgement::from_cp_loss(cp_loss),
+            });
+        }
+    }
+
+    dubious.sort_by_key(|d| std::cmp::Reverse(d.cp_loss));
+    Ok(dubious)
+}
+
+#[cfg(feature = "desktop")]
+fn parse_position(fen: &str) -> Result<Chess, String> {
+    fen.parse::<Fen>()
+        .map_err(|e| e.to_string())?
+        .into_position(CastlingMode::Standard)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn create_repertoire(
+    name: &str,
+    color: Side,
+    state: State<'_, AppState>,
+) -> Result<i64, String> {
+    state
+        .db
+        .create_repertoire(name, color)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn get_repertoires(state: State<'_, AppState>) -> Result<Vec<Repertoire>, String> {
+    state.db.repertoires().await.map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn delete_repertoire(id: i64, state: State<'_, AppState>) -> Result<(), String> {
+    state
+        .db
+        .delete_repertoire(id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn import_repertoire(
+    id: i64,
+    pgn: &str,
+    state: State<'_, AppState>,
+) -> Result<RepertoireImport, String> {
+    state
+        .db
+        .import_repertoire(id, pgn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The repertoire moves from a position.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn repertoire_moves(
+    id: i64,
+    fen: &str,
+    state: State<'_, AppState>,
+) -> Result<Vec<RepertoireMove>, String> {
+    let pos = parse_position(fen)?;
+    let tree = state
+        .db
+        .repertoire_tree(id)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(tree.moves(&pos).to_vec())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn add_repertoire_move(
+    id: i64,
+    fen: &str,
+    uci: &str,
+    comment: Option<&str>,
+    state: State<'_, AppState>,
+) -> Result<bool, String> {
+    let pos = parse_position(fen)?;
+    let m = uci
+        .parse::<UciMove>()
+        .map_err(|e| e.to_string())?
+        .to_move(&pos)
+        .map_err(|e| e.to_string())?;
+    state
+        .db
+        .add_repertoire_move(id, &pos, &m, comment)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn remove_repertoire_move(
+    id: i64,
+    fen: &str,
+    uci: &str,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let pos = parse_position(fen)?;
+    state
+        .db
+        .remove_repertoire_move(id, &pos, uci)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn repertoire_coverage(
+    id: i64,
+    options: CoverageOptions,
+    state: State<'_, AppState>,
+) -> Result<CoverageReport, String> {
+    let book = state.openings.read().unwrap().clone();
+    state
+        .db
+        .repertoire_coverage(id, &book, &options)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Repertoire moves losing more than `threshold` centipawns at the given depth.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn check_repertoire(
+    id: i64,
+    engine: EngineConfig,
+    depth: u32,
+    threshold: i32,
+    state: State<'_, AppState>,
+) -> Result<Vec<DubiousMove>, String> {
+    let tree = state
+        .db
+        .repertoire_tree(id)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut uci = engine.start().await.map_err(|e| e.to_string())?;
+    let dubious = dubious_moves(&mut uci, &tree, &Go::new().depth(depth), threshold).await;
+    uci.kill().await.map_err(|e| e.to_string())?;
+    dubious.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPERTOIRE: &str =
+        "1. e4 e5 { main line } (1... c5 2. Nf3) 2. Nf3 Nc6 (2... d6 3. d4) 3. Bb5 *\n";
+
+    const GAMES: [&str; 4] = [
+        "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 *\n",
+        "1. e4 c5 2. Nf3 d6 *\n",
+        "1. e4 e6 2. d4 d5 *\n",
+        "1. Nf3 e5 2. e4 Nc6 *\n",
+    ];
+
+    #[tokio::test]
+    async fn import_and_coverage() {
+        let db = Database::memory().await.unwrap();
+        let id = db.create_repertoire("1. e4", Side::White).await.unwrap();
+        let import = db.import_repertoire(id, REPERTOIRE).await.unwrap();
+        assert_eq!((import.games, import.added), (1, 9));
+        assert_eq!(db.import_repertoire(id, REPERTOIRE).await.unwrap().added, 0);
+
+        let tree = db.repertoire_tree(id).await.unwrap();
+        assert_eq!(tree.len(), 9);
+        let after_e4 = tree.moves(&Chess::default())[0].clone();
+        assert_eq!(after_e4.san, "e4");
+        let mut pos = Chess::default();
+        pos.play_unchecked(
+            after_e4
+                .uci
+                .parse::<UciMove>()
+                .unwrap()
+                .to_move(&pos)
+                .unwrap(),
+        );
+        let replies = tree.moves(&pos);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].comment.as_deref(), Some("main line"));
+
+        for pgn in GAMES {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+        let options = CoverageOptions {
+            min_games: 1,
+            min_share: 0.0,
+            book: false,
+            ..Default::default()
+        };
+        let report = db
+            .repertoire_coverage(id, &OpeningBook::default(), &options)
+            .await
+            .unwrap();
+        let gaps = report
+            .gaps
+            .iter()
+            .map(|g| (g.line.join(" "), g.san.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gaps,
+            [
+                ("e4".into(), "e6"),
+                ("e4 c5 Nf3".into(), "d6"),
+                ("e4 e5 Nf3 Nc6 Bb5".into(), "a6")
+            ]
+        );
+        // 1. Nf3 e5 2. e4 Nc6 transposes into the repertoire.
+        assert_eq!((report.games, report.covered), (7, 4));
+        assert!(report.positions >= 4);
+
+        db.delete_repertoire(id).await.unwrap();
+        assert!(db.repertoires().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,449 @@
+//! Searching the stored games by their headers.
+
+use shakmaty::{fen::Fen, CastlingMode, Chess};
+use sqlx::{QueryBuilder, Sqlite};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::{
+    explorer::{ExplorerMove, GameFilter, Side, Tally},
+    position_hash, Database,
+};
+use crate::chess::clock::Speed;
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// Games per page when the query does not say.
+const PAGE: i64 = 50;
+/// The largest page a query may ask for.
+const MAX_PAGE: i64 = 500;
+
+/// The columns of [`GameRow`] but `ply`, from the game table aliased `g`.
+pub(super) const ROW: &str =
+    "g.id, g.white, g.black, g.white_elo, g.black_elo, g.result, g.date, g.event, \
+                   g.round, g.eco, g.opening, g.time_control, g.ply_count";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameSort {
+    #[default]
+    Date,
+    White,
+    Black,
+    Event,
+    Eco,
+    /// Average rating of the players, with one known rating standing for both.
+    Rating,
+    Plies,
+}
+
+impl GameSort {
+    fn column(self) -> &'static str {
+        match self {
+            Self::Date => "g.date",
+            Self::White => "g.white collate nocase",
+            Self::Black => "g.black collate nocase",
+            Self::Event => "g.event collate nocase",
+            Self::Eco => "g.eco",
+            Self::Rating => {
+                "(coalesce(g.white_elo, g.black_elo) + coalesce(g.black_elo, g.white_elo)) / 2.0"
+            }
+            Self::Plies => "g.ply_count",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GameQuery {
+    /// Only games of players with a name or alias starting with this, compared
+    /// case-insensitively.
+    pub player: Option<String>,
+    /// The colour `player` had, either colour if unset.
+    pub color: Option<Side>,
+    /// Lowest rating of `player`, or of both players if there is no player.
+    pub min_rating: Option<i64>,
+    pub max_rating: Option<i64>,
+    /// Earliest date, in the PGN `YYYY.MM.DD` format.
+    pub since: Option<String>,
+    /// Latest date, in the PGN `YYYY.MM.DD` format.
+    pub until: Option<String>,
+    /// Only games whose event contains this, compared case-insensitively.
+    pub event: Option<String>,
+    /// `1-0`, `0-1`, `1/2-1/2` or `*`.
+    pub result: Option<String>,
+    /// Only games whose ECO code starts with this, e.g. `B9`.
+    pub eco: Option<String>,
+    /// Only games whose opening name contains this, compared case-insensitively.
+    pub opening: Option<String>,
+    /// The exact `TimeControl` tag, e.g. `180+2`.
+    pub time_control: Option<String>,
+    /// Only games of these speeds, all games if empty.
+    pub speeds: Vec<Speed>,
+    pub min_plies: Option<i64>,
+    pub max_plies: Option<i64>,
+    /// Only games reaching this position, as FEN, by any move order.
+    pub position: Option<String>,
+    pub sort: GameSort,
+    pub descending: bool,
+    pub offset: i64,
+    /// Games per page, 50 if unset.
+    pub limit: Option<i64>,
+}
+
+/// Escapes `%`, `_` and `\` for a `like` pattern with `escape '\'`.
+pub(super) fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn parse_position(fen: &str) -> anyhow::Result<Chess> {
+    Ok(fen.parse::<Fen>()?.into_position(CastlingMode::Standard)?)
+}
+
+impl GameQuery {
+    /// The hash of `position`, if the query has one.
+    fn position_hash(&self) -> anyhow::Result<Option<i64>> {
+        self.position
+            .as_deref()
+            .map(|fen| Ok(position_hash(&parse_position(fen)?)))
+            .transpose()
+    }
+
+    /// Fails if the query has a position other than `pos`, from which its moves are counted.
+    pub fn check_position(&self, pos: &Chess) -> anyhow::Result<()> {
+        match self.position_hash()? {
+            Some(hash) if hash != position_hash(pos) => {
+                anyhow::bail!("the query position differs from the one whose moves are counted")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl GameFilter for GameQuery {
+    fn push(&self, q: &mut QueryBuilder<'_, Sqlite>) -> anyhow::Result<()> {
+        let ratings = |q: &mut QueryBuilder<'_, Sqlite>, elo: &str| {
+            if let Some(min) = self.min_rating {
+                q.push(format!(" and {elo} >= ")).push_bind(min);
+            }
+            if let Some(max) = self.max_rating {
+                q.push(format!(" and {elo} <= ")).push_bind(max);
+            }
+        };
+        match &self.player {
+            Some(player) => {
+                let pattern = format!("{}%", like_escape(player.trim()));
+                let sides: &[(&str, &str)] = match self.color {
+                    Some(Side::White) => &[("g.white_id", "g.white_elo")],
+                    Some(Side::Black) => &[("g.black_id", "g.black_elo")],
+                    None => &[("g.white_id", "g.white_elo"), ("g.black_id", "g.black_elo")],
+                };
+                q.push(" and (");
+                for (i, (id, elo)) in sides.iter().enumerate() {
+                    if i > 0 {
+                        q.push(" or ");
+                    }
+                    q.push(format!(
+                        "({id} in (select id from player where coalesce(alias_of, id) in \
+                         (select coalesce(alias_of, id) from player where name like "
+                    ));
+                    q.push_bind(pattern.clone());
+                    q.push(" escape '\\'))");
+                    // The rating range applies to the player, whichever colour they had.
+                    ratings(q, elo);
+                    q.push(")");
+                }
+                q.push(")");
+            }
+            None => {
+                ratings(q, "g.white_elo");
+                ratings(q, "g.black_elo");
+            }
+        }
+        if let Some(since) = &self.since {
+            q.push(" and g.date >= ").push_bind(since.clone());
+        }
+        if let Some(until) = &self.until {
+            q.push(" and g.date <= ").push_bind(until.clone());
+        }
+        if let Some(event) = &self.event {
+            q.push(" and g.event like ")
+                .push_bind(format!("%{}%", like_escape(event)));
+            q.push(" escape '\\'");
+        }
+        if let Some(result) = &self.result {
+            q.push(" and g.result = ").push_bind(result.clone());
+        }
+        if let Some(eco) = &self.eco {
+            q.push(" and g.eco like ")
+                .push_bind(format!("{}%", like_escape(eco)));
+            q.push(" escape '\\'");
+        }
+        if let Some(opening) = &self.opening {
+            q.push(" and g.opening like ")
+                .push_bind(format!("%{}%", like_escape(opening)));
+            q.push(" escape '\\'");
+        }
+        if let Some(time_control) = &self.time_control {
+            q.push(" and g.time_control = ")
+                .push_bind(time_control.clone());
+        }
+        if !self.speeds.is_empty() {
+            q.push(" and g.speed in (");
+            let mut list = q.separated(", ");
+            for speed in &self.speeds {
+                list.push_bind(speed.as_str());
+            }
+            q.push(")");
+        }
+        if let Some(min) = self.min_plies {
+            q.push(" and g.ply_count >= ").push_bind(min);
+        }
+        if let Some(max) = self.max_plies {
+            q.push(" and g.ply_count <= ").push_bind(max);
+        }
+        if let Some(hash) = self.position_hash()? {
+            q.push(" and g.id in (select game_id from position where hash = ")
+                .push_bind(hash);
+            q.push(")");
+        }
+        Ok(())
+    }
+}
+
+/// A stored game without its moves, for lists.
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameRow {
+    pub id: i64,
+    pub white: String,
+    pub black: String,
+    pub white_elo: Option<i64>,
+    pub black_elo: Option<i64>,
+    pub result: String,
+    pub date: Option<String>,
+    pub event: Option<String>,
+    pub round: Option<String>,
+    pub eco: Option<String>,
+    pub opening: Option<String>,
+    pub time_control: Option<String>,
+    pub ply_count: Option<i64>,
+    /// The first ply at which the game reached the queried position.
+    pub ply: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GamePage {
+    /// Games matching the query, on every page.
+    pub total: i64,
+    pub games: Vec<GameRow>,
+}
+
+impl Database {
+    /// Number of games matching `query`.
+    pub async fn count_games(&self, query: &GameQuery) -> anyhow::Result<i64> {
+        let mut q = QueryBuilder::<Sqlite>::new("select count(*) from game g where true");
+        query.push(&mut q)?;
+        Ok(q.build_query_scalar().fetch_one(&self.pool).await?)
+    }
+
+    pub async fn search_games(&self, query: &GameQuery) -> anyhow::Result<GamePage> {
+        let position = query.position_hash()?;
+        let total = self.count_games(query).await?;
+
+        let mut q = QueryBuilder::<Sqlite>::new(format!("select {ROW}, "));
+        match position {
+            Some(hash) => {
+                q.push("(select min(ply) from position p where p.game_id = g.id and p.hash = ")
+                    .push_bind(hash);
+                q.push(") as ply");
+            }
+            None => {
+                q.push("null as ply");
+            }
+        }
+        q.push(" from game g where true");
+        query.push(&mut q)?;
+        let order = if query.descending { "desc" } else { "asc" };
+        q.push(format!(
+            " order by {} {order} nulls last, g.id {order}",
+            query.sort.column()
+        ));
+        q.push(" limit ")
+            .push_bind(query.limit.unwrap_or(PAGE).clamp(1, MAX_PAGE));
+        q.push(" offset ").push_bind(query.offset.max(0));
+        let games = q.build_query_as().fetch_all(&self.pool).await?;
+        Ok(GamePage { total, games })
+    }
+}
+
+/// What was played from a position in the games matching a query.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionMoves {
+    #[serde(flatten)]
+    pub tally: Tally,
+    /// White, draw and Black percentages.
+    pub percentages: [f64; 3],
+    pub moves: Vec<ExplorerMove>,
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn search_games(
+    query: GameQuery,
+    state: State<'_, AppState>,
+) -> Result<GamePage, String> {
+    state
+        .db
+        .search_games(&query)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The moves played from `fen` in the games matching `query`, with their results. Games are
+/// counted once, from the first time they reached the position.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn position_moves(
+    fen: &str,
+    query: GameQuery,
+    state: State<'_, AppState>,
+) -> Result<PositionMoves, String> {
+    let pos = parse_position(fen).map_err(|e| e.to_string())?;
+    query.check_position(&pos).map_err(|e| e.to_string())?;
+    let (tally, moves) = state
+        .db
+        .next_moves(&pos, &query)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(PositionMoves {
+        percentages: tally.percentages(),
+        tally,
+        moves,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::openings::OpeningBook;
+
+    const GAMES: [&str; 4] = [
+        "[Event \"Tata Steel\"]\n[White \"Carlsen, Magnus\"]\n[Black \"Giri, Anish\"]\n[Result \"1-0\"]\n[Date \"2024.01.20\"]\n[WhiteElo \"2830\"]\n[BlackElo \"2750\"]\n\n1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 1-0\n",
+        "[Event \"Titled Arena\"]\n[White \"Giri, Anish\"]\n[Black \"Carlsen, Magnus\"]\n[Result \"1/2-1/2\"]\n[Date \"2024.02.01\"]\n[WhiteElo \"2760\"]\n[BlackElo \"2840\"]\n[TimeControl \"60+0\"]\n\n1. d4 d5 2. c4 e6 1/2-1/2\n",
+        "[Event \"Tata Steel\"]\n[White \"Firouzja, Alireza\"]\n[Black \"Carlsen, Magnus\"]\n[Result \"0-1\"]\n[Date \"2024.01.21\"]\n[WhiteElo \"2760\"]\n[BlackElo \"2830\"]\n\n1. e4 e5 0-1\n",
+        "[Event \"Club 100%\"]\n[White \"Carlsen, Magnus\"]\n[Black \"Anonymous\"]\n[Result \"*\"]\n\n1. e4 *\n",
+    ];
+
+    #[tokio::test]
+    async fn search() {
+        let db = Database::memory().await.unwrap();
+        for pgn in GAMES {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+        let ids = |page: GamePage| page.games.iter().map(|g| g.id).collect::<Vec<_>>();
+
+        let carlsen = GameQuery {
+            player: Some("carlsen".into()),
+            ..Default::default()
+        };
+        let page = db.search_games(&carlsen).await.unwrap();
+        assert_eq!(page.total, 4);
+        // Games without a date come last.
+        assert_eq!(ids(page), [1, 3, 2, 4]);
+
+        let as_black = GameQuery {
+            color: Some(Side::Black),
+            min_rating: Some(2835),
+            ..carlsen.clone()
+        };
+        assert_eq!(ids(db.search_games(&as_black).await.unwrap()), [2]);
+
+        let query = GameQuery {
+            event: Some("tata".into()),
+            result: Some("1-0".into()),
+            eco: Some("B9".into()),
+            opening: Some("najdorf".into()),
+            min_plies: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(ids(db.search_games(&query).await.unwrap()), [1]);
+
+        let query = GameQuery {
+            since: Some("2024.01.21".into()),
+            speeds: vec![Speed::Bullet],
+            time_control: Some("60+0".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(db.search_games(&query).await.unwrap()), [2]);
+
+        let query = GameQuery {
+            event: Some("100%".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(db.search_games(&query).await.unwrap()), [4]);
+
+        let query = GameQuery {
+            sort: GameSort::Rating,
+            descending: true,
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = db.search_games(&query).await.unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(ids(page), [3, 1]);
+    }
+
+    #[tokio::test]
+    async fn position_search() {
+        let db = Database::memory().await.unwrap();
+        for pgn in [
+            "[White \"Alice\"]\n[Black \"Bob\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bb5 1-0\n",
+            "[White \"Bob\"]\n[Black \"Alice\"]\n[Result \"0-1\"]\n\n1. Nf3 Nc6 2. e4 e5 3. Bc4 0-1\n",
+            "[White \"Alice\"]\n[Black \"Carol\"]\n[Result \"1/2-1/2\"]\n\n1. e4 c5 1/2-1/2\n",
+        ] {
+            db.insert_pgn(pgn, &OpeningBook::bundled()).await.unwrap();
+        }
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        let query = GameQuery {
+            position: Some(fen.into()),
+            ..Default::default()
+        };
+
+        // Both move orders reach the position after four plies.
+        let page = db.search_games(&query).await.unwrap();
+        assert_eq!(page.total, 2);
+        assert!(page.games.iter().all(|g| g.ply == Some(4)));
+
+        let pos = parse_position(fen).unwrap();
+        let (tally, moves) = db.next_moves(&pos, &query).await.unwrap();
+        assert_eq!((tally.games, tally.white, tally.black), (2, 1, 1));
+        let sans = moves.iter().map(|m| m.san.as_str()).collect::<Vec<_>>();
+        assert_eq!(sans, ["Bb5", "Bc4"]);
+
+        // Preparing against Alice with Black.
+        let against = GameQuery {
+            player: Some("alice".into()),
+            color: Some(Side::White),
+            ..query.clone()
+        };
+        let (tally, moves) = db.next_moves(&pos, &against).await.unwrap();
+        assert_eq!((tally.games, moves[0].san.as_str()), (1, "Bb5"));
+
+        let bad = GameQuery {
+            position: Some("not a fen".into()),
+            ..Default::default()
+        };
+        assert!(db.search_games(&bad).await.is_err());
+        assert!(db.next_moves(&pos, &bad).await.is_err());
+        query.check_position(&pos).unwrap();
+        let start = Chess::default();
+        assert!(query.check_position(&start).is_err());
+        assert!(bad.check_position(&pos).is_err());
+    }
+}
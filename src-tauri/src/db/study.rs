@@ -0,0 +1,591 @@
+//! Studies: named move trees edited in the notation panel.
+//!
+//! Writes carry the `updated_at` the editor last saw and only apply if the study has not been
+//! saved since, so two windows editing one study cannot overwrite each other unnoticed.
+//!
+//! A study's own tree is its first chapter; PGN imports with several games add the others as
+//! `chapter` rows.
+
+use anyhow::{Context, Result};
+#[cfg(feature = "desktop")]
+use tauri::State;
+
+use super::Database;
+use crate::chess::{
+    pgn::{read_annotated, AnnotatedGame},
+    tree::{parse_tree, GameTree},
+};
+#[cfg(feature = "desktop")]
+use crate::AppState;
+
+/// The new `updated_at`: the current time in milliseconds, and always later than the previous
+/// value so that two saves within the same millisecond still differ.
+const TOUCH: &str = "updated_at = max(strftime('%Y-%m-%d %H:%M:%f', 'now'), \
+                     strftime('%Y-%m-%d %H:%M:%f', updated_at, '+0.001 seconds'))";
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Study {
+    pub id: i64,
+    pub name: String,
+    pub tree_json: String,
+    /// PGN tag pairs, as a JSON array of `[name, value]` pairs.
+    pub tags_json: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Study {
+    pub fn tree(&self) -> Result<GameTree> {
+        parse_tree(&self.tree_json)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewStudy {
+    pub name: String,
+    pub tree_json: String,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub id: i64,
+    pub study_id: i64,
+    pub ord: i64,
+    pub name: String,
+    pub tags_json: String,
+    pub tree_json: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Chapter {
+    pub fn tree(&self) -> Result<GameTree> {
+        parse_tree(&self.tree_json)
+    }
+}
+
+/// Where the games of a PGN import go.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ImportTarget {
+    /// A new study per game.
+    Studies,
+    /// One new study, with a chapter per game after the first.
+    Study { name: String },
+    /// New chapters at the end of an existing study.
+    Chapters { id: i64 },
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PgnImport {
+    /// The studies created or extended.
+    pub studies: Vec<i64>,
+    pub chapters: usize,
+    /// Games that could not be read, which were skipped.
+    pub errors: Vec<String>,
+}
+
+/// Names a game after its players, or its event if they are unknown.
+fn game_name(game: &AnnotatedGame, index: usize) -> String {
+    let known = |name| game.tag(name).filter(|v| !v.is_empty() && *v != "?");
+    match (known("White"), known("Black")) {
+        (Some(white), Some(black)) => format!("{white} - {black}"),
+        _ => known("Event")
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("Chapter {}", index + 1)),
+    }
+}
+
+/// The outcome of a write guarded by `updated_at`.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum StudyWrite {
+    Saved {
+        study: Study,
+    },
+    Deleted,
+    /// The study was saved elsewhere since the editor loaded it. Nothing was written.
+    Conflict {
+        current: Study,
+    },
+}
+
+impl Database {
+    /// Stores a new study. Its tree must be valid.
+    pub async fn insert_study(&self, study: &NewStudy) -> Result<i64> {
+        parse_tree(&study.tree_json)?;
+        let id = sqlx::query("insert into study (name, tree_json) values ($1, $2)")
+            .bind(&study.name)
+            .bind(&study.tree_json)
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+        Ok(id)
+    }
+
+    /// Every study, the most recently saved first.
+    pub async fn studies(&self) -> Result<Vec<Study>> {
+        let studies = sqlx::query_as("select * from study order by updated_at desc, id desc")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(studies)
+    }
+
+    pub async fn study(&self, id: i64) -> Result<Study> {
+        sqlx::query_as("select * from study where id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .with_context(|| format!("no study with id {id}"))
+    }
+
+    /// The study after a guarded write that changed `rows` rows.
+    async fn written(&self, id: i64, rows: u64) -> Result<StudyWrite> {
+        let current = self.study(id).await?;
+        Ok(match rows {
+            0 => StudyWrite::Conflict { current },
+            _ => StudyWrite::Saved { study: current },
+        })
+    }
+
+    /// Replaces the move tree if the study is still at `updated_at`. The tree must be valid.
+    pub async fn update_study(
+        &self,
+        id: i64,
+        tree_json: &str,
+        updated_at: &str,
+    ) -> Result<StudyWrite> {
+        parse_tree(tree_json)?;
+        let rows = sqlx::query(&format!(
+            "update study set tree_json = $1, {TOUCH} where id = $2 and updated_at = $3"
+        ))
+        .bind(tree_json)
+        .bind(id)
+        .bind(updated_at)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        self.written(id, rows).await
+    }
+
+    pub async fn rename_study(&self, id: i64, name: &str, updated_at: &str) -> Result<StudyWrite> {
+        let rows = sqlx::query(&format!(
+            "update study set name = $1, {TOUCH} where id = $2 and updated_at = $3"
+        ))
+        .bind(name)
+        .bind(id)
+        .bind(updated_at)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        self.written(id, rows).await
+    }
+
+    pub async fn delete_study(&self, id: i64, updated_at: &str) -> Result<StudyWrite> {
+        let rows = sqlx::query("delete from study where id = $1 and updated_at = $2")
+            .bind(id)
+            .bind(updated_at)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        match rows {
+            0 => Ok(StudyWrite::Conflict {
+                current: self.study(id).await?,
+            }),
+            _ => Ok(StudyWrite::Deleted),
+        }
+    }
+
+    /// Copies a study and its chapters, named `name` or after the original.
+    pub async fn duplicate_study(&self, id: i64, name: Option<&str>) -> Result<Study> {
+        let study = self.study(id).await?;
+        let name = name
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{} (copy)", study.name));
+        let mut tx = self.pool.begin().await?;
+        let copy =
+            sqlx::query("insert into study (name, tree_json, tags_json) values ($1, $2, $3)")
+                .bind(&name)
+                .bind(&study.tree_json)
+                .bind(&study.tags_json)
+                .execute(&mut *tx)
+                .await?
+                .last_insert_rowid();
+        sqlx::query(
+            "insert into chapter (study_id, ord, name, tags_json, tree_json) \
+             select $1, ord, name, tags_json, tree_json from chapter where study_id = $2",
+        )
+        .bind(copy)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.study(copy).await
+    }
+
+    /// The chapters of a study after its own tree, in order.
+    pub async fn chapters(&self, study_id: i64) -> Result<Vec<Chapter>> {
+        let chapters = sqlx::query_as("select * from chapter where study_id = $1 order by ord, id")
+            .bind(study_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(chapters)
+    }
+
+    /// Writes a study as PGN: its own tree, then a game per chapter. Games without an `Event` tag
+    /// are named after the study and chapter.
+    pub async fn export_study_pgn(&self, id: i64) -> Result<String> {
+        let study = self.study(id).await?;
+        let mut games = vec![(study.name.clone(), study.tags_json, study.tree_json)];
+        for chapter in self.chapters(id).await? {
+            let event = format!("{}: {}", study.name, chapter.name);
+            games.push((event, chapter.tags_json, chapter.tree_json));
+        }
+
+        let mut out = String::new();
+        for (event, tags_json, tree_json) in games {
+            let mut game = AnnotatedGame {
+                tags: serde_json::from_str(&tags_json)?,
+                tree: parse_tree(&tree_json)?,
+            };
+            if game.tag("Event").is_none() {
+                game.tags.insert(0, ("Event".to_string(), event));
+            }
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&game.to_pgn());
+        }
+        Ok(out)
+    }
+
+    /// Reads every game of `pgn`, with its variations and annotations, into studies or
+    /// chapters. Games that cannot be read are reported and skipped.
+    pub async fn import_study_pgn(&self, pgn: &str, target: &ImportTarget) -> Result<PgnImport> {
+        let mut import = PgnImport::default();
+        let mut games = Vec::new();
+        for (i, game) in read_annotated(pgn.as_bytes()).enumerate() {
+            match game {
+                Ok(game) => games.push(game),
+                Err(e) => import.errors.push(format!("game {}: {e}", i + 1)),
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut chapters = Vec::new();
+        match target {
+            ImportTarget::Studies => {
+                for (i, game) in games.iter().enumerate() {
+                    let id = sqlx::query(
+                        "insert into study (name, tree_json, tags_json) values ($1, $2, $3)",
+                    )
+                    .bind(game_name(game, i))
+                    .bind(serde_json::to_string(&game.tree)?)
+                    .bind(serde_json::to_string(&game.tags)?)
+                    .execute(&mut *tx)
+                    .await?
+                    .last_insert_rowid();
+                    import.studies.push(id);
+                }
+            }
+            ImportTarget::Study { name } => {
+                let first = games.first().cloned().unwrap_or_default();
+                let id = sqlx::query(
+                    "insert into study (name, tree_json, tags_json) values ($1, $2, $3)",
+                )
+                .bind(name)
+                .bind(serde_json::to_string(&first.tree)?)
+                .bind(serde_json::to_string(&first.tags)?)
+                .execute(&mut *tx)
+                .await?
+                .last_insert_rowid();
+                import.studies.push(id);
+                chapters.extend(games.iter().enumerate().skip(1).map(|g| (id, g)));
+            }
+            ImportTarget::Chapters { id } => {
+                let rows = sqlx::query(&format!("update study set {TOUCH} where id = $1"))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+                anyhow::ensure!(rows > 0, "no study with id {id}");
+                import.studies.push(*id);
+                chapters.extend(games.iter().enumerate().map(|g| (*id, g)));
+            }
+        }
+        for (study_id, (i, game)) in chapters {
+            sqlx::query(
+                "insert into chapter (study_id, ord, name, tags_json, tree_json) values \
+                 ($1, (select coalesce(max(ord), 0) + 1 from chapter where study_id = $1), \
+                 $2, $3, $4)",
+            )
+            .bind(study_id)
+            .bind(game_name(game, i))
+            .bind(serde_json::to_string(&game.tags)?)
+            .bind(serde_json::to_string(&game.tree)?)
+            .execute(&mut *tx)
+            .await?;
+            import.chapters += 1;
+        }
+        tx.commit().await?;
+        Ok(import)
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn insert_study(study: NewStudy, state: State<'_, AppState>) -> Result<i64, String> {
+    state
+        .db
+        .insert_study(&study)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn get_studies(state: State<'_, AppState>) -> Result<Vec<Study>, String> {
+    state.db.studies().await.map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn get_study(id: i64, state: State<'_, AppState>) -> Result<Study, String> {
+    state.db.study(id).await.map_err(|e| e.to_string())
+}
+
+/// Saves the move tree, unless the study changed since `updated_at`.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn update_study(
+    id: i64,
+    tree_json: &str,
+    updated_at: &str,
+    state: State<'_, AppState>,
+) -> Result<StudyWrite, String> {
+    state
+        .db
+        .update_study(id, tree_json, updated_at)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn rename_study(
+    id: i64,
+    name: &str,
+    updated_at: &str,
+    state: State<'_, AppState>,
+) -> Result<StudyWrite, String> {
+    state
+        .db
+        .rename_study(id, name, updated_at)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn duplicate_study(
+    id: i64,
+    name: Option<&str>,
+    state: State<'_, AppState>,
+) -> Result<Study, String> {
+    state
+        .db
+        .duplicate_study(id, name)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn get_chapters(
+    study_id: i64,
+    state: State<'_, AppState>,
+) -> Result<Vec<Chapter>, String> {
+    state.db.chapters(study_id).await.map_err(|e| e.to_string())
+}
+
+/// Imports the games of a PGN text, variations and annotations included.
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn import_study_pgn(
+    pgn: &str,
+    target: ImportTarget,
+    state: State<'_, AppState>,
+) -> Result<PgnImport, String> {
+    state
+        .db
+        .import_study_pgn(pgn, &target)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn export_study_pgn(id: i64, state: State<'_, AppState>) -> Result<String, String> {
+    state
+        .db
+        .export_study_pgn(id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "desktop")]
+#[tauri::command]
+pub async fn delete_study(
+    id: i64,
+    updated_at: &str,
+    state: State<'_, AppState>,
+) -> Result<StudyWrite, String> {
+    state
+        .db
+        .delete_study(id, updated_at)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_edits() {
+        let db = Database::memory().await.unwrap();
+        let id = db
+            .insert_study(&NewStudy {
+                name: "Najdorf".into(),
+                tree_json: "[]".into(),
+            })
+            .await
+            .unwrap();
+        let loaded = db.study(id).await.unwrap();
+
+        // Two windows load the study; the first one saves twice in a row.
+        let StudyWrite::Saved { study: first } = db
+            .update_study(id, "[[]]", &loaded.updated_at)
+            .await
+            .unwrap()
+        else {
+            panic!("first save conflicted");
+        };
+        assert_ne!(first.updated_at, loaded.updated_at);
+        let StudyWrite::Saved { study: second } = db
+            .rename_study(id, "Najdorf 6. Bg5", &first.updated_at)
+            .await
+            .unwrap()
+        else {
+            panic!("rename conflicted");
+        };
+        assert!(second.updated_at > first.updated_at);
+
+        let StudyWrite::Conflict { current } =
+            db.update_study(id, "[]", &loaded.updated_at).await.unwrap()
+        else {
+            panic!("stale save was applied");
+        };
+        assert_eq!(
+            (current.name.as_str(), current.tree_json.as_str()),
+            ("Najdorf 6. Bg5", "[[]]")
+        );
+
+        let copy = db.duplicate_study(id, None).await.unwrap();
+        assert_eq!(copy.name, "Najdorf 6. Bg5 (copy)");
+        assert_eq!(copy.tree_json, "[[]]");
+
+        assert!(matches!(
+            db.delete_study(id, &first.updated_at).await.unwrap(),
+            StudyWrite::Conflict { .. }
+        ));
+        assert!(matches!(
+            db.delete_study(id, &second.updated_at).await.unwrap(),
+            StudyWrite::Deleted
+        ));
+        assert!(db.study(id).await.is_err());
+        assert!(copy.tree().unwrap().is_empty());
+        assert!(db
+            .update_study(copy.id, "[[{}]]", &copy.updated_at)
+            .await
+            .is_err());
+        assert_eq!(db.studies().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn import_pgn() {
+        let db = Database::memory().await.unwrap();
+        let pgn = r#"[White "Fischer"]
+[Black "Spassky"]
+
+1. e4 (1. d4 Nf6) 1... e5 $2 { [%cal Rg1f3] } *
+
+[Event "Bad"]
+
+1. e4 e4 *
+
+[Event "Sicilian"]
+
+1. e4 c5 *"#;
+        let import = db
+            .import_study_pgn(
+                pgn,
+                &ImportTarget::Study {
+                    name: "Imported".into(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(import.chapters, 1);
+        assert_eq!(import.errors.len(), 1);
+
+        let study = db.study(import.studies[0]).await.unwrap();
+        assert_eq!(study.name, "Imported");
+        let tree = study.tree().unwrap();
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.children[0].children[0].nags, [2]);
+        assert_eq!(tree.children[0].children[0].shapes.len(), 1);
+        assert!(study.tags_json.contains("Spassky"));
+        let chapters = db.chapters(study.id).await.unwrap();
+        assert_eq!(chapters[0].name, "Sicilian");
+
+        let more = db
+            .import_study_pgn(pgn, &ImportTarget::Chapters { id: study.id })
+            .await
+            .unwrap();
+        assert_eq!(more.chapters, 2);
+        let chapters = db.chapters(study.id).await.unwrap();
+        assert_eq!(
+            chapters.iter().map(|c| c.ord).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(chapters[1].name, "Fischer - Spassky");
+        assert!(db.study(study.id).await.unwrap().updated_at > study.updated_at);
+
+        let copy = db.duplicate_study(study.id, None).await.unwrap();
+        assert_eq!(db.chapters(copy.id).await.unwrap().len(), 3);
+        let exported = db.export_study_pgn(copy.id).await.unwrap();
+        assert!(exported.starts_with("[Event \"Imported (copy)\"]\n"));
+        assert!(exported.contains("[Event \"Sicilian\"]"));
+        assert!(exported.contains("1. e4 ( 1. d4 Nf6 ) 1... e5 $2 { [%cal Rg1f3] } *"));
+        let reimported = db
+            .import_study_pgn(
+                &exported,
+                &ImportTarget::Study {
+                    name: "Again".into(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!((reimported.chapters, reimported.errors.len()), (3, 0));
+        let separate = db
+            .import_study_pgn(pgn, &ImportTarget::Studies)
+            .await
+            .unwrap();
+        assert_eq!(separate.studies.len(), 2);
+    }
+}
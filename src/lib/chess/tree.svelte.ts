@@ -13,8 +13,14 @@ export interface MoveNode {
   isWhite: boolean
   prev: Cursor
   variations?: number[]
+  commentBefore?: string
   comment?: string
   nag?: { text: string; color: string }
+  /** every numeric annotation glyph, kept by the backend */
+  nags?: number[]
+  shapes?: { orig: string; dest?: string; brush: "green" | "red" | "blue" | "yellow" }[]
+  /** remaining clock time in milliseconds */
+  clock?: number
 }
 
 export class Tree {